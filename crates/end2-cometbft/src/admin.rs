@@ -0,0 +1,296 @@
+// Offline maintenance of an ABCI data directory. These open the same rocksdb
+// the server uses, so the server must be stopped first.
+//
+// The tree commits to exactly the contents of the device column family, so
+// either can be checked or regenerated from the other.
+//
+// Export format, JSON Lines: a header line, then one line per entry sorted by key.
+// {"height": <u64>, "app_hash": "<hex>"}
+// {"key": "<rocksdb key>", "value": "<base64 value>"}
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Write},
+};
+
+use anyhow::{Context, bail, ensure};
+use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
+use jmt::{
+    JellyfishMerkleTree, KeyHash, OwnedValue, Version,
+    storage::{LeafNode, Node, NodeKey, TreeReader},
+};
+use rocksdb::{IteratorMode, WriteBatch};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::store::{CF_JMT, CF_JMT_STALE, CF_JMT_VALUES, META_APP_HASH, META_HEIGHT, Store};
+
+pub const USAGE: &str = "usage: end2-cometbft [serve]
+       end2-cometbft verify
+       end2-cometbft export [HEIGHT] > dump.jsonl
+       end2-cometbft import dump.jsonl
+       end2-cometbft rebuild-tree
+
+The data directory is ABCI_DB_PATH. Stop the server before running a subcommand.";
+
+#[derive(Serialize, Deserialize)]
+struct ExportHeader {
+    height: u64,
+    app_hash: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportEntry {
+    key: String,
+    value: String,
+}
+
+// Lets `put_value_set` compute a root from scratch without touching the store.
+struct EmptyTree;
+
+impl TreeReader for EmptyTree {
+    fn get_node_option(&self, _node_key: &NodeKey) -> anyhow::Result<Option<Node>> {
+        Ok(None)
+    }
+
+    fn get_value_option(
+        &self,
+        _max_version: Version,
+        _key_hash: KeyHash,
+    ) -> anyhow::Result<Option<OwnedValue>> {
+        Ok(None)
+    }
+
+    fn get_rightmost_leaf(&self) -> anyhow::Result<Option<(NodeKey, LeafNode)>> {
+        Ok(None)
+    }
+}
+
+fn tree_updates(entries: &BTreeMap<Vec<u8>, Vec<u8>>) -> Vec<(KeyHash, Option<Vec<u8>>)> {
+    entries
+        .iter()
+        .map(|(k, v)| (KeyHash::with::<Sha256>(k), Some(v.clone())))
+        .collect()
+}
+
+// Root of a tree holding exactly `entries`. Leaf hashes don't depend on the
+// version, so this equals the root any version holding the same set would have.
+pub(crate) fn root_of(entries: &BTreeMap<Vec<u8>, Vec<u8>>) -> anyhow::Result<[u8; 32]> {
+    let (root, _) = JellyfishMerkleTree::<_, Sha256>::new(&EmptyTree)
+        .put_value_set(tree_updates(entries), 0)?;
+    Ok(root.0)
+}
+
+pub(crate) fn device_entries(store: &Store) -> anyhow::Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let mut entries = BTreeMap::new();
+    for item in store.db.iterator_cf(store.cf_device(), IteratorMode::Start) {
+        let (k, v) = item?;
+        entries.insert(k.to_vec(), v.to_vec());
+    }
+    Ok(entries)
+}
+
+// The tree's contents at `version`. A key the device column family no longer
+// holds is either a revoked device, whose id survives in its `r/` marker, or
+// the user index entry of a user who has no devices left, whose sequence
+// survives under `s/`.
+fn entries_at(store: &Store, version: Version) -> anyhow::Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let current = device_entries(store)?;
+    let revoked = current
+        .keys()
+        .filter_map(|k| k.strip_prefix(b"r/").map(<[u8]>::to_vec));
+    let indexed = current.keys().filter_map(|k| {
+        k.strip_prefix(b"s/")
+            .map(|user| [b"u/".as_slice(), user].concat())
+    });
+    let candidates: Vec<Vec<u8>> = current
+        .keys()
+        .cloned()
+        .chain(revoked)
+        .chain(indexed)
+        .collect();
+
+    let mut entries = BTreeMap::new();
+    for key in candidates {
+        if let Some(value) = store.get_value_at(&key, version)? {
+            entries.insert(key, value);
+        }
+    }
+    Ok(entries)
+}
+
+fn stored_app_hash(store: &Store) -> anyhow::Result<[u8; 32]> {
+    store
+        .last_app_hash()
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("store has no app hash; was init_chain ever run?"))
+}
+
+/// Checks the committed tree and the device column family against the app hash.
+pub fn verify(store: &Store) -> anyhow::Result<()> {
+    let height = store.last_height();
+    let app_hash = stored_app_hash(store)?;
+    println!("height       {height}");
+    println!("app hash     {}", hex::encode(app_hash));
+
+    let mut ok = true;
+    match JellyfishMerkleTree::<_, Sha256>::new(store).get_root_hash(height) {
+        Ok(root) if root.0 == app_hash => println!("tree root    {} ok", hex::encode(root.0)),
+        Ok(root) => {
+            println!("tree root    {} MISMATCH", hex::encode(root.0));
+            ok = false;
+        }
+        Err(e) => {
+            println!("tree root    unreadable: {e}");
+            ok = false;
+        }
+    }
+
+    let values_root = root_of(&device_entries(store)?)?;
+    if values_root == app_hash {
+        println!("values root  {} ok", hex::encode(values_root));
+    } else {
+        println!("values root  {} MISMATCH", hex::encode(values_root));
+        ok = false;
+    }
+
+    ensure!(
+        ok,
+        "store does not match its app hash; if only the tree is wrong, run rebuild-tree"
+    );
+    Ok(())
+}
+
+/// Writes the directory as of `height` (default: the last committed height).
+/// The latest state comes from the device column family, older ones from the
+/// versioned tree values.
+pub fn export(store: &Store, height: Option<u64>, out: &mut impl Write) -> anyhow::Result<()> {
+    let last_height = store.last_height();
+    let height = height.unwrap_or(last_height);
+    ensure!(
+        height <= last_height,
+        "height {height} is past the last committed height {last_height}"
+    );
+    let entries = if height == last_height {
+        device_entries(store)?
+    } else {
+        entries_at(store, height)?
+    };
+
+    let header = ExportHeader {
+        height,
+        app_hash: hex::encode(root_of(&entries)?),
+    };
+    serde_json::to_writer(&mut *out, &header)?;
+    writeln!(out)?;
+    for (k, v) in &entries {
+        let entry = ExportEntry {
+            key: String::from_utf8(k.clone()).context("non-utf8 key in device cf")?,
+            value: BASE64_STANDARD_NO_PAD.encode(v),
+        };
+        serde_json::to_writer(&mut *out, &entry)?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Seeds an empty store from an export. The tree starts at the exported
+/// height, so CometBFT must resume from that height too (e.g. via its own
+/// state at that height or `--initial-height`).
+pub fn import(store: &Store, input: impl BufRead) -> anyhow::Result<()> {
+    ensure!(
+        store.db.get(META_APP_HASH)?.is_none() && device_entries(store)?.is_empty(),
+        "import needs an empty data directory"
+    );
+
+    let mut lines = input.lines();
+    let header: ExportHeader = serde_json::from_str(&lines.next().context("empty export")??)
+        .context("invalid export header")?;
+    let mut entries = BTreeMap::new();
+    for line in lines {
+        let entry: ExportEntry = serde_json::from_str(&line?).context("invalid export entry")?;
+        let value = BASE64_STANDARD_NO_PAD
+            .decode(&entry.value)
+            .context("entry value is not valid base64")?;
+        entries.insert(entry.key.into_bytes(), value);
+    }
+
+    let root = root_of(&entries)?;
+    if hex::encode(root) != header.app_hash {
+        bail!(
+            "export root {} does not match its header {}",
+            hex::encode(root),
+            header.app_hash
+        );
+    }
+
+    let mut batch = WriteBatch::default();
+    for (k, v) in &entries {
+        batch.put_cf(store.cf_device(), k, v);
+    }
+    store.db.write(batch)?;
+    write_tree(store, &entries, header.height)?;
+    println!(
+        "imported {} entries at height {}, app hash {}",
+        entries.len(),
+        header.height,
+        header.app_hash
+    );
+    Ok(())
+}
+
+/// Replaces the tree with one built from the device column family at the last
+/// committed height. Older versions are gone afterwards, so historical queries
+/// and proofs only work from this height on.
+pub fn rebuild_tree(store: &Store) -> anyhow::Result<()> {
+    let height = store.last_height();
+    let app_hash = stored_app_hash(store)?;
+    let entries = device_entries(store)?;
+
+    let root = root_of(&entries)?;
+    ensure!(
+        root == app_hash,
+        "device values hash to {}, not the app hash {}; the values themselves are wrong, \
+         restore from a snapshot or an export instead",
+        hex::encode(root),
+        hex::encode(app_hash)
+    );
+
+    let mut batch = WriteBatch::default();
+    for name in [CF_JMT, CF_JMT_VALUES, CF_JMT_STALE] {
+        let cf = store.db.cf_handle(name).context("tree cf missing")?;
+        for item in store.db.iterator_cf(cf, IteratorMode::Start) {
+            let (k, _) = item?;
+            batch.delete_cf(cf, k);
+        }
+    }
+    store.db.write(batch)?;
+    write_tree(store, &entries, height)?;
+    println!(
+        "rebuilt tree for {} entries at height {height}",
+        entries.len()
+    );
+    Ok(())
+}
+
+// Writes `entries` as the only version of the tree, at `version`, the same way
+// init_chain writes the genesis state.
+pub(crate) fn write_tree(
+    store: &Store,
+    entries: &BTreeMap<Vec<u8>, Vec<u8>>,
+    version: Version,
+) -> anyhow::Result<()> {
+    if version > 0 {
+        store.write_genesis_root(version - 1)?;
+    }
+    let (root, tree_update) = JellyfishMerkleTree::<_, Sha256>::new(store)
+        .put_value_set(tree_updates(entries), version)?;
+
+    let mut batch = WriteBatch::default();
+    store.write_tree_update(&mut batch, tree_update);
+    batch.put(META_HEIGHT, version.to_le_bytes());
+    batch.put(META_APP_HASH, root.0);
+    store.db.write(batch)?;
+    Ok(())
+}
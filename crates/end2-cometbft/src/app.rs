@@ -0,0 +1,1439 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
+
+use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
+use ed25519_dalek::VerifyingKey;
+use jmt::{JellyfishMerkleTree, KeyHash, storage::TreeUpdateBatch};
+use rocksdb::WriteBatch;
+use sha2::{Digest, Sha256};
+use tendermint_abci::Application;
+use tendermint_proto::{
+    abci::{
+        Event, EventAttribute, ExecTxResult, RequestApplySnapshotChunk, RequestCheckTx,
+        RequestFinalizeBlock, RequestInfo, RequestInitChain, RequestLoadSnapshotChunk,
+        RequestOfferSnapshot, RequestPrepareProposal, RequestProcessProposal, RequestQuery,
+        ResponseApplySnapshotChunk, ResponseCheckTx, ResponseCommit, ResponseFinalizeBlock,
+        ResponseInfo, ResponseInitChain, ResponseListSnapshots, ResponseLoadSnapshotChunk,
+        ResponseOfferSnapshot, ResponsePrepareProposal, ResponseProcessProposal, ResponseQuery,
+        response_apply_snapshot_chunk, response_offer_snapshot, response_process_proposal,
+    },
+    crypto::{ProofOp, ProofOps},
+};
+
+use crate::{
+    keylog::{LogEntry, LogHead, LogKind},
+    policy::{Limits, TxCutoffs, TxError, WindowCount},
+    relayer::RelayerSet,
+    snapshot::{self, Restore},
+    stats::{UserCount, UserStats, UsersPage, parse_page},
+    store::{
+        LIMITS_KEY, META_APP_HASH, META_HEIGHT, RELAYERS_KEY, STATS_KEY, Store, TX_CUTOFFS_KEY,
+    },
+    telemetry,
+    tx::{
+        BatchEntryResult, DeviceKeys, GovernanceAction, GovernanceTx, KeyTx, SignedKeyBatch,
+        SignedKeyTx, Tx, decode_tx, is_json_tx, verify_device_keys, verify_governance_tx,
+        verify_key_batch, verify_key_tx, verify_revocation, verify_unversioned_tx,
+    },
+};
+
+pub const PROOF_OP_JMT: &str = "jmt:sha256";
+
+// A block's writes keyed by rocksdb key, layered over committed state; None is a delete.
+type Overlay = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+// What an applied tx leaves in its ExecTxResult.
+struct Applied {
+    events: Vec<Event>,
+    data: Vec<u8>,
+}
+
+impl From<Event> for Applied {
+    fn from(event: Event) -> Self {
+        Self {
+            events: vec![event],
+            data: Vec::new(),
+        }
+    }
+}
+
+// Pending state produced by finalize_block, written atomically in commit.
+struct Pending {
+    height: u64,
+    app_hash: [u8; 32],
+    // (rocksdb key, JSON(DeviceKeys)) — written into rocksdb on commit; None deletes.
+    writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    tree_update: TreeUpdateBatch,
+}
+
+#[derive(Clone)]
+pub struct KeyDirectoryApp {
+    store: Arc<Store>,
+    // ABCI_SERVER_PUBKEY. Seeds the relayer set at genesis when app_state has
+    // none, and stands in for it on chains created before the registry.
+    legacy_relayer: Option<VerifyingKey>,
+    pending: Arc<Mutex<Option<Pending>>>,
+    // Writes of the txs check_tx admitted since the last commit, so mempool
+    // txs are checked against each other as a block would apply them.
+    check_state: Arc<Mutex<Overlay>>,
+    // Take a state-sync snapshot every this many heights; 0 disables.
+    snapshot_interval: u64,
+    // Set while a snapshot is being written in the background; a snapshot
+    // height that comes up meanwhile is skipped.
+    snapshotting: Arc<AtomicBool>,
+    restore: Arc<Mutex<Option<Restore>>>,
+    // Number of most recent tree versions to keep; 0 keeps all (archive node).
+    prune_keep_versions: u64,
+}
+
+impl KeyDirectoryApp {
+    pub fn new(
+        legacy_relayer: Option<VerifyingKey>,
+        store: Store,
+        snapshot_interval: u64,
+        prune_keep_versions: u64,
+    ) -> Self {
+        Self {
+            store: Arc::new(store),
+            legacy_relayer,
+            pending: Arc::new(Mutex::new(None)),
+            check_state: Arc::new(Mutex::new(Overlay::new())),
+            snapshot_interval,
+            snapshotting: Arc::new(AtomicBool::new(false)),
+            restore: Arc::new(Mutex::new(None)),
+            prune_keep_versions,
+        }
+    }
+
+    // Committed devices for `user_hash_hex` with this block's earlier writes
+    // layered on top, so a device added earlier in the block can authorize a
+    // later one and a device revoked earlier in the block can't.
+    fn user_devices(&self, user_hash_hex: &str, overlay: &Overlay) -> BTreeMap<String, DeviceKeys> {
+        let prefix = Store::device_prefix(user_hash_hex);
+        let mut devices: BTreeMap<String, Vec<u8>> = self
+            .store
+            .iter_user_devices(user_hash_hex)
+            .into_iter()
+            .collect();
+        for (k, v) in overlay
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+        {
+            let id = String::from_utf8_lossy(&k[prefix.len()..]).into_owned();
+            match v {
+                Some(bytes) => devices.insert(id, bytes.clone()),
+                None => devices.remove(&id),
+            };
+        }
+        devices
+            .into_iter()
+            .filter_map(|(id, bytes)| {
+                serde_json::from_slice::<DeviceKeys>(&bytes)
+                    .ok()
+                    .map(|keys| (id, keys))
+            })
+            .collect()
+    }
+
+    fn is_revoked(&self, user_hash_hex: &str, device_id: &str, overlay: &Overlay) -> bool {
+        let rk = Store::revoked_key(user_hash_hex, device_id);
+        match overlay.get(&rk) {
+            Some(v) => v.is_some(),
+            None => self.store.get(&rk).is_some(),
+        }
+    }
+
+    // Next sequence number expected from `user_hash_hex`, counting this
+    // block's earlier txs.
+    fn next_sequence(&self, user_hash_hex: &str, overlay: &Overlay) -> u64 {
+        let sk = Store::sequence_key(user_hash_hex);
+        let bytes = match overlay.get(&sk) {
+            Some(v) => v.clone(),
+            None => self.store.get(&sk),
+        };
+        decode_sequence(bytes)
+    }
+
+    fn log_head(&self, user_hash_hex: &str, overlay: &Overlay) -> LogHead {
+        let hk = Store::log_head_key(user_hash_hex);
+        let bytes = match overlay.get(&hk) {
+            Some(v) => v.clone(),
+            None => self.store.get(&hk),
+        };
+        bytes
+            .and_then(|b| serde_json::from_slice(&b).ok())
+            .unwrap_or_default()
+    }
+
+    fn append_log(&self, user_hash_hex: &str, entry: &LogEntry, overlay: &mut Overlay) {
+        let bytes = serde_json::to_vec(entry).expect("LogEntry json");
+        let head = self.log_head(user_hash_hex, overlay);
+        let next = head.append(&bytes);
+        overlay.insert(Store::log_entry_key(user_hash_hex, head.count), Some(bytes));
+        overlay.insert(
+            Store::log_head_key(user_hash_hex),
+            Some(serde_json::to_vec(&next).expect("LogHead json")),
+        );
+    }
+
+    fn limits(&self, overlay: &Overlay) -> Limits {
+        let bytes = match overlay.get(LIMITS_KEY) {
+            Some(v) => v.clone(),
+            None => self.store.get(LIMITS_KEY),
+        };
+        decode_limits(bytes)
+    }
+
+    fn tx_cutoffs(&self, overlay: &Overlay) -> TxCutoffs {
+        let bytes = match overlay.get(TX_CUTOFFS_KEY) {
+            Some(v) => v.clone(),
+            None => self.store.get(TX_CUTOFFS_KEY),
+        };
+        bytes
+            .and_then(|b| serde_json::from_slice(&b).ok())
+            .unwrap_or_default()
+    }
+
+    fn window_count(&self, user_hash_hex: &str, overlay: &Overlay) -> WindowCount {
+        let wk = Store::window_key(user_hash_hex);
+        let bytes = match overlay.get(&wk) {
+            Some(v) => v.clone(),
+            None => self.store.get(&wk),
+        };
+        bytes
+            .and_then(|b| serde_json::from_slice(&b).ok())
+            .unwrap_or_default()
+    }
+
+    fn user_stats(&self, overlay: &Overlay) -> UserStats {
+        let bytes = match overlay.get(STATS_KEY) {
+            Some(v) => v.clone(),
+            None => self.store.get(STATS_KEY),
+        };
+        bytes
+            .and_then(|b| serde_json::from_slice(&b).ok())
+            .unwrap_or_default()
+    }
+
+    // Moves `user_hash_hex` from `before` to `after` live devices in the user
+    // index and the totals.
+    fn count_devices(&self, user_hash_hex: &str, before: u64, after: u64, overlay: &mut Overlay) {
+        let stats = self.user_stats(overlay).apply(before, after);
+        overlay.insert(
+            Store::user_index_key(user_hash_hex),
+            (after > 0).then(|| after.to_be_bytes().to_vec()),
+        );
+        overlay.insert(
+            STATS_KEY.to_vec(),
+            Some(serde_json::to_vec(&stats).expect("UserStats json")),
+        );
+    }
+
+    // Chains created before the user index get it built from their devices in
+    // the first block that runs this code. Every node holds the same devices at
+    // that height, so they all write the same index.
+    fn seed_user_index(&self, overlay: &mut Overlay) {
+        if self.store.get(STATS_KEY).is_some() {
+            return;
+        }
+        let mut stats = UserStats::default();
+        for (user_hash_hex, count) in self.store.count_devices_by_user() {
+            stats = stats.apply(0, count);
+            overlay.insert(
+                Store::user_index_key(&user_hash_hex),
+                Some(count.to_be_bytes().to_vec()),
+            );
+        }
+        overlay.insert(
+            STATS_KEY.to_vec(),
+            Some(serde_json::to_vec(&stats).expect("UserStats json")),
+        );
+    }
+
+    fn relayer_set(&self, overlay: &Overlay) -> Option<RelayerSet> {
+        let bytes = match overlay.get(RELAYERS_KEY) {
+            Some(v) => v.clone(),
+            None => self.store.get(RELAYERS_KEY),
+        };
+        self.decode_relayers(bytes)
+    }
+
+    fn decode_relayers(&self, bytes: Option<Vec<u8>>) -> Option<RelayerSet> {
+        match bytes {
+            Some(b) => serde_json::from_slice(&b).ok(),
+            None => self
+                .legacy_relayer
+                .map(|k| RelayerSet::new([k.to_bytes()].into(), None).expect("one-key set")),
+        }
+    }
+
+    // Checks one tx against the committed state plus `overlay` and, if it is
+    // valid, records its writes in `overlay`. `height` is the block the tx
+    // lands in. Returns the events and data to report.
+    fn execute_tx(
+        &self,
+        raw: &[u8],
+        height: u64,
+        overlay: &mut Overlay,
+    ) -> Result<Applied, TxError> {
+        let limits = self.limits(overlay);
+        limits.check_size(raw)?;
+        // Blocks below the cutoff still decode JSON, so old blocks replay.
+        if is_json_tx(raw) {
+            self.tx_cutoffs(overlay).check_json(height)?;
+        }
+        let relayers = self
+            .relayer_set(overlay)
+            .ok_or("no relayers are registered")?;
+        match decode_tx(raw)? {
+            Tx::Key(tx) => Ok(Applied {
+                events: self.execute_key_tx(*tx, &relayers, &limits, height, overlay)?,
+                data: Vec::new(),
+            }),
+            Tx::Governance(tx) => Ok(Self::execute_governance_tx(tx, &relayers, overlay)?.into()),
+            Tx::KeyBatch(tx) => self.execute_key_batch(*tx, &relayers, &limits, height, overlay),
+            Tx::Unversioned(tx) => {
+                self.tx_cutoffs(overlay).check_unversioned(height)?;
+                let payload = verify_unversioned_tx(*tx, &relayers)?;
+                let user_hash_hex = hex::encode(payload.user_hash);
+                let tx = if self
+                    .user_devices(&user_hash_hex, overlay)
+                    .contains_key(&payload.device_id)
+                {
+                    KeyTx::RotateDevice(payload)
+                } else {
+                    KeyTx::AddDevice(payload)
+                };
+                // It still takes the user's next sequence, so versioned txs
+                // signed after it line up.
+                let sequence = self.next_sequence(&user_hash_hex, overlay);
+                Ok(Applied {
+                    events: self.execute_key_entry(sequence, tx, &limits, height, overlay)?,
+                    data: Vec::new(),
+                })
+            }
+        }
+    }
+
+    fn execute_governance_tx(
+        tx: GovernanceTx,
+        relayers: &RelayerSet,
+        overlay: &mut Overlay,
+    ) -> Result<Event, &'static str> {
+        let action = verify_governance_tx(tx, relayers)?;
+        let next = relayers.apply(&action)?;
+        overlay.insert(
+            RELAYERS_KEY.to_vec(),
+            Some(serde_json::to_vec(&next).expect("RelayerSet json")),
+        );
+        match &action {
+            GovernanceAction::SetLimits { limits } => {
+                overlay.insert(
+                    LIMITS_KEY.to_vec(),
+                    Some(serde_json::to_vec(limits).expect("Limits json")),
+                );
+            }
+            GovernanceAction::SetTxCutoffs { cutoffs } => {
+                overlay.insert(
+                    TX_CUTOFFS_KEY.to_vec(),
+                    Some(serde_json::to_vec(cutoffs).expect("TxCutoffs json")),
+                );
+            }
+            _ => {}
+        }
+
+        let (name, value) = match &action {
+            GovernanceAction::AddRelayer { key } => {
+                ("add_relayer", BASE64_STANDARD_NO_PAD.encode(key))
+            }
+            GovernanceAction::RemoveRelayer { key } => {
+                ("remove_relayer", BASE64_STANDARD_NO_PAD.encode(key))
+            }
+            GovernanceAction::SetThreshold { threshold } => {
+                ("set_threshold", threshold.to_string())
+            }
+            GovernanceAction::SetLimits { limits } => (
+                "set_limits",
+                serde_json::to_string(limits).expect("Limits json"),
+            ),
+            GovernanceAction::SetTxCutoffs { cutoffs } => (
+                "set_tx_cutoffs",
+                serde_json::to_string(cutoffs).expect("TxCutoffs json"),
+            ),
+        };
+        Ok(Event {
+            r#type: "relayer_governance".to_owned(),
+            attributes: vec![
+                EventAttribute {
+                    key: "action".to_owned(),
+                    value: name.to_owned(),
+                    index: true,
+                },
+                EventAttribute {
+                    key: "value".to_owned(),
+                    value,
+                    index: true,
+                },
+                EventAttribute {
+                    key: "set_version".to_owned(),
+                    value: next.version.to_string(),
+                    index: true,
+                },
+            ],
+        })
+    }
+
+    fn execute_key_tx(
+        &self,
+        tx: SignedKeyTx,
+        relayers: &RelayerSet,
+        limits: &Limits,
+        height: u64,
+        overlay: &mut Overlay,
+    ) -> Result<Vec<Event>, TxError> {
+        let body = verify_key_tx(tx, relayers)?;
+        self.execute_key_entry(body.sequence, body.tx, limits, height, overlay)
+    }
+
+    // Applies each entry of a batch in order. The batch only fails as a whole
+    // if it is malformed or badly signed; an entry that fails gets a
+    // key_batch_rejected event and a non-zero code in the data, and leaves no
+    // writes behind.
+    fn execute_key_batch(
+        &self,
+        tx: SignedKeyBatch,
+        relayers: &RelayerSet,
+        limits: &Limits,
+        height: u64,
+        overlay: &mut Overlay,
+    ) -> Result<Applied, TxError> {
+        let body = verify_key_batch(tx, relayers)?;
+        let mut events = Vec::new();
+        let mut results = Vec::with_capacity(body.entries.len());
+        for (index, entry) in body.entries.into_iter().enumerate() {
+            let user_hash_hex = entry.tx.user_hash_hex();
+            let (entry_events, result) =
+                match self.execute_key_entry(entry.sequence, entry.tx, limits, height, overlay) {
+                    Ok(entry_events) => (
+                        entry_events,
+                        BatchEntryResult {
+                            code: 0,
+                            log: String::new(),
+                        },
+                    ),
+                    Err(err) => (
+                        vec![batch_rejected_event(&user_hash_hex, &err)],
+                        BatchEntryResult {
+                            code: err.code,
+                            log: err.log.to_owned(),
+                        },
+                    ),
+                };
+            events.extend(entry_events.into_iter().map(|mut event| {
+                event
+                    .attributes
+                    .push(indexed("batch_index", &index.to_string()));
+                event
+            }));
+            results.push(result);
+        }
+        Ok(Applied {
+            events,
+            data: serde_json::to_vec(&results).expect("BatchEntryResult json"),
+        })
+    }
+
+    // Applies one key tx whose relayer signature has already been checked.
+    // Every check runs before the first write, so an error leaves `overlay`
+    // as it was.
+    fn execute_key_entry(
+        &self,
+        sequence: u64,
+        tx: KeyTx,
+        limits: &Limits,
+        height: u64,
+        overlay: &mut Overlay,
+    ) -> Result<Vec<Event>, TxError> {
+        let user_hash_hex = tx.user_hash_hex();
+        let expected = self.next_sequence(&user_hash_hex, overlay);
+        if sequence != expected {
+            return Err("wrong sequence number; fetch the current one and re-sign".into());
+        }
+        // Every applied key tx bumps the sequence, so the distance from the
+        // committed one is the user's txs in this block (or, in check_tx, in
+        // the mempool since the last commit).
+        limits.check_block(sequence - self.next_sequence(&user_hash_hex, &Overlay::new()))?;
+        let window = limits.bump_window(self.window_count(&user_hash_hex, overlay), height)?;
+
+        let (events, entry) =
+            self.apply_key_tx(&user_hash_hex, tx, limits, height, sequence, overlay)?;
+        self.append_log(&user_hash_hex, &entry, overlay);
+        if let Some(window) = window {
+            overlay.insert(
+                Store::window_key(&user_hash_hex),
+                Some(serde_json::to_vec(&window).expect("WindowCount json")),
+            );
+        }
+        overlay.insert(
+            Store::sequence_key(&user_hash_hex),
+            Some((sequence + 1).to_be_bytes().to_vec()),
+        );
+        Ok(events)
+    }
+
+    // Returns the events to emit and the entry for the user's key log.
+    fn apply_key_tx(
+        &self,
+        user_hash_hex: &str,
+        tx: KeyTx,
+        limits: &Limits,
+        height: u64,
+        sequence: u64,
+        overlay: &mut Overlay,
+    ) -> Result<(Vec<Event>, LogEntry), TxError> {
+        let existing = self.user_devices(user_hash_hex, overlay);
+        let count = existing.len() as u64;
+
+        let (payload, event_type, kind, new_count) = match tx {
+            KeyTx::AddDevice(p) => {
+                if existing.contains_key(&p.device_id) {
+                    return Err("device already exists; use rotate_device".into());
+                }
+                limits.check_devices(existing.len())?;
+                (p, "key_add", LogKind::AddDevice, count + 1)
+            }
+            KeyTx::RotateDevice(p) => {
+                if !existing.contains_key(&p.device_id) {
+                    return Err("device does not exist; use add_device".into());
+                }
+                (p, "key_update", LogKind::RotateDevice, count)
+            }
+            KeyTx::RevokeDevice(p) => {
+                let revoked = verify_revocation(&p, &existing)?;
+                let revoked_json = serde_json::to_vec(&revoked).expect("DeviceKeys json");
+                overlay.insert(Store::device_key(user_hash_hex, &p.device_id), None);
+                overlay.insert(
+                    Store::revoked_key(user_hash_hex, &p.device_id),
+                    Some(revoked_json),
+                );
+                self.count_devices(user_hash_hex, count, count - 1, overlay);
+                let entry = LogEntry::revoke(height, sequence, &p, &revoked);
+                return Ok((
+                    key_events("key_revoke", user_hash_hex, &entry, &revoked),
+                    entry,
+                ));
+            }
+        };
+
+        if self.is_revoked(user_hash_hex, &payload.device_id, overlay) {
+            return Err("device_id has been revoked".into());
+        }
+        let new_keys = verify_device_keys(&payload, &existing)?;
+        let value_json = serde_json::to_vec(&new_keys).expect("DeviceKeys json");
+        overlay.insert(
+            Store::device_key(user_hash_hex, &payload.device_id),
+            Some(value_json),
+        );
+        if new_count != count {
+            self.count_devices(user_hash_hex, count, new_count, overlay);
+        }
+        let entry = LogEntry::upload(height, sequence, kind, &payload);
+        Ok((
+            key_events(event_type, user_hash_hex, &entry, &new_keys),
+            entry,
+        ))
+    }
+
+    // Devices `user_hash_hex` had at `version`, read from the tree. Every device
+    // that ever existed is either live or revoked now, since revoked ids can't
+    // come back, so those two sets are the candidates.
+    fn user_devices_at(
+        &self,
+        user_hash_hex: &str,
+        version: u64,
+    ) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        let mut out = Vec::new();
+        let candidates = self
+            .store
+            .iter_user_devices(user_hash_hex)
+            .into_iter()
+            .chain(self.store.iter_user_revoked(user_hash_hex));
+        for (id, _) in candidates {
+            let rk = Store::device_key(user_hash_hex, &id);
+            if let Some(value) = self.store.get_value_at(&rk, version)? {
+                out.push((id, value));
+            }
+        }
+        out.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(out)
+    }
+
+    // The user's key log as the tree held it at `version`, for the `history`
+    // query. None if the user had no log yet.
+    fn key_log_at(
+        &self,
+        user_hash_hex: &str,
+        version: u64,
+    ) -> anyhow::Result<Option<serde_json::Value>> {
+        let Some(head_bytes) = self
+            .store
+            .get_value_at(&Store::log_head_key(user_hash_hex), version)?
+        else {
+            return Ok(None);
+        };
+        let head: LogHead = serde_json::from_slice(&head_bytes)?;
+        let mut entries = Vec::with_capacity(head.count as usize);
+        for index in 0..head.count {
+            let entry = self
+                .store
+                .get_value_at(&Store::log_entry_key(user_hash_hex, index), version)?
+                .ok_or_else(|| anyhow::anyhow!("key log entry {index} is missing"))?;
+            entries.push(BASE64_STANDARD_NO_PAD.encode(entry));
+        }
+        Ok(Some(
+            serde_json::json!({ "head": head, "entries": entries }),
+        ))
+    }
+
+    fn take_snapshot(&self, height: u64, app_hash: [u8; 32]) {
+        if self.snapshotting.swap(true, Ordering::AcqRel) {
+            tracing::warn!(height, "previous snapshot still running, skipping this one");
+            return;
+        }
+        let snapshotting = Arc::clone(&self.snapshotting);
+        snapshot::spawn(Arc::clone(&self.store), height, app_hash, move |result| {
+            if let Err(e) = result {
+                tracing::error!(height, "failed to take snapshot: {e}");
+            }
+            snapshotting.store(false, Ordering::Release);
+        });
+    }
+
+    // Pruning drops the nodes of old versions, so proofs only exist inside the
+    // retention window.
+    fn proof_available(&self, version: u64, last_height: u64) -> bool {
+        self.prune_keep_versions == 0 || version + self.prune_keep_versions > last_height
+    }
+
+    // The committed value under `key` at `height`: read from the tree when
+    // `historical`, else from the store, which holds the last height's.
+    fn committed_value(
+        &self,
+        key: &[u8],
+        height: u64,
+        historical: bool,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        if historical {
+            self.store.get_value_at(key, height)
+        } else {
+            Ok(self.store.get(key))
+        }
+    }
+
+    // JMT proof for one key at `version`, borsh-encoded. Returns the value the
+    // tree holds for the key at that version alongside the op.
+    fn proof_op(&self, rk: Vec<u8>, version: u64) -> anyhow::Result<(Option<Vec<u8>>, ProofOp)> {
+        let (value, proof) = self.store.get_with_proof(&rk, version)?;
+        let op = ProofOp {
+            r#type: PROOF_OP_JMT.to_owned(),
+            key: rk,
+            data: borsh::to_vec(&proof)?,
+        };
+        Ok((value, op))
+    }
+}
+
+fn decode_sequence(bytes: Option<Vec<u8>>) -> u64 {
+    bytes
+        .and_then(|b| b.try_into().ok())
+        .map_or(0, u64::from_be_bytes)
+}
+
+fn decode_limits(bytes: Option<Vec<u8>>) -> Limits {
+    bytes
+        .and_then(|b| serde_json::from_slice(&b).ok())
+        .unwrap_or_default()
+}
+
+fn indexed(key: &str, value: &str) -> EventAttribute {
+    EventAttribute {
+        key: key.to_owned(),
+        value: value.to_owned(),
+        index: true,
+    }
+}
+
+// Hex SHA-256 of a raw 32-byte public key, as the events carry it.
+fn key_fingerprint(key: &[u8; 32]) -> String {
+    hex::encode(Sha256::digest(key))
+}
+
+// The events of an applied key tx, built from its key log entry. `keys` are
+// the uploaded keys, or for a revocation the revoked ones. Every attribute is
+// indexed so tx_search can find txs by any of them without decoding bodies.
+// An authorized upload also emits device_authorized, one edge of the user's
+// authorization graph.
+fn key_events(
+    event_type: &str,
+    user_hash_hex: &str,
+    entry: &LogEntry,
+    keys: &DeviceKeys,
+) -> Vec<Event> {
+    let sequence = entry.sequence.to_string();
+    let mut attributes = vec![
+        indexed("user_hash", user_hash_hex),
+        indexed("device_id", &entry.device_id),
+        indexed("kind", entry.kind.as_str()),
+        indexed("sequence", &sequence),
+        indexed("x25519_fingerprint", &key_fingerprint(&keys.x25519)),
+        indexed("ed25519_fingerprint", &key_fingerprint(&keys.ed25519)),
+    ];
+    if let Some(revoking_device_id) = &entry.revoking_device_id {
+        attributes.push(indexed("revoking_device_id", revoking_device_id));
+    }
+    let Some(authorization) = &entry.authorization else {
+        return vec![Event {
+            r#type: event_type.to_owned(),
+            attributes,
+        }];
+    };
+
+    attributes.push(indexed(
+        "authorizing_device_id",
+        &authorization.authorizing_device_id,
+    ));
+    vec![
+        Event {
+            r#type: event_type.to_owned(),
+            attributes,
+        },
+        Event {
+            r#type: "device_authorized".to_owned(),
+            attributes: vec![
+                indexed("user_hash", user_hash_hex),
+                indexed("device_id", &entry.device_id),
+                indexed(
+                    "authorizing_device_id",
+                    &authorization.authorizing_device_id,
+                ),
+                indexed("sequence", &sequence),
+            ],
+        },
+    ]
+}
+
+fn batch_rejected_event(user_hash_hex: &str, err: &TxError) -> Event {
+    Event {
+        r#type: "key_batch_rejected".to_owned(),
+        attributes: vec![
+            EventAttribute {
+                key: "user_hash".to_owned(),
+                value: user_hash_hex.to_owned(),
+                index: true,
+            },
+            EventAttribute {
+                key: "code".to_owned(),
+                value: err.code.to_string(),
+                index: false,
+            },
+            EventAttribute {
+                key: "log".to_owned(),
+                value: err.log.to_owned(),
+                index: false,
+            },
+        ],
+    }
+}
+
+// The order a proposal applies `txs` in: each user's key txs are gathered at
+// the position of that user's first one and sorted by sequence, so updates to
+// the same device land in the order they were signed whatever order the
+// mempool saw them in. Governance txs, key batches and undecodable ones keep
+// their place.
+fn proposal_order<T: AsRef<[u8]>>(txs: &[T]) -> Vec<usize> {
+    let mut slots: HashMap<[u8; 32], usize> = HashMap::new();
+    let mut keyed: Vec<(usize, u64, usize)> = txs
+        .iter()
+        .enumerate()
+        .map(|(i, raw)| match decode_tx(raw.as_ref()) {
+            Ok(Tx::Key(tx)) => {
+                let body = tx.unverified_body();
+                let slot = *slots.entry(body.tx.user_hash()).or_insert(i);
+                (slot, body.sequence, i)
+            }
+            _ => (i, 0, i),
+        })
+        .collect();
+    keyed.sort_unstable();
+    keyed.into_iter().map(|(_, _, i)| i).collect()
+}
+
+// What `raw` counts for against max_tx_bytes. CometBFT sizes the txs with
+// ComputeProtoSizeForTxs, which also counts each one's field tag and length
+// varint in the block's Data.
+fn proto_tx_size(raw: &[u8]) -> usize {
+    let len_bits = usize::BITS - (raw.len() | 1).leading_zeros();
+    1 + len_bits.div_ceil(7) as usize + raw.len()
+}
+
+fn check_tx_err(err: &TxError) -> ResponseCheckTx {
+    ResponseCheckTx {
+        code: err.code,
+        log: err.log.to_owned(),
+        ..Default::default()
+    }
+}
+
+fn err_query(msg: &str) -> ResponseQuery {
+    ResponseQuery {
+        code: 1,
+        log: msg.to_owned(),
+        ..Default::default()
+    }
+}
+
+impl Application for KeyDirectoryApp {
+    fn info(&self, _req: RequestInfo) -> ResponseInfo {
+        let height = self.store.last_height();
+        let app_hash = self.store.last_app_hash();
+        ResponseInfo {
+            data: "end2-cometbft".to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            app_version: 1,
+            last_block_height: height as i64,
+            last_block_app_hash: app_hash.into(),
+        }
+    }
+
+    // The genesis relayer set is committed as tree version initial_height-1, so
+    // the first block builds on it and the returned app hash covers it.
+    fn init_chain(&self, req: RequestInitChain) -> ResponseInitChain {
+        let relayers = RelayerSet::from_genesis(&req.app_state_bytes)
+            .expect("invalid relayers in genesis app_state")
+            .or_else(|| self.relayer_set(&Overlay::new()))
+            .expect("genesis app_state must list relayers when ABCI_SERVER_PUBKEY is unset");
+        let limits = Limits::from_genesis(&req.app_state_bytes)
+            .expect("invalid limits in genesis app_state");
+        let initial_height = req.initial_height.max(1) as u64;
+        let tx_cutoffs = TxCutoffs::from_genesis(&req.app_state_bytes, initial_height)
+            .expect("invalid tx_cutoffs in genesis app_state");
+
+        let genesis_version = initial_height - 1;
+        if genesis_version > 0 {
+            self.store
+                .write_genesis_root(genesis_version - 1)
+                .expect("failed to write genesis JMT root");
+        }
+
+        let genesis_state = [
+            (
+                RELAYERS_KEY,
+                serde_json::to_vec(&relayers).expect("RelayerSet json"),
+            ),
+            (
+                LIMITS_KEY,
+                serde_json::to_vec(&limits).expect("Limits json"),
+            ),
+            (
+                STATS_KEY,
+                serde_json::to_vec(&UserStats::default()).expect("UserStats json"),
+            ),
+            (
+                TX_CUTOFFS_KEY,
+                serde_json::to_vec(&tx_cutoffs).expect("TxCutoffs json"),
+            ),
+        ];
+        let tree = JellyfishMerkleTree::<_, Sha256>::new(self.store.as_ref());
+        let (root_hash, tree_update) = tree
+            .put_value_set(
+                genesis_state
+                    .iter()
+                    .map(|(k, v)| (KeyHash::with::<Sha256>(k), Some(v.clone()))),
+                genesis_version,
+            )
+            .expect("JMT put_value_set failed");
+
+        let mut batch = WriteBatch::default();
+        for (k, v) in genesis_state {
+            batch.put_cf(self.store.cf_device(), k, v);
+        }
+        self.store.write_tree_update(&mut batch, tree_update);
+        batch.put(META_APP_HASH, root_hash.0);
+        self.store
+            .db
+            .write(batch)
+            .expect("rocksdb init_chain write failed");
+
+        ResponseInitChain {
+            app_hash: root_hash.0.to_vec().into(),
+            ..Default::default()
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(tx_len = req.tx.len()))]
+    fn check_tx(&self, req: RequestCheckTx) -> ResponseCheckTx {
+        let height = self.store.last_height() + 1;
+        let result = self.execute_tx(
+            &req.tx,
+            height,
+            &mut self.check_state.lock().expect("lock check state"),
+        );
+        match result {
+            Ok(_) => {
+                telemetry::tx_accepted("check_tx");
+                ResponseCheckTx::default()
+            }
+            Err(err) => {
+                tracing::debug!(code = err.code, reason = err.log, "check_tx rejected");
+                telemetry::tx_rejected("check_tx", err.log);
+                check_tx_err(&err)
+            }
+        }
+    }
+
+    /// Builds the block from the mempool's txs: reorders them with
+    /// `proposal_order`, then keeps only those that apply on top of the ones
+    /// kept before them and fit in `max_tx_bytes`. Exact duplicates are
+    /// dropped without being checked again.
+    #[tracing::instrument(skip_all, fields(height = req.height, txs = req.txs.len()))]
+    fn prepare_proposal(&self, req: RequestPrepareProposal) -> ResponsePrepareProposal {
+        let height = req.height as u64;
+        let max_tx_bytes = usize::try_from(req.max_tx_bytes).unwrap_or(0);
+        let mut seen = HashSet::new();
+        let mut overlay = Overlay::new();
+        let mut total_bytes = 0_usize;
+        let mut txs = Vec::with_capacity(req.txs.len());
+        for i in proposal_order(&req.txs) {
+            let raw = &req.txs[i];
+            if total_bytes + proto_tx_size(raw) > max_tx_bytes
+                || !seen.insert(<[u8; 32]>::from(Sha256::digest(raw)))
+            {
+                continue;
+            }
+            match self.execute_tx(raw, height, &mut overlay) {
+                Ok(_) => {
+                    total_bytes += proto_tx_size(raw);
+                    txs.push(raw.clone());
+                }
+                Err(err) => {
+                    tracing::debug!(code = err.code, reason = err.log, "tx left out of proposal");
+                    telemetry::tx_rejected("prepare_proposal", err.log);
+                }
+            }
+        }
+        ResponsePrepareProposal { txs }
+    }
+
+    /// Rejects a proposal holding any tx that would fail in `finalize_block`,
+    /// which `prepare_proposal` never includes, so only a faulty or malicious
+    /// proposer can produce one.
+    #[tracing::instrument(skip_all, fields(height = req.height, txs = req.txs.len()))]
+    fn process_proposal(&self, req: RequestProcessProposal) -> ResponseProcessProposal {
+        let height = req.height as u64;
+        let mut overlay = Overlay::new();
+        let failed = req.txs.iter().enumerate().find_map(|(i, raw)| {
+            self.execute_tx(raw, height, &mut overlay)
+                .err()
+                .map(|err| (i, err))
+        });
+        let status = match &failed {
+            None => response_process_proposal::ProposalStatus::Accept,
+            Some((index, err)) => {
+                tracing::warn!(
+                    index,
+                    code = err.code,
+                    reason = err.log,
+                    "rejecting proposal"
+                );
+                response_process_proposal::ProposalStatus::Reject
+            }
+        };
+        telemetry::proposal_processed(failed.is_none());
+        ResponseProcessProposal {
+            status: status as i32,
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(height = req.height, txs = req.txs.len()))]
+    fn finalize_block(&self, req: RequestFinalizeBlock) -> ResponseFinalizeBlock {
+        let start = Instant::now();
+        let mut overlay = Overlay::new();
+        self.seed_user_index(&mut overlay);
+        let tx_results: Vec<ExecTxResult> = req
+            .txs
+            .iter()
+            .map(
+                |raw| match self.execute_tx(raw, req.height as u64, &mut overlay) {
+                    Ok(applied) => {
+                        telemetry::tx_accepted("finalize_block");
+                        ExecTxResult {
+                            data: applied.data.into(),
+                            events: applied.events,
+                            ..Default::default()
+                        }
+                    }
+                    Err(err) => {
+                        tracing::debug!(code = err.code, reason = err.log, "tx rejected");
+                        telemetry::tx_rejected("finalize_block", err.log);
+                        ExecTxResult {
+                            code: err.code,
+                            log: err.log.to_owned(),
+                            ..Default::default()
+                        }
+                    }
+                },
+            )
+            .collect();
+
+        let tree_updates: Vec<(KeyHash, Option<Vec<u8>>)> = overlay
+            .iter()
+            .map(|(k, v)| (KeyHash::with::<Sha256>(k), v.clone()))
+            .collect();
+
+        let version = req.height as u64;
+
+        let tree = JellyfishMerkleTree::<_, Sha256>::new(self.store.as_ref());
+        let (root_hash, tree_update) = tree
+            .put_value_set(tree_updates, version)
+            .expect("JMT put_value_set failed");
+
+        let writes: Vec<(Vec<u8>, Option<Vec<u8>>)> = overlay.into_iter().collect();
+        *self.pending.lock().expect("lock pending") = Some(Pending {
+            height: version,
+            app_hash: root_hash.0,
+            writes,
+            tree_update,
+        });
+        telemetry::block_finalized(req.txs.len(), start.elapsed());
+
+        ResponseFinalizeBlock {
+            tx_results,
+            app_hash: root_hash.0.to_vec().into(),
+            ..Default::default()
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(height = tracing::field::Empty))]
+    fn commit(&self) -> ResponseCommit {
+        let start = Instant::now();
+        let pending = self.pending.lock().unwrap().take();
+        if let Some(p) = pending {
+            tracing::Span::current().record("height", p.height);
+            let nodes_written = p.tree_update.node_batch.nodes().len();
+            let mut batch = WriteBatch::default();
+
+            for (k, v) in p.writes {
+                match v {
+                    Some(v) => batch.put_cf(self.store.cf_device(), k, v),
+                    None => batch.delete_cf(self.store.cf_device(), k),
+                }
+            }
+
+            self.store.write_tree_update(&mut batch, p.tree_update);
+
+            batch.put(META_HEIGHT, p.height.to_le_bytes());
+            batch.put(META_APP_HASH, p.app_hash);
+
+            let mut wo = rocksdb::WriteOptions::default();
+            wo.set_sync(true);
+            self.store
+                .db
+                .write_opt(batch, &wo)
+                .expect("rocksdb commit write failed");
+            // CometBFT rechecks what is left in the mempool against the new state.
+            *self.check_state.lock().expect("lock check state") = Overlay::new();
+            telemetry::block_committed(&self.store, p.height, nodes_written, start.elapsed());
+
+            if self.snapshot_interval > 0 && p.height.is_multiple_of(self.snapshot_interval) {
+                self.take_snapshot(p.height, p.app_hash);
+            }
+
+            if self.prune_keep_versions > 0 && p.height > self.prune_keep_versions {
+                let oldest_kept = p.height - self.prune_keep_versions + 1;
+                if let Err(e) = self.store.prune_stale_nodes(oldest_kept) {
+                    tracing::error!(height = p.height, "failed to prune stale nodes: {e}");
+                }
+            }
+        }
+        ResponseCommit::default()
+    }
+
+    fn list_snapshots(&self) -> ResponseListSnapshots {
+        ResponseListSnapshots {
+            snapshots: snapshot::list(&self.store),
+        }
+    }
+
+    fn offer_snapshot(&self, req: RequestOfferSnapshot) -> ResponseOfferSnapshot {
+        let Some(offered) = req.snapshot else {
+            return ResponseOfferSnapshot {
+                result: response_offer_snapshot::Result::Reject.into(),
+            };
+        };
+
+        let result = match Restore::offer(&offered, &req.app_hash) {
+            Ok(restore) => {
+                // CometBFT moves on to another snapshot without telling us
+                // when fetching chunks of the last one stalls, so rows it left
+                // behind have to go first.
+                let mut guard = self.restore.lock().expect("lock restore");
+                match snapshot::clear_state(&self.store) {
+                    Ok(()) => {
+                        *guard = Some(restore);
+                        response_offer_snapshot::Result::Accept
+                    }
+                    Err(e) => {
+                        tracing::error!("failed to clear state for snapshot: {e}");
+                        *guard = None;
+                        response_offer_snapshot::Result::Abort
+                    }
+                }
+            }
+            Err(result) => result,
+        };
+        ResponseOfferSnapshot {
+            result: result.into(),
+        }
+    }
+
+    fn load_snapshot_chunk(&self, req: RequestLoadSnapshotChunk) -> ResponseLoadSnapshotChunk {
+        ResponseLoadSnapshotChunk {
+            chunk: snapshot::load_chunk(&self.store, req.height, req.format, req.chunk)
+                .unwrap_or_default()
+                .into(),
+        }
+    }
+
+    fn apply_snapshot_chunk(&self, req: RequestApplySnapshotChunk) -> ResponseApplySnapshotChunk {
+        let mut guard = self.restore.lock().expect("lock restore");
+        let Some(restore) = guard.as_mut() else {
+            return ResponseApplySnapshotChunk {
+                result: response_apply_snapshot_chunk::Result::Abort.into(),
+                ..Default::default()
+            };
+        };
+
+        match restore.apply(&self.store, req.index, &req.chunk) {
+            Ok(done) => {
+                if done {
+                    *guard = None;
+                }
+                ResponseApplySnapshotChunk {
+                    result: response_apply_snapshot_chunk::Result::Accept.into(),
+                    ..Default::default()
+                }
+            }
+            Err(snapshot::ApplyError::BadChunk) => ResponseApplySnapshotChunk {
+                result: response_apply_snapshot_chunk::Result::Retry.into(),
+                refetch_chunks: vec![req.index],
+                reject_senders: vec![req.sender],
+            },
+            Err(e) => {
+                if let snapshot::ApplyError::Store(err) = &e {
+                    tracing::error!(index = req.index, "failed to apply snapshot chunk: {err}");
+                    if let Err(err) = snapshot::clear_state(&self.store) {
+                        tracing::error!("failed to clear state after snapshot: {err}");
+                    }
+                }
+                *guard = None;
+                ResponseApplySnapshotChunk {
+                    result: response_apply_snapshot_chunk::Result::from(e).into(),
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    /// Supported query paths. Store key is `hex(user_hash)`, a 64-char hex string.
+    ///
+    /// `"device"` - fetch one device's keys.
+    /// `data` = `"<hex_user_hash>:<device_id>"` as UTF-8, then hex-encoded for the RPC.
+    /// Returns `DeviceKeys` as JSON in `response.value` (RPC base64-encodes it).
+    ///
+    /// `"devices"` - fetch all devices for a user.
+    /// `data` = `"<hex_user_hash>"` as UTF-8, then hex-encoded for the RPC.
+    /// Returns `HashMap<device_id, DeviceKeys>` as JSON.
+    ///
+    /// With `prove = true`, `proof_ops` carries one `"jmt:sha256"` op per device:
+    /// `key` is the store key `"<hex_user_hash>/<device_id>"` and `data` is a
+    /// borsh-encoded `SparseMerkleProof<Sha256>` against the app hash at
+    /// `response.height`, proving the JSON value exactly as returned. A `devices`
+    /// query adds one last op for `"u/<hex_user_hash>"`, whose value is the
+    /// user's live device count as a big-endian u64, so a complete list can be
+    /// told from one with devices left out. A `device` query for a missing
+    /// device, or a `devices` query for a user with none, still returns a
+    /// non-zero code, but with the non-inclusion proof of its key attached.
+    ///
+    /// `"sequence"` - the sequence number the next tx for a user must carry.
+    /// `data` = `"<hex_user_hash>"` as UTF-8, then hex-encoded for the RPC.
+    /// Returns the number as a decimal string; 0 for a user with no txs.
+    ///
+    /// `"relayers"` - the relayer set allowed to submit txs, `data` ignored.
+    /// Returns `RelayerSet` as JSON.
+    ///
+    /// `"limits"` - the admission limits txs are held to, `data` ignored.
+    /// Returns `Limits` as JSON.
+    ///
+    /// `"history"` - the user's key log, see `keylog`.
+    /// `data` = `"<hex_user_hash>"` as UTF-8, then hex-encoded for the RPC.
+    /// Returns `{"head": LogHead, "entries": [<base64 of each stored entry>]}`
+    /// as JSON. With `prove = true`, `proof_ops` carries the `"jmt:sha256"` op
+    /// for the head key `"h/<hex_user_hash>"`, whose value is the head as JSON;
+    /// a user without a log gets a non-zero code and its non-inclusion proof.
+    ///
+    /// `"stats"` - chain-wide totals, `data` ignored.
+    /// Returns `{"users": <users with a live device>, "devices": <live devices>}`.
+    ///
+    /// `"users"` - one page of users with a live device, in user hash order.
+    /// `data` = `"[<after_hex_user_hash>][:<limit>]"`; an empty `after` starts at
+    /// the beginning and `limit` defaults to 100, at most 1000.
+    /// Returns `{"users": [{"user_hash", "devices"}], "next"}` as JSON, where
+    /// `next`, absent on the last page, is the `after` for the following page.
+    /// Only served at the last committed height.
+    ///
+    /// All but `users` honor `height`: 0 means the last committed
+    /// height, anything else reads the tree as of that height. Proofs need the
+    /// tree nodes of that height, which a pruning node only keeps for the last
+    /// ABCI_PRUNE_KEEP_VERSIONS heights.
+    ///
+    /// Revoked devices are absent from `device` and `devices`. Their last keys stay in the
+    /// tree under `"r/<hex_user_hash>/<device_id>"`.
+    fn query(&self, req: RequestQuery) -> ResponseQuery {
+        let last_height = self.store.last_height();
+        let height = match u64::try_from(req.height) {
+            Ok(0) => last_height,
+            Ok(h) if h <= last_height => h,
+            _ => {
+                return err_query(&format!(
+                    "height {} is not a committed height (last is {last_height})",
+                    req.height
+                ));
+            }
+        };
+        let historical = height != last_height;
+        if req.prove && !self.proof_available(height, last_height) {
+            return err_query(&format!(
+                "tree nodes for height {height} have been pruned; query without prove"
+            ));
+        }
+
+        match req.path.as_str() {
+            "device" => {
+                let raw = String::from_utf8_lossy(&req.data);
+                let Some((user_hash_hex, device_id)) = raw.split_once(':') else {
+                    return err_query("data must be '<hex_user_hash>:<device_id>'");
+                };
+                let (value, proof_ops) = if req.prove {
+                    match self.proof_op(Store::device_key(user_hash_hex, device_id), height) {
+                        Ok((value, op)) => (value, Some(ProofOps { ops: vec![op] })),
+                        Err(e) => return err_query(&format!("failed to build proof: {e}")),
+                    }
+                } else if historical {
+                    let rk = Store::device_key(user_hash_hex, device_id);
+                    match self.store.get_value_at(&rk, height) {
+                        Ok(value) => (value, None),
+                        Err(e) => return err_query(&format!("failed to read tree: {e}")),
+                    }
+                } else {
+                    (self.store.get_device(user_hash_hex, device_id), None)
+                };
+
+                match value {
+                    Some(bytes) => ResponseQuery {
+                        value: bytes.into(),
+                        proof_ops,
+                        height: height as i64,
+                        ..Default::default()
+                    },
+                    None => ResponseQuery {
+                        proof_ops,
+                        height: height as i64,
+                        ..err_query(&format!(
+                            "no device '{device_id}' for hash '{user_hash_hex}'"
+                        ))
+                    },
+                }
+            }
+
+            "devices" => {
+                let user_hash_hex = String::from_utf8_lossy(&req.data);
+                let v = if historical {
+                    match self.user_devices_at(&user_hash_hex, height) {
+                        Ok(v) => v,
+                        Err(e) => return err_query(&format!("failed to read tree: {e}")),
+                    }
+                } else {
+                    self.store.iter_user_devices(user_hash_hex.as_ref())
+                };
+                // The live device count under `u/`, proven last, shows the
+                // list is complete; its absence proves there are none.
+                let count_op = if req.prove {
+                    match self.proof_op(Store::user_index_key(&user_hash_hex), height) {
+                        Ok((_, op)) => Some(op),
+                        Err(e) => return err_query(&format!("failed to build proof: {e}")),
+                    }
+                } else {
+                    None
+                };
+                if v.is_empty() {
+                    return ResponseQuery {
+                        proof_ops: count_op.map(|op| ProofOps { ops: vec![op] }),
+                        height: height as i64,
+                        ..err_query(&format!("no devices for hash '{user_hash_hex}'"))
+                    };
+                }
+
+                let mut ops = Vec::new();
+                let mut map = serde_json::Map::with_capacity(v.len());
+                for (id, bytes) in v {
+                    if req.prove {
+                        match self.proof_op(Store::device_key(&user_hash_hex, &id), height) {
+                            Ok((_, op)) => ops.push(op),
+                            Err(e) => return err_query(&format!("failed to build proof: {e}")),
+                        }
+                    }
+                    let val: serde_json::Value =
+                        serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null);
+                    map.insert(id, val);
+                }
+                ops.extend(count_op);
+                ResponseQuery {
+                    value: serde_json::to_vec(&serde_json::Value::Object(map))
+                        .unwrap_or_default()
+                        .into(),
+                    proof_ops: req.prove.then_some(ProofOps { ops }),
+                    height: height as i64,
+                    ..Default::default()
+                }
+            }
+
+            "sequence" => {
+                let user_hash_hex = String::from_utf8_lossy(&req.data);
+                let sk = Store::sequence_key(&user_hash_hex);
+                match self.committed_value(&sk, height, historical) {
+                    Ok(bytes) => ResponseQuery {
+                        value: decode_sequence(bytes).to_string().into_bytes().into(),
+                        height: height as i64,
+                        ..Default::default()
+                    },
+                    Err(e) => err_query(&format!("failed to read tree: {e}")),
+                }
+            }
+
+            "history" => {
+                let user_hash_hex = String::from_utf8_lossy(&req.data);
+                match self.key_log_at(&user_hash_hex, height) {
+                    Ok(Some(log)) => {
+                        let proof_ops = if req.prove {
+                            let hk = Store::log_head_key(&user_hash_hex);
+                            match self.proof_op(hk, height) {
+                                Ok((_, op)) => Some(ProofOps { ops: vec![op] }),
+                                Err(e) => return err_query(&format!("failed to build proof: {e}")),
+                            }
+                        } else {
+                            None
+                        };
+                        ResponseQuery {
+                            value: serde_json::to_vec(&log).unwrap_or_default().into(),
+                            proof_ops,
+                            height: height as i64,
+                            ..Default::default()
+                        }
+                    }
+                    Ok(None) => {
+                        let proof_ops = if req.prove {
+                            match self.proof_op(Store::log_head_key(&user_hash_hex), height) {
+                                Ok((_, op)) => Some(ProofOps { ops: vec![op] }),
+                                Err(e) => return err_query(&format!("failed to build proof: {e}")),
+                            }
+                        } else {
+                            None
+                        };
+                        ResponseQuery {
+                            proof_ops,
+                            height: height as i64,
+                            ..err_query(&format!("no key log for hash '{user_hash_hex}'"))
+                        }
+                    }
+                    Err(e) => err_query(&format!("failed to read tree: {e}")),
+                }
+            }
+
+            "relayers" => match self.committed_value(RELAYERS_KEY, height, historical) {
+                Ok(bytes) => match self.decode_relayers(bytes) {
+                    Some(set) => ResponseQuery {
+                        value: serde_json::to_vec(&set).unwrap_or_default().into(),
+                        height: height as i64,
+                        ..Default::default()
+                    },
+                    None => err_query("no relayers are registered"),
+                },
+                Err(e) => err_query(&format!("failed to read tree: {e}")),
+            },
+
+            "limits" => match self.committed_value(LIMITS_KEY, height, historical) {
+                Ok(bytes) => ResponseQuery {
+                    value: serde_json::to_vec(&decode_limits(bytes))
+                        .unwrap_or_default()
+                        .into(),
+                    height: height as i64,
+                    ..Default::default()
+                },
+                Err(e) => err_query(&format!("failed to read tree: {e}")),
+            },
+
+            "stats" => {
+                let stats = if historical {
+                    match self.store.get_value_at(STATS_KEY, height) {
+                        Ok(bytes) => bytes
+                            .and_then(|b| serde_json::from_slice(&b).ok())
+                            .unwrap_or_default(),
+                        Err(e) => return err_query(&format!("failed to read tree: {e}")),
+                    }
+                } else {
+                    self.user_stats(&Overlay::new())
+                };
+                ResponseQuery {
+                    value: serde_json::to_vec(&stats).unwrap_or_default().into(),
+                    height: height as i64,
+                    ..Default::default()
+                }
+            }
+
+            "users" => {
+                if historical {
+                    return err_query("'users' only lists the last committed height");
+                }
+                let data = String::from_utf8_lossy(&req.data);
+                let (after, limit) = match parse_page(&data) {
+                    Ok(page) => page,
+                    Err(e) => return err_query(e),
+                };
+                let (users, more) = self.store.iter_user_index(after, limit);
+                let page = UsersPage {
+                    next: more.then(|| users.last().map(|(u, _)| u.clone())).flatten(),
+                    users: users
+                        .into_iter()
+                        .map(|(user_hash, devices)| UserCount { user_hash, devices })
+                        .collect(),
+                };
+                ResponseQuery {
+                    value: serde_json::to_vec(&page).unwrap_or_default().into(),
+                    height: height as i64,
+                    ..Default::default()
+                }
+            }
+
+            other => err_query(&format!(
+                "unknown path '{other}': use 'device', 'devices', 'sequence', 'history', \
+                 'relayers', 'limits', 'stats' or 'users'"
+            )),
+        }
+    }
+}
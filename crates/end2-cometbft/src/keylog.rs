@@ -0,0 +1,115 @@
+// Every key event a user's devices went through, in order, kept in the tree
+// next to the devices themselves. Entries are hash-chained and the chain's head
+// is its own tree key, so one proof of the head commits to the whole log: a
+// client recomputes the chain over the entries it was given and compares.
+//
+// head.hash = sha256(previous head.hash || entry bytes), starting from 32 zero
+// bytes, where the entry bytes are exactly the stored value.
+
+use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::tx::{DeviceKeys, KeyPayload, RevokePayload};
+
+// Named after the tx types.
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+pub enum LogKind {
+    AddDevice,
+    RotateDevice,
+    RevokeDevice,
+}
+
+impl LogKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AddDevice => "add_device",
+            Self::RotateDevice => "rotate_device",
+            Self::RevokeDevice => "revoke_device",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct LogAuthorization {
+    pub authorizing_device_id: String,
+    pub signature: String,
+}
+
+// Binary fields are unpadded base64, as in the JSON txs.
+#[derive(Serialize)]
+pub struct LogEntry {
+    pub height: u64,
+    pub sequence: u64,
+    #[serde(rename = "type")]
+    pub kind: LogKind,
+    pub device_id: String,
+    // the uploaded keys, or for a revocation the keys that were revoked
+    pub x25519: String,
+    pub ed25519: String,
+    // the device's self-signature, or for a revocation the revoking device's
+    pub signature: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authorization: Option<LogAuthorization>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revoking_device_id: Option<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct LogHead {
+    pub count: u64,
+    #[serde(with = "hex::serde")]
+    pub hash: [u8; 32],
+}
+
+impl LogEntry {
+    pub fn upload(height: u64, sequence: u64, kind: LogKind, payload: &KeyPayload) -> Self {
+        Self {
+            height,
+            sequence,
+            kind,
+            device_id: payload.device_id.clone(),
+            x25519: BASE64_STANDARD_NO_PAD.encode(payload.x25519),
+            ed25519: BASE64_STANDARD_NO_PAD.encode(payload.ed25519),
+            signature: BASE64_STANDARD_NO_PAD.encode(payload.signature),
+            authorization: payload.authorization.as_ref().map(|a| LogAuthorization {
+                authorizing_device_id: a.authorizing_device_id.clone(),
+                signature: BASE64_STANDARD_NO_PAD.encode(a.signature),
+            }),
+            revoking_device_id: None,
+        }
+    }
+
+    pub fn revoke(
+        height: u64,
+        sequence: u64,
+        payload: &RevokePayload,
+        revoked: &DeviceKeys,
+    ) -> Self {
+        Self {
+            height,
+            sequence,
+            kind: LogKind::RevokeDevice,
+            device_id: payload.device_id.clone(),
+            x25519: BASE64_STANDARD_NO_PAD.encode(revoked.x25519),
+            ed25519: BASE64_STANDARD_NO_PAD.encode(revoked.ed25519),
+            signature: BASE64_STANDARD_NO_PAD.encode(payload.signature),
+            authorization: None,
+            revoking_device_id: Some(payload.revoking_device_id.clone()),
+        }
+    }
+}
+
+impl LogHead {
+    pub fn append(&self, entry: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(self.hash);
+        hasher.update(entry);
+        Self {
+            count: self.count + 1,
+            hash: hasher.finalize().into(),
+        }
+    }
+}
@@ -0,0 +1,16 @@
+//! The end2 key directory as a CometBFT ABCI application. `main.rs` serves
+//! it over a socket; tests drive `KeyDirectoryApp` in-process.
+
+pub mod admin;
+mod app;
+pub mod keylog;
+pub mod policy;
+pub mod relayer;
+mod snapshot;
+pub mod stats;
+pub mod store;
+pub mod telemetry;
+pub mod tx;
+
+pub use app::{KeyDirectoryApp, PROOF_OP_JMT};
+pub use store::Store;
@@ -0,0 +1,172 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+
+// ResponseCheckTx / ExecTxResult codes. Anything that fails validation is
+// CODE_INVALID; each limit gets its own code so a relayer can tell a tx that
+// will never apply from one it should retry later.
+pub const CODE_INVALID: u32 = 1;
+pub const CODE_TX_TOO_LARGE: u32 = 2;
+pub const CODE_USER_BLOCK_LIMIT: u32 = 3;
+pub const CODE_USER_WINDOW_LIMIT: u32 = 4;
+pub const CODE_DEVICE_LIMIT: u32 = 5;
+
+pub struct TxError {
+    pub code: u32,
+    pub log: &'static str,
+}
+
+impl From<&'static str> for TxError {
+    fn from(log: &'static str) -> Self {
+        Self {
+            code: CODE_INVALID,
+            log,
+        }
+    }
+}
+
+/// Admission limits. They decide which txs a block applies, so they live in
+/// the tree like the relayer set: seeded from `"limits"` in the genesis
+/// app_state and changed by a SetLimits governance tx. 0 disables a limit.
+#[derive(Clone, Copy, Default, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
+#[serde(default)]
+pub struct Limits {
+    pub max_tx_bytes: u64,
+    // key txs per user_hash
+    pub max_user_txs_per_block: u32,
+    pub max_user_txs_per_window: u32,
+    // the window is fixed: heights [n * window_blocks, (n + 1) * window_blocks)
+    pub window_blocks: u64,
+    pub max_devices_per_user: u32,
+}
+
+// Key txs a user landed in the current window, under `w/<user_hash_hex>`.
+#[derive(Default, Serialize, Deserialize)]
+pub struct WindowCount {
+    pub window: u64,
+    pub count: u32,
+}
+
+/// Heights from which blocks stop accepting an older tx encoding; 0 never
+/// does. Like `Limits` they decide which txs a block applies, so they live in
+/// the tree: seeded from `"tx_cutoffs"` in the genesis app_state and changed
+/// by a SetTxCutoffs governance tx. A chain from before they existed has none
+/// until that tx lands, and accepts every encoding meanwhile.
+#[derive(Clone, Copy, Default, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
+#[serde(default)]
+pub struct TxCutoffs {
+    // the JSON upload from before txs carried a version and a sequence
+    pub unversioned_height: u64,
+    // every JSON tx, versioned or not, from before the binary encoding
+    pub json_height: u64,
+}
+
+#[derive(Deserialize)]
+struct GenesisAppState {
+    limits: Option<Limits>,
+    tx_cutoffs: Option<TxCutoffs>,
+}
+
+impl Limits {
+    pub fn from_genesis(app_state: &[u8]) -> Result<Self, &'static str> {
+        if app_state.is_empty() {
+            return Ok(Self::default());
+        }
+        let genesis: GenesisAppState =
+            serde_json::from_slice(app_state).map_err(|_| "app_state is not valid JSON")?;
+        Ok(genesis.limits.unwrap_or_default())
+    }
+
+    pub fn check_size(&self, tx: &[u8]) -> Result<(), TxError> {
+        if self.max_tx_bytes > 0 && tx.len() as u64 > self.max_tx_bytes {
+            return Err(TxError {
+                code: CODE_TX_TOO_LARGE,
+                log: "tx exceeds the maximum tx size",
+            });
+        }
+        Ok(())
+    }
+
+    /// `in_block` is how many key txs the user already has in this block.
+    pub fn check_block(&self, in_block: u64) -> Result<(), TxError> {
+        if self.max_user_txs_per_block > 0 && in_block >= u64::from(self.max_user_txs_per_block) {
+            return Err(TxError {
+                code: CODE_USER_BLOCK_LIMIT,
+                log: "too many key txs for this user in one block",
+            });
+        }
+        Ok(())
+    }
+
+    /// The user's window counter after one more key tx at `height`, or None if
+    /// the window limit is off.
+    pub fn bump_window(
+        &self,
+        current: WindowCount,
+        height: u64,
+    ) -> Result<Option<WindowCount>, TxError> {
+        if self.max_user_txs_per_window == 0 || self.window_blocks == 0 {
+            return Ok(None);
+        }
+        let window = height / self.window_blocks;
+        let count = if current.window == window {
+            current.count
+        } else {
+            0
+        };
+        if count >= self.max_user_txs_per_window {
+            return Err(TxError {
+                code: CODE_USER_WINDOW_LIMIT,
+                log: "too many key txs for this user in the current window",
+            });
+        }
+        Ok(Some(WindowCount {
+            window,
+            count: count + 1,
+        }))
+    }
+
+    /// `devices` is how many live devices the user has before an add.
+    pub fn check_devices(&self, devices: usize) -> Result<(), TxError> {
+        if self.max_devices_per_user > 0 && devices >= self.max_devices_per_user as usize {
+            return Err(TxError {
+                code: CODE_DEVICE_LIMIT,
+                log: "user already has the maximum number of devices",
+            });
+        }
+        Ok(())
+    }
+}
+
+impl TxCutoffs {
+    /// Cutoffs from the genesis app state. A chain that starts with this code
+    /// never had unversioned or JSON txs, so without a setting it rejects them
+    /// from `initial_height` on.
+    pub fn from_genesis(app_state: &[u8], initial_height: u64) -> Result<Self, &'static str> {
+        let genesis: Option<GenesisAppState> = if app_state.is_empty() {
+            None
+        } else {
+            Some(serde_json::from_slice(app_state).map_err(|_| "app_state is not valid JSON")?)
+        };
+        Ok(genesis.and_then(|g| g.tx_cutoffs).unwrap_or(Self {
+            unversioned_height: initial_height,
+            json_height: initial_height,
+        }))
+    }
+
+    pub fn check_unversioned(&self, height: u64) -> Result<(), TxError> {
+        if self.unversioned_height > 0 && height >= self.unversioned_height {
+            return Err(
+                "unversioned txs are no longer accepted; sign a versioned tx with a sequence"
+                    .into(),
+            );
+        }
+        Ok(())
+    }
+
+    pub fn check_json(&self, height: u64) -> Result<(), TxError> {
+        if self.json_height > 0 && height >= self.json_height {
+            return Err("JSON txs are no longer accepted; use the binary encoding".into());
+        }
+        Ok(())
+    }
+}
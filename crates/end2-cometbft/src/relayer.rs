@@ -0,0 +1,120 @@
+use std::collections::BTreeSet;
+
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::tx::{GovernanceAction, RelayerSignature, decode_key32};
+
+/// Keys allowed to submit key txs, and how many of them must sign a change to
+/// the set. Lives in the tree so every node agrees on it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RelayerSet {
+    // Bumped by every governance tx; a governance tx names the version it
+    // applies to, so it can't be replayed.
+    pub version: u64,
+    pub keys: BTreeSet<[u8; 32]>,
+    pub threshold: u32,
+}
+
+// `app_state` in genesis.json:
+// { "relayers": ["<base64 ed25519>", ...], "governance_threshold": 2 }
+// governance_threshold defaults to a majority of the relayers.
+#[derive(Deserialize)]
+struct GenesisAppState {
+    #[serde(default)]
+    relayers: Vec<String>,
+    governance_threshold: Option<u32>,
+}
+
+impl RelayerSet {
+    pub fn new(keys: BTreeSet<[u8; 32]>, threshold: Option<u32>) -> Result<Self, &'static str> {
+        let threshold = threshold.unwrap_or(keys.len() as u32 / 2 + 1);
+        let set = Self {
+            version: 0,
+            keys,
+            threshold,
+        };
+        set.check()?;
+        Ok(set)
+    }
+
+    /// Relayers listed in the genesis app state, or None if it lists none.
+    pub fn from_genesis(app_state: &[u8]) -> Result<Option<Self>, &'static str> {
+        if app_state.is_empty() {
+            return Ok(None);
+        }
+        let genesis: GenesisAppState =
+            serde_json::from_slice(app_state).map_err(|_| "app_state is not valid JSON")?;
+        if genesis.relayers.is_empty() {
+            return Ok(None);
+        }
+        let keys = genesis
+            .relayers
+            .iter()
+            .map(|k| decode_key32(k))
+            .collect::<Result<_, _>>()?;
+        Self::new(keys, genesis.governance_threshold).map(Some)
+    }
+
+    fn check(&self) -> Result<(), &'static str> {
+        if self.keys.is_empty() {
+            return Err("relayer set cannot be empty");
+        }
+        if self.threshold == 0 || self.threshold as usize > self.keys.len() {
+            return Err("governance threshold must be between 1 and the number of relayers");
+        }
+        for key in &self.keys {
+            VerifyingKey::from_bytes(key).map_err(|_| "relayer key is not a valid point")?;
+        }
+        Ok(())
+    }
+
+    pub fn member(&self, signer: &[u8; 32]) -> Result<VerifyingKey, &'static str> {
+        if !self.keys.contains(signer) {
+            return Err("signer is not an authorized relayer");
+        }
+        VerifyingKey::from_bytes(signer).map_err(|_| "relayer key is not a valid point")
+    }
+
+    /// Checks that at least `threshold` distinct members signed `msg`.
+    pub fn verify_quorum(
+        &self,
+        msg: &[u8],
+        signatures: &[RelayerSignature],
+    ) -> Result<(), &'static str> {
+        let mut signers = BTreeSet::new();
+        for sig in signatures {
+            let key = self.member(&sig.signer)?;
+            key.verify(msg, &Signature::from_bytes(&sig.signature))
+                .map_err(|_| "governance signature verification failed")?;
+            signers.insert(key.to_bytes());
+        }
+        if signers.len() < self.threshold as usize {
+            return Err("not enough relayer signatures for governance quorum");
+        }
+        Ok(())
+    }
+
+    /// The set after `action`, with its version bumped.
+    pub fn apply(&self, action: &GovernanceAction) -> Result<Self, &'static str> {
+        let mut next = self.clone();
+        next.version += 1;
+        match action {
+            GovernanceAction::AddRelayer { key } => {
+                if !next.keys.insert(*key) {
+                    return Err("key is already a relayer");
+                }
+            }
+            GovernanceAction::RemoveRelayer { key } => {
+                if !next.keys.remove(key) {
+                    return Err("key is not a relayer");
+                }
+            }
+            GovernanceAction::SetThreshold { threshold } => next.threshold = *threshold,
+            // stored separately; only bumps the version
+            GovernanceAction::SetLimits { .. } | GovernanceAction::SetTxCutoffs { .. } => {}
+        }
+        next.check()?;
+        Ok(next)
+    }
+}
@@ -0,0 +1,297 @@
+use std::sync::{Arc, mpsc};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use rocksdb::{Direction, IteratorMode, WriteBatch};
+use sha2::{Digest, Sha256};
+use tendermint_proto::abci::{
+    Snapshot, response_apply_snapshot_chunk::Result as ApplyResult,
+    response_offer_snapshot::Result as OfferResult,
+};
+
+use crate::{
+    admin,
+    store::{CF_DEVICE, CF_JMT, CF_JMT_STALE, CF_JMT_VALUES, Store},
+};
+
+// Snapshot layout in the snapshot column family.
+// m<height u64 BE>                    -> borsh(SnapshotMetadata)
+// c<height u64 BE><chunk u32 BE>      -> borsh(Vec<ChunkEntry>)
+//
+// A snapshot is every key of the device column family at `height`, in
+// iteration order, cut into chunks of roughly CHUNK_SIZE bytes. The tree
+// commits to exactly those keys, so a restoring node rebuilds its tree from
+// them and checks the root against the trusted app hash; nothing it applies
+// goes unverified. The contents only depend on committed state, so every node
+// produces the same chunks for the same height and a syncing node can fetch
+// them from any peer.
+//
+// Format 1 also carried every stored version of the tree column families.
+pub const SNAPSHOT_FORMAT: u32 = 2;
+
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+const SNAPSHOTS_KEPT: usize = 2;
+
+// Everything a restore writes, cleared again if it fails.
+const RESTORED_CFS: [&str; 4] = [CF_DEVICE, CF_JMT, CF_JMT_VALUES, CF_JMT_STALE];
+
+const P_META: u8 = b'm';
+const P_CHUNK: u8 = b'c';
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct SnapshotMetadata {
+    app_hash: [u8; 32],
+    chunk_hashes: Vec<[u8; 32]>,
+}
+
+// (device column family key, value)
+type ChunkEntry = (Vec<u8>, Vec<u8>);
+
+fn meta_key(height: u64) -> Vec<u8> {
+    let mut k = Vec::with_capacity(1 + 8);
+    k.push(P_META);
+    k.extend_from_slice(&height.to_be_bytes());
+    k
+}
+
+fn chunk_key(height: u64, index: u32) -> Vec<u8> {
+    let mut k = Vec::with_capacity(1 + 8 + 4);
+    k.push(P_CHUNK);
+    k.extend_from_slice(&height.to_be_bytes());
+    k.extend_from_slice(&index.to_be_bytes());
+    k
+}
+
+fn snapshot_hash(chunk_hashes: &[[u8; 32]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for h in chunk_hashes {
+        hasher.update(h);
+    }
+    hasher.finalize().into()
+}
+
+/// Writes a snapshot of the state as of the last commit on a background
+/// thread, then calls `done` with the outcome. Returns as soon as the thread
+/// has pinned that state with a RocksDB snapshot, so the next block can be
+/// committed while the chunks are still being written.
+pub fn spawn(
+    store: Arc<Store>,
+    height: u64,
+    app_hash: [u8; 32],
+    done: impl FnOnce(anyhow::Result<()>) + Send + 'static,
+) {
+    let (pinned_tx, pinned_rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let pinned = store.db.snapshot();
+        let _ = pinned_tx.send(());
+        done(create(&store, &pinned, height, app_hash));
+    });
+    // Only fails if the thread died before pinning, and then there is nothing
+    // to wait for.
+    let _ = pinned_rx.recv();
+}
+
+fn create(
+    store: &Store,
+    pinned: &rocksdb::Snapshot<'_>,
+    height: u64,
+    app_hash: [u8; 32],
+) -> anyhow::Result<()> {
+    let mut batch = WriteBatch::default();
+    let mut chunk_hashes = Vec::new();
+    let mut entries: Vec<ChunkEntry> = Vec::new();
+    let mut entries_size = 0;
+
+    for item in pinned.iterator_cf(store.cf_device(), IteratorMode::Start) {
+        let (k, v) = item?;
+        entries_size += k.len() + v.len();
+        entries.push((k.to_vec(), v.to_vec()));
+        if entries_size >= CHUNK_SIZE {
+            write_chunk(store, &mut batch, height, &mut chunk_hashes, &mut entries);
+            entries_size = 0;
+        }
+    }
+    if !entries.is_empty() || chunk_hashes.is_empty() {
+        write_chunk(store, &mut batch, height, &mut chunk_hashes, &mut entries);
+    }
+
+    let metadata = SnapshotMetadata {
+        app_hash,
+        chunk_hashes,
+    };
+    batch.put_cf(
+        store.cf_snapshot(),
+        meta_key(height),
+        borsh::to_vec(&metadata)?,
+    );
+
+    for old in list(store).into_iter().skip(SNAPSHOTS_KEPT - 1) {
+        delete(store, &mut batch, &old);
+    }
+
+    store.db.write(batch)?;
+    Ok(())
+}
+
+fn write_chunk(
+    store: &Store,
+    batch: &mut WriteBatch,
+    height: u64,
+    chunk_hashes: &mut Vec<[u8; 32]>,
+    entries: &mut Vec<ChunkEntry>,
+) {
+    let chunk = borsh::to_vec(entries).expect("snapshot encode chunk");
+    let index = chunk_hashes.len() as u32;
+    chunk_hashes.push(Sha256::digest(&chunk).into());
+    batch.put_cf(store.cf_snapshot(), chunk_key(height, index), chunk);
+    entries.clear();
+}
+
+fn delete(store: &Store, batch: &mut WriteBatch, snapshot: &Snapshot) {
+    batch.delete_cf(store.cf_snapshot(), meta_key(snapshot.height));
+    for index in 0..snapshot.chunks {
+        batch.delete_cf(store.cf_snapshot(), chunk_key(snapshot.height, index));
+    }
+}
+
+/// Stored snapshots, newest first.
+pub fn list(store: &Store) -> Vec<Snapshot> {
+    let iter = store.db.iterator_cf(
+        store.cf_snapshot(),
+        IteratorMode::From(&meta_key(u64::MAX), Direction::Reverse),
+    );
+
+    let mut out = Vec::new();
+    for item in iter {
+        let (k, v) = item.expect("rocksdb iterate snapshots");
+        if k.first() != Some(&P_META) || k.len() != 1 + 8 {
+            break;
+        }
+        let Ok(metadata) = borsh::from_slice::<SnapshotMetadata>(&v) else {
+            continue;
+        };
+        let height = u64::from_be_bytes(k[1..].try_into().expect("8-byte height"));
+        out.push(Snapshot {
+            height,
+            format: SNAPSHOT_FORMAT,
+            chunks: metadata.chunk_hashes.len() as u32,
+            hash: snapshot_hash(&metadata.chunk_hashes).to_vec().into(),
+            metadata: v.to_vec().into(),
+        });
+    }
+    out
+}
+
+pub fn load_chunk(store: &Store, height: u64, format: u32, index: u32) -> Option<Vec<u8>> {
+    if format != SNAPSHOT_FORMAT {
+        return None;
+    }
+    store
+        .db
+        .get_cf(store.cf_snapshot(), chunk_key(height, index))
+        .expect("rocksdb get snapshot chunk")
+}
+
+/// A snapshot being restored from peers, from offer_snapshot until its last
+/// chunk has been applied.
+pub struct Restore {
+    height: u64,
+    app_hash: [u8; 32],
+    chunk_hashes: Vec<[u8; 32]>,
+    applied: Vec<bool>,
+}
+
+pub enum ApplyError {
+    // The chunk doesn't match its hash in the metadata; fetch it elsewhere.
+    BadChunk,
+    // The restored tree doesn't hash to the trusted app hash.
+    AppHashMismatch,
+    Store(anyhow::Error),
+}
+
+impl From<ApplyError> for ApplyResult {
+    fn from(e: ApplyError) -> Self {
+        match e {
+            ApplyError::BadChunk => Self::Retry,
+            ApplyError::AppHashMismatch => Self::RejectSnapshot,
+            ApplyError::Store(_) => Self::Abort,
+        }
+    }
+}
+
+impl Restore {
+    /// Accepts `snapshot` if its metadata agrees with itself and with the app
+    /// hash CometBFT's light client verified for its height.
+    pub fn offer(snapshot: &Snapshot, trusted_app_hash: &[u8]) -> Result<Self, OfferResult> {
+        if snapshot.format != SNAPSHOT_FORMAT {
+            return Err(OfferResult::RejectFormat);
+        }
+
+        let metadata = borsh::from_slice::<SnapshotMetadata>(&snapshot.metadata)
+            .map_err(|_| OfferResult::Reject)?;
+        if metadata.chunk_hashes.len() != snapshot.chunks as usize
+            || snapshot.hash.as_ref() != snapshot_hash(&metadata.chunk_hashes)
+            || trusted_app_hash != metadata.app_hash
+        {
+            return Err(OfferResult::Reject);
+        }
+
+        Ok(Self {
+            height: snapshot.height,
+            app_hash: metadata.app_hash,
+            applied: vec![false; metadata.chunk_hashes.len()],
+            chunk_hashes: metadata.chunk_hashes,
+        })
+    }
+
+    /// Writes one chunk into the store. Returns true once every chunk has been
+    /// applied and the tree rebuilt from them hashes to the app hash.
+    ///
+    /// The restored tree starts at the snapshot height, like one written by
+    /// `import`, so historical queries and proofs only work from there on.
+    pub fn apply(&mut self, store: &Store, index: u32, chunk: &[u8]) -> Result<bool, ApplyError> {
+        let i = index as usize;
+        let expected = self.chunk_hashes.get(i).ok_or(ApplyError::BadChunk)?;
+        if Sha256::digest(chunk).as_slice() != expected {
+            return Err(ApplyError::BadChunk);
+        }
+
+        let entries =
+            borsh::from_slice::<Vec<ChunkEntry>>(chunk).map_err(|_| ApplyError::BadChunk)?;
+        let mut batch = WriteBatch::default();
+        for (k, v) in entries {
+            batch.put_cf(store.cf_device(), k, v);
+        }
+        store
+            .db
+            .write(batch)
+            .map_err(|e| ApplyError::Store(e.into()))?;
+        self.applied[i] = true;
+
+        if !self.applied.iter().all(|a| *a) {
+            return Ok(false);
+        }
+
+        let entries = admin::device_entries(store).map_err(ApplyError::Store)?;
+        if admin::root_of(&entries).map_err(ApplyError::Store)? != self.app_hash {
+            clear_state(store).map_err(ApplyError::Store)?;
+            return Err(ApplyError::AppHashMismatch);
+        }
+        admin::write_tree(store, &entries, self.height).map_err(ApplyError::Store)?;
+        Ok(true)
+    }
+}
+
+/// Drops everything a restore wrote, so the next snapshot starts clean after
+/// one that failed or was given up on.
+pub fn clear_state(store: &Store) -> anyhow::Result<()> {
+    let mut batch = WriteBatch::default();
+    for name in RESTORED_CFS {
+        let cf = store.db.cf_handle(name).expect("snapshot cf missing");
+        for item in store.db.iterator_cf(cf, IteratorMode::Start) {
+            let (k, _) = item?;
+            batch.delete_cf(cf, k);
+        }
+    }
+    store.db.write(batch)?;
+    Ok(())
+}
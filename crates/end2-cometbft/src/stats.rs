@@ -0,0 +1,59 @@
+// Per-user device counts and chain-wide totals, kept in the tree so they can
+// be listed and proven without walking every device key.
+//
+// u/<user_hash_hex> holds the user's live device count and exists only while
+// that is at least one. g/stats holds the totals over all of them.
+
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PAGE: usize = 100;
+pub const MAX_PAGE: usize = 1000;
+
+#[derive(Default, Clone, Copy, Serialize, Deserialize)]
+pub struct UserStats {
+    // users with at least one live device
+    pub users: u64,
+    pub devices: u64,
+}
+
+impl UserStats {
+    /// Totals after one user's device count went from `before` to `after`.
+    pub fn apply(mut self, before: u64, after: u64) -> Self {
+        // saturating so check_tx can't panic on a chain whose index isn't
+        // seeded yet; finalize_block always sees a seeded one
+        self.devices = (self.devices + after).saturating_sub(before);
+        match (before, after) {
+            (0, 1..) => self.users += 1,
+            (1.., 0) => self.users = self.users.saturating_sub(1),
+            _ => {}
+        }
+        self
+    }
+}
+
+#[derive(Serialize)]
+pub struct UserCount {
+    pub user_hash: String,
+    pub devices: u64,
+}
+
+#[derive(Serialize)]
+pub struct UsersPage {
+    pub users: Vec<UserCount>,
+    // pass as `after` to get the next page; absent on the last one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+}
+
+/// Parses the `users` query data, `"[<after_hex_user_hash>][:<limit>]"`.
+pub fn parse_page(data: &str) -> Result<(&str, usize), &'static str> {
+    let (after, limit) = match data.split_once(':') {
+        Some((after, "")) => (after, DEFAULT_PAGE),
+        Some((after, limit)) => (after, limit.parse().map_err(|_| "limit must be a number")?),
+        None => (data, DEFAULT_PAGE),
+    };
+    if limit == 0 || limit > MAX_PAGE {
+        return Err("limit must be between 1 and 1000");
+    }
+    Ok((after, limit))
+}
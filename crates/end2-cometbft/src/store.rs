@@ -1,21 +1,53 @@
 use std::sync::Arc;
 
-use jmt::storage::{Node, NodeKey, TreeReader, TreeUpdateBatch};
+use jmt::{
+    JellyfishMerkleTree, KeyHash, Version,
+    proof::SparseMerkleProof,
+    storage::{NibblePath, Node, NodeKey, TreeReader, TreeUpdateBatch},
+};
 use rocksdb::{
     BlockBasedOptions, ColumnFamily, ColumnFamilyDescriptor, DB, Direction, IteratorMode, Options,
     WriteBatchWithTransaction,
 };
+use sha2::Sha256;
 
 // Key layout in a single column family.
 // d/<user_hash_hex>/<device_id>  -> JSON(DeviceKeys)
+// r/<user_hash_hex>/<device_id>  -> JSON(DeviceKeys) of a revoked device
+// s/<user_hash_hex>              -> next tx sequence, u64 BE
+// g/relayers                     -> JSON(RelayerSet)
+// l/<user_hash_hex>/<index 016x>  -> JSON(LogEntry), see keylog
+// h/<user_hash_hex>              -> JSON(LogHead)
+// w/<user_hash_hex>              -> JSON(WindowCount), see policy
+// g/limits                       -> JSON(Limits)
+// g/tx_cutoffs                   -> JSON(TxCutoffs)
+// u/<user_hash_hex>              -> live device count, u64 BE, see stats
+// g/stats                        -> JSON(UserStats)
 // m/height                       -> u64 LE
 // m/app_hash                     -> 32 bytes
+//
+// jmt_values holds every value the tree has ever committed:
+// <key_hash (32 bytes)><version u64 BE>  -> borsh(Option<value>)
+// The big-endian version keeps one key's versions adjacent and ordered, so the
+// value as of any version is a single reverse seek.
+//
+// jmt_stale indexes nodes that later versions replaced, for pruning:
+// <stale_since_version u64 BE><borsh(NodeKey)>  -> empty
 pub const CF_DEVICE: &str = "device";
 pub const CF_JMT: &str = "jmt";
+pub const CF_JMT_VALUES: &str = "jmt_values";
+pub const CF_JMT_STALE: &str = "jmt_stale";
+pub const CF_SNAPSHOT: &str = "snapshot";
 
 pub const META_HEIGHT: &[u8] = b"m/height";
 pub const META_APP_HASH: &[u8] = b"m/app_hash";
 
+// In the device column family, so it is part of the tree and of snapshots.
+pub const RELAYERS_KEY: &[u8] = b"g/relayers";
+pub const LIMITS_KEY: &[u8] = b"g/limits";
+pub const TX_CUTOFFS_KEY: &[u8] = b"g/tx_cutoffs";
+pub const STATS_KEY: &[u8] = b"g/stats";
+
 pub struct Store {
     pub db: Arc<DB>,
 }
@@ -40,10 +72,16 @@ impl Store {
         jmt_block_opts.set_bloom_filter(10f64, false);
         jmt_opts.set_block_based_table_factory(&jmt_block_opts);
 
+        let mut jmt_values_opts = Options::default();
+        jmt_values_opts.optimize_level_style_compaction(256 * 1024 * 1024);
+
         let cfs = vec![
             ColumnFamilyDescriptor::new("default", default_opts),
             ColumnFamilyDescriptor::new(CF_DEVICE, device_opts),
             ColumnFamilyDescriptor::new(CF_JMT, jmt_opts),
+            ColumnFamilyDescriptor::new(CF_JMT_VALUES, jmt_values_opts),
+            ColumnFamilyDescriptor::new(CF_JMT_STALE, Options::default()),
+            ColumnFamilyDescriptor::new(CF_SNAPSHOT, Options::default()),
         ];
 
         let db = DB::open_cf_descriptors(&db_opts, path, cfs)?;
@@ -51,6 +89,21 @@ impl Store {
         Ok(Self { db: Arc::new(db) })
     }
 
+    // On-disk SST size of each column family, for metrics.
+    pub fn cf_sizes(&self) -> Vec<(&'static str, u64)> {
+        [CF_DEVICE, CF_JMT, CF_JMT_VALUES, CF_JMT_STALE, CF_SNAPSHOT]
+            .into_iter()
+            .filter_map(|name| {
+                let cf = self.db.cf_handle(name)?;
+                let size = self
+                    .db
+                    .property_int_value_cf(cf, "rocksdb.total-sst-files-size")
+                    .ok()??;
+                Some((name, size))
+            })
+            .collect()
+    }
+
     pub fn cf_device(&self) -> &ColumnFamily {
         self.db.cf_handle(CF_DEVICE).expect("device cf missing")
     }
@@ -59,6 +112,22 @@ impl Store {
         self.db.cf_handle(CF_JMT).expect("jmt cf missing")
     }
 
+    pub fn cf_jmt_values(&self) -> &ColumnFamily {
+        self.db
+            .cf_handle(CF_JMT_VALUES)
+            .expect("jmt_values cf missing")
+    }
+
+    pub fn cf_jmt_stale(&self) -> &ColumnFamily {
+        self.db
+            .cf_handle(CF_JMT_STALE)
+            .expect("jmt_stale cf missing")
+    }
+
+    pub fn cf_snapshot(&self) -> &ColumnFamily {
+        self.db.cf_handle(CF_SNAPSHOT).expect("snapshot cf missing")
+    }
+
     pub fn last_height(&self) -> u64 {
         match self.db.get(META_HEIGHT).expect("rocksdb get height") {
             Some(b) if b.len() == 8 => {
@@ -96,6 +165,54 @@ impl Store {
         k
     }
 
+    // Revoked devices live under their own prefix so they drop out of
+    // iter_user_devices but stay in the tree and can't be re-added.
+    pub fn revoked_key(user_hash_hex: &str, device_id: &str) -> Vec<u8> {
+        let mut k = Vec::with_capacity(2 + user_hash_hex.len() + 1 + device_id.len());
+        k.extend_from_slice(b"r/");
+        k.extend_from_slice(&Self::device_key(user_hash_hex, device_id));
+        k
+    }
+
+    pub fn sequence_key(user_hash_hex: &str) -> Vec<u8> {
+        let mut k = Vec::with_capacity(2 + user_hash_hex.len());
+        k.extend_from_slice(b"s/");
+        k.extend_from_slice(user_hash_hex.as_bytes());
+        k
+    }
+
+    // Fixed-width hex index, so a user's entries iterate in log order.
+    pub fn log_entry_key(user_hash_hex: &str, index: u64) -> Vec<u8> {
+        format!("l/{user_hash_hex}/{index:016x}").into_bytes()
+    }
+
+    pub fn window_key(user_hash_hex: &str) -> Vec<u8> {
+        let mut k = Vec::with_capacity(2 + user_hash_hex.len());
+        k.extend_from_slice(b"w/");
+        k.extend_from_slice(user_hash_hex.as_bytes());
+        k
+    }
+
+    pub fn log_head_key(user_hash_hex: &str) -> Vec<u8> {
+        let mut k = Vec::with_capacity(2 + user_hash_hex.len());
+        k.extend_from_slice(b"h/");
+        k.extend_from_slice(user_hash_hex.as_bytes());
+        k
+    }
+
+    pub fn user_index_key(user_hash_hex: &str) -> Vec<u8> {
+        let mut k = Vec::with_capacity(2 + user_hash_hex.len());
+        k.extend_from_slice(b"u/");
+        k.extend_from_slice(user_hash_hex.as_bytes());
+        k
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.db
+            .get_cf(self.cf_device(), key)
+            .expect("rocksdb get device")
+    }
+
     pub fn get_device(&self, user_hash_hex: &str, device_id: &str) -> Option<Vec<u8>> {
         self.db
             .get_cf(
@@ -106,15 +223,23 @@ impl Store {
     }
 
     pub fn iter_user_devices(&self, user_hash_hex: &str) -> Vec<(String, Vec<u8>)> {
-        let prefix = Self::device_prefix(user_hash_hex);
+        self.iter_device_ids(&Self::device_prefix(user_hash_hex))
+    }
+
+    // Devices of `user_hash_hex` that were revoked, with their last keys.
+    pub fn iter_user_revoked(&self, user_hash_hex: &str) -> Vec<(String, Vec<u8>)> {
+        self.iter_device_ids(&Self::revoked_key(user_hash_hex, ""))
+    }
+
+    fn iter_device_ids(&self, prefix: &[u8]) -> Vec<(String, Vec<u8>)> {
         let mut out = Vec::new();
         let iter = self.db.iterator_cf(
             self.cf_device(),
-            IteratorMode::From(&prefix, Direction::Forward),
+            IteratorMode::From(prefix, Direction::Forward),
         );
         for item in iter {
             let (k, v) = item.expect("rocksdb iterate user devices");
-            if !k.starts_with(&prefix) {
+            if !k.starts_with(prefix) {
                 break;
             }
             let device_id = std::str::from_utf8(&k[prefix.len()..])
@@ -125,6 +250,57 @@ impl Store {
         out
     }
 
+    // Up to `limit` entries of the user index after `after` (exclusive), in
+    // user hash order, and whether more follow.
+    pub fn iter_user_index(&self, after: &str, limit: usize) -> (Vec<(String, u64)>, bool) {
+        let start = Self::user_index_key(after);
+        let iter = self.db.iterator_cf(
+            self.cf_device(),
+            IteratorMode::From(&start, Direction::Forward),
+        );
+        let mut out = Vec::new();
+        for item in iter {
+            let (k, v) = item.expect("rocksdb iterate user index");
+            if !k.starts_with(b"u/") {
+                break;
+            }
+            if *k == *start {
+                continue;
+            }
+            if out.len() == limit {
+                return (out, true);
+            }
+            let user_hash_hex = std::str::from_utf8(&k[2..])
+                .expect("non-utf8 user hash in rocksdb")
+                .to_owned();
+            let count = v[..].try_into().map_or(0, u64::from_be_bytes);
+            out.push((user_hash_hex, count));
+        }
+        (out, false)
+    }
+
+    // Live devices per user, counted from the device keys themselves. Walks
+    // the whole column family; only used to seed the user index.
+    pub fn count_devices_by_user(&self) -> std::collections::BTreeMap<String, u64> {
+        let mut counts = std::collections::BTreeMap::new();
+        for item in self.db.iterator_cf(self.cf_device(), IteratorMode::Start) {
+            let (k, _) = item.expect("rocksdb iterate devices");
+            // <64 hex chars>/<device_id>; every other key has a one-letter prefix
+            if k.len() > 65 && k[64] == b'/' && k[..64].iter().all(u8::is_ascii_hexdigit) {
+                let user_hash_hex = String::from_utf8_lossy(&k[..64]).into_owned();
+                *counts.entry(user_hash_hex).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    pub fn jmt_value_key(key_hash: KeyHash, version: Version) -> Vec<u8> {
+        let mut k = Vec::with_capacity(32 + 8);
+        k.extend_from_slice(&key_hash.0);
+        k.extend_from_slice(&version.to_be_bytes());
+        k
+    }
+
     pub fn jmt_node_key(node_key: &NodeKey) -> Vec<u8> {
         let key_bytes = borsh::to_vec(node_key).expect("failed to serialize jmt NodeKey");
         key_bytes
@@ -137,13 +313,76 @@ impl Store {
     ) {
         for (node_key, node) in tree_update.node_batch.nodes() {
             let encoded_node = borsh::to_vec(node).expect("jmt encode node");
-            batch.put(Self::jmt_node_key(node_key), encoded_node);
+            batch.put_cf(self.cf_jmt(), Self::jmt_node_key(node_key), encoded_node);
+        }
+
+        for ((version, key_hash), value) in tree_update.node_batch.values() {
+            let encoded_value = borsh::to_vec(value).expect("jmt encode value");
+            batch.put_cf(
+                self.cf_jmt_values(),
+                Self::jmt_value_key(*key_hash, *version),
+                encoded_value,
+            );
+        }
+
+        for stale_node in &tree_update.stale_node_index_batch {
+            let mut k = stale_node.stale_since_version.to_be_bytes().to_vec();
+            k.extend_from_slice(&Self::jmt_node_key(&stale_node.node_key));
+            batch.put_cf(self.cf_jmt_stale(), k, []);
+        }
+    }
+
+    // Deletes every node that no version from `oldest_kept` onwards still
+    // references. A node stale since version v is last used by v - 1, so it can
+    // go once v <= oldest_kept. Returns how many nodes were removed.
+    pub fn prune_stale_nodes(&self, oldest_kept: Version) -> Result<usize, rocksdb::Error> {
+        let mut batch = WriteBatchWithTransaction::<false>::default();
+        let mut pruned = 0;
+
+        for item in self
+            .db
+            .iterator_cf(self.cf_jmt_stale(), IteratorMode::Start)
+        {
+            let (k, _) = item?;
+            let stale_since = Version::from_be_bytes(k[..8].try_into().expect("8-byte version"));
+            if stale_since > oldest_kept {
+                break;
+            }
+            batch.delete_cf(self.cf_jmt(), &k[8..]);
+            batch.delete_cf(self.cf_jmt_stale(), &k);
+            pruned += 1;
+        }
+
+        if pruned > 0 {
+            self.db.write(batch)?;
         }
+        Ok(pruned)
+    }
+
+    // The JMT builds version N on top of the root at N - 1, so the first block
+    // needs an empty root to exist at `initial_height - 1`.
+    pub fn write_genesis_root(&self, version: Version) -> Result<(), rocksdb::Error> {
+        let root_key = NodeKey::new(version, std::iter::empty().collect::<NibblePath>());
+        let encoded_node = borsh::to_vec(&Node::Null).expect("jmt encode node");
+        self.db
+            .put_cf(self.cf_jmt(), Self::jmt_node_key(&root_key), encoded_node)
+    }
 
-        // TODO prune stale nodes
-        // for stale_node in tree_update.stale_node_index_batch {
+    // Value the tree held for `key` as of `version`. Values are never pruned,
+    // so this works for any committed version.
+    pub fn get_value_at(&self, key: &[u8], version: Version) -> anyhow::Result<Option<Vec<u8>>> {
+        self.get_value_option(version, KeyHash::with::<Sha256>(key))
+    }
 
-        // }
+    // Value of `key` at `version` together with its inclusion or non-inclusion
+    // proof against that version's root.
+    pub fn get_with_proof(
+        &self,
+        key: &[u8],
+        version: Version,
+    ) -> anyhow::Result<(Option<jmt::OwnedValue>, SparseMerkleProof<Sha256>)> {
+        JellyfishMerkleTree::<_, Sha256>::new(self)
+            .get_with_proof(KeyHash::with::<Sha256>(key), version)
     }
 }
 
@@ -160,16 +399,43 @@ impl TreeReader for Store {
         }
     }
 
-    #[allow(unused_variables)]
     fn get_value_option(
         &self,
         max_version: jmt::Version,
         key_hash: jmt::KeyHash,
     ) -> anyhow::Result<Option<jmt::OwnedValue>> {
-        unimplemented!("get_value_option unimplemented")
+        let seek = Self::jmt_value_key(key_hash, max_version);
+        let mut iter = self.db.iterator_cf(
+            self.cf_jmt_values(),
+            IteratorMode::From(&seek, Direction::Reverse),
+        );
+        match iter.next() {
+            Some(item) => {
+                let (k, v) = item?;
+                if !k.starts_with(&key_hash.0) {
+                    return Ok(None);
+                }
+                Ok(borsh::from_slice::<Option<jmt::OwnedValue>>(&v)?)
+            }
+            None => Ok(None),
+        }
     }
 
+    // Node keys are stored borsh-encoded, which doesn't sort by key hash, so
+    // this walks the whole column family. Only restore uses it.
     fn get_rightmost_leaf(&self) -> anyhow::Result<Option<(NodeKey, jmt::storage::LeafNode)>> {
-        unimplemented!("get_rightmost_leaf unimplemented")
+        let mut rightmost: Option<(NodeKey, jmt::storage::LeafNode)> = None;
+        for item in self.db.iterator_cf(self.cf_jmt(), IteratorMode::Start) {
+            let (k, v) = item?;
+            if let Node::Leaf(leaf) = borsh::from_slice::<Node>(&v)? {
+                let is_rightmost = rightmost
+                    .as_ref()
+                    .is_none_or(|(_, best)| leaf.key_hash() > best.key_hash());
+                if is_rightmost {
+                    rightmost = Some((borsh::from_slice::<NodeKey>(&k)?, leaf));
+                }
+            }
+        }
+        Ok(rightmost)
     }
 }
@@ -0,0 +1,99 @@
+// Prometheus metrics and OTLP tracing for the ABCI server.
+//
+// Metrics are served in Prometheus text format on ABCI_METRICS_ADDR. Spans and
+// logs go to the OTLP collector the same way the backend sends them, configured
+// through the usual OTEL_EXPORTER_OTLP_* variables.
+
+use std::{net::SocketAddr, time::Duration};
+
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use opentelemetry::trace::TracerProvider;
+use tokio::runtime::Runtime;
+use tracing_subscriber::prelude::*;
+
+use crate::store::Store;
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+const BLOCK_TXS_BUCKETS: &[f64] = &[0.0, 1.0, 5.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0];
+
+/// Installs the tracing subscriber and the metrics listener. The exporters run
+/// on the returned runtime, which must outlive the ABCI server.
+pub fn init(metrics_addr: SocketAddr) -> Runtime {
+    let runtime = Runtime::new().expect("telemetry runtime");
+    let _guard = runtime.enter();
+
+    let span_exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .build()
+        .expect("span exporter");
+    let tracer_provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_batch_exporter(span_exporter)
+        .build();
+
+    let log_exporter = opentelemetry_otlp::LogExporter::builder()
+        .with_tonic()
+        .build()
+        .expect("log exporter");
+    let logger_provider = opentelemetry_sdk::logs::SdkLoggerProvider::builder()
+        .with_batch_exporter(log_exporter)
+        .build();
+
+    let tracer = tracer_provider.tracer("end2-cometbft");
+    let otel_trace_layer = tracing_opentelemetry::layer().with_tracer(tracer);
+    let otel_logs_layer =
+        opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge::new(&logger_provider);
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().json())
+        .with(otel_trace_layer)
+        .with(otel_logs_layer)
+        .with(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
+
+    opentelemetry::global::set_tracer_provider(tracer_provider);
+
+    PrometheusBuilder::new()
+        .with_http_listener(metrics_addr)
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".into()), LATENCY_BUCKETS)
+        .and_then(|b| {
+            b.set_buckets_for_metric(Matcher::Full("end2_block_txs".into()), BLOCK_TXS_BUCKETS)
+        })
+        .expect("metric buckets")
+        .install()
+        .expect("failed to start metrics listener");
+
+    runtime
+}
+
+// `stage` is "check_tx" or "finalize_block".
+pub fn tx_accepted(stage: &'static str) {
+    counter!("end2_txs_accepted_total", "stage" => stage).increment(1);
+}
+
+// `reason` is the rejection message, which is always one of a fixed set of
+// static strings.
+pub fn tx_rejected(stage: &'static str, reason: &'static str) {
+    counter!("end2_txs_rejected_total", "stage" => stage, "reason" => reason).increment(1);
+}
+
+pub fn proposal_processed(accepted: bool) {
+    let status = if accepted { "accept" } else { "reject" };
+    counter!("end2_proposals_processed_total", "status" => status).increment(1);
+}
+
+pub fn block_finalized(txs: usize, elapsed: Duration) {
+    histogram!("end2_block_txs").record(txs as f64);
+    histogram!("end2_finalize_block_seconds").record(elapsed);
+}
+
+pub fn block_committed(store: &Store, height: u64, nodes_written: usize, elapsed: Duration) {
+    histogram!("end2_commit_seconds").record(elapsed);
+    counter!("end2_jmt_nodes_written_total").increment(nodes_written as u64);
+    gauge!("end2_height").set(height as f64);
+    for (cf, bytes) in store.cf_sizes() {
+        gauge!("end2_rocksdb_sst_bytes", "cf" => cf).set(bytes as f64);
+    }
+}
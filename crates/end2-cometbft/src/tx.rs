@@ -0,0 +1,208 @@
+mod json;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier};
+use end2_protocol::tx::WireTx;
+pub use end2_protocol::{
+    keys::{
+        DeviceKeys, REVOKE_CONTEXT, decode_key32, decode_sig64, verify_device_keys,
+        verify_revocation,
+    },
+    tx::{
+        Authorization, BINARY_TX_TAG, BatchEntryResult, KeyBatchBody, KeyBatchEntry, KeyPayload,
+        KeyTx, KeyTxBody, RelayerSignature, RevokePayload, TX_VERSION,
+    },
+};
+
+use crate::{
+    policy::{Limits, TxCutoffs},
+    relayer::RelayerSet,
+};
+
+// The key tx types and the rules they're checked by live in end2-protocol,
+// shared with the backend that builds them. Governance txs only ever come from
+// operator tooling, so their body stays here next to the `Limits` it carries.
+// JSON txs (they start with '{') still decode during the migration window, see
+// `json`, as does the unversioned upload from before them until the chain's
+// `TxCutoffs` say otherwise.
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub enum GovernanceAction {
+    AddRelayer { key: [u8; 32] },
+    RemoveRelayer { key: [u8; 32] },
+    SetThreshold { threshold: u32 },
+    SetLimits { limits: Limits },
+    SetTxCutoffs { cutoffs: TxCutoffs },
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct GovernanceBody {
+    pub version: u32,
+    // Must equal the current relayer set version.
+    pub set_version: u64,
+    pub action: GovernanceAction,
+}
+
+impl GovernanceBody {
+    /// The wire bytes of this body signed by each of `relayers`.
+    pub fn sign(&self, relayers: &[&SigningKey]) -> Vec<u8> {
+        let body = borsh::to_vec(self).expect("GovernanceBody borsh");
+        let signatures = relayers
+            .iter()
+            .map(|k| RelayerSignature {
+                signer: k.verifying_key().to_bytes(),
+                signature: k.sign(&body).to_bytes(),
+            })
+            .collect();
+        WireTx::Governance { body, signatures }.encode()
+    }
+}
+
+pub struct SignedKeyTx {
+    body: KeyTxBody,
+    // the bytes `signature` covers
+    signed: Vec<u8>,
+    signer: [u8; 32],
+    signature: [u8; 64],
+}
+
+impl SignedKeyTx {
+    /// The body as submitted, before its signature is checked.
+    pub fn unverified_body(&self) -> &KeyTxBody {
+        &self.body
+    }
+}
+
+pub struct SignedKeyBatch {
+    body: KeyBatchBody,
+    signed: Vec<u8>,
+    signer: [u8; 32],
+    signature: [u8; 64],
+}
+
+// A relayer-signed upload with no version, sequence or signer. It adds the
+// device or, if it exists, rotates it.
+pub struct UnversionedKeyTx {
+    payload: KeyPayload,
+    signed: Vec<u8>,
+    signature: [u8; 64],
+}
+
+pub struct GovernanceTx {
+    governance: GovernanceBody,
+    signed: Vec<u8>,
+    signatures: Vec<RelayerSignature>,
+}
+
+pub enum Tx {
+    Key(Box<SignedKeyTx>),
+    Governance(GovernanceTx),
+    KeyBatch(Box<SignedKeyBatch>),
+    Unversioned(Box<UnversionedKeyTx>),
+}
+
+/// Whether `bytes` is a JSON tx from before the binary encoding.
+pub fn is_json_tx(bytes: &[u8]) -> bool {
+    bytes.first() != Some(&BINARY_TX_TAG)
+}
+
+pub fn decode_tx(bytes: &[u8]) -> Result<Tx, &'static str> {
+    let Some(wire) = WireTx::decode(bytes)? else {
+        return json::decode_tx(bytes);
+    };
+
+    match wire {
+        WireTx::Key {
+            body,
+            signer,
+            signature,
+        } => Ok(Tx::Key(Box::new(SignedKeyTx {
+            body: borsh::from_slice(&body).map_err(|_| "invalid tx body encoding")?,
+            signed: body,
+            signer,
+            signature,
+        }))),
+        WireTx::Governance { body, signatures } => Ok(Tx::Governance(GovernanceTx {
+            governance: borsh::from_slice(&body).map_err(|_| "invalid tx body encoding")?,
+            signed: body,
+            signatures,
+        })),
+        WireTx::KeyBatch {
+            body,
+            signer,
+            signature,
+        } => Ok(Tx::KeyBatch(Box::new(SignedKeyBatch {
+            body: borsh::from_slice(&body).map_err(|_| "invalid tx body encoding")?,
+            signed: body,
+            signer,
+            signature,
+        }))),
+    }
+}
+
+pub fn verify_key_tx(tx: SignedKeyTx, relayers: &RelayerSet) -> Result<KeyTxBody, &'static str> {
+    if tx.body.version != TX_VERSION {
+        return Err("unsupported tx version");
+    }
+
+    verify_relayer_signature(&tx.signed, &tx.signer, &tx.signature, relayers)?;
+    Ok(tx.body)
+}
+
+pub fn verify_key_batch(
+    tx: SignedKeyBatch,
+    relayers: &RelayerSet,
+) -> Result<KeyBatchBody, &'static str> {
+    if tx.body.version != TX_VERSION {
+        return Err("unsupported tx version");
+    }
+    if tx.body.entries.is_empty() {
+        return Err("key batch has no entries");
+    }
+
+    verify_relayer_signature(&tx.signed, &tx.signer, &tx.signature, relayers)?;
+    Ok(tx.body)
+}
+
+fn verify_relayer_signature(
+    signed: &[u8],
+    signer: &[u8; 32],
+    signature: &[u8; 64],
+    relayers: &RelayerSet,
+) -> Result<(), &'static str> {
+    let key = relayers.member(signer)?;
+    key.verify(signed, &Signature::from_bytes(signature))
+        .map_err(|_| "signature verification failed")
+}
+
+// The tx names no signer, so any relayer's signature will do.
+pub fn verify_unversioned_tx(
+    tx: UnversionedKeyTx,
+    relayers: &RelayerSet,
+) -> Result<KeyPayload, &'static str> {
+    let signature = Signature::from_bytes(&tx.signature);
+    let signed_by_relayer = relayers.keys.iter().any(|signer| {
+        relayers
+            .member(signer)
+            .is_ok_and(|key| key.verify(&tx.signed, &signature).is_ok())
+    });
+    if !signed_by_relayer {
+        return Err("signature verification failed");
+    }
+    Ok(tx.payload)
+}
+
+pub fn verify_governance_tx(
+    tx: GovernanceTx,
+    relayers: &RelayerSet,
+) -> Result<GovernanceAction, &'static str> {
+    if tx.governance.version != TX_VERSION {
+        return Err("unsupported tx version");
+    }
+    if tx.governance.set_version != relayers.version {
+        return Err("governance tx is for a different relayer set version");
+    }
+
+    relayers.verify_quorum(&tx.signed, &tx.signatures)?;
+    Ok(tx.governance.action)
+}
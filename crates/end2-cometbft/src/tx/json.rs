@@ -0,0 +1,215 @@
+// The JSON encoding txs used before the binary one. The signature covers
+// serde_json::to_vec of the body as decoded here, so these structs must keep
+// the field order the backend signed them with.
+//
+// Binary fields are unpadded base64 strings, user_hash is hex.
+//
+// The oldest of them, from before txs were versioned, is a bare
+// `{"payload", "signature"}` upload with the same payload fields.
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Authorization, GovernanceAction, GovernanceBody, GovernanceTx, KeyPayload, KeyTx, KeyTxBody,
+    RelayerSignature, RevokePayload, SignedKeyTx, Tx, UnversionedKeyTx, decode_key32, decode_sig64,
+};
+
+#[derive(Serialize, Deserialize)]
+struct JsonAuthorization {
+    authorizing_device_id: String,
+    signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonKeyPayload {
+    user_hash: String,
+    device_id: String,
+    x25519: String,
+    ed25519: String,
+    signature: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authorization: Option<JsonAuthorization>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonRevokePayload {
+    user_hash: String,
+    device_id: String,
+    revoking_device_id: String,
+    signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+enum JsonKeyTx {
+    AddDevice(JsonKeyPayload),
+    RotateDevice(JsonKeyPayload),
+    RevokeDevice(JsonRevokePayload),
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonKeyTxBody {
+    version: u32,
+    sequence: u64,
+    tx: JsonKeyTx,
+}
+
+#[derive(Deserialize)]
+struct JsonSignedKeyTx {
+    body: JsonKeyTxBody,
+    signer: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct JsonKeyUploadTx {
+    payload: JsonKeyPayload,
+    signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonGovernanceAction {
+    AddRelayer { key: String },
+    RemoveRelayer { key: String },
+    SetThreshold { threshold: u32 },
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonGovernanceBody {
+    version: u32,
+    set_version: u64,
+    action: JsonGovernanceAction,
+}
+
+#[derive(Deserialize)]
+struct JsonRelayerSignature {
+    signer: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct JsonGovernanceTx {
+    governance: JsonGovernanceBody,
+    signatures: Vec<JsonRelayerSignature>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonTx {
+    Key(JsonSignedKeyTx),
+    Governance(JsonGovernanceTx),
+    Unversioned(JsonKeyUploadTx),
+}
+
+fn decode_user_hash(s: &str) -> Result<[u8; 32], &'static str> {
+    hex::decode(s)
+        .map_err(|_| "user_hash is not valid hex")?
+        .try_into()
+        .map_err(|_| "user_hash must be 32 bytes")
+}
+
+impl TryFrom<&JsonKeyPayload> for KeyPayload {
+    type Error = &'static str;
+
+    fn try_from(p: &JsonKeyPayload) -> Result<Self, Self::Error> {
+        let (Ok(x25519), Ok(ed25519)) = (decode_key32(&p.x25519), decode_key32(&p.ed25519)) else {
+            return Err("invalid key encoding");
+        };
+        let authorization = p
+            .authorization
+            .as_ref()
+            .map(|a| {
+                Ok::<_, &'static str>(Authorization {
+                    authorizing_device_id: a.authorizing_device_id.clone(),
+                    signature: decode_sig64(&a.signature)?,
+                })
+            })
+            .transpose()?;
+        Ok(Self {
+            user_hash: decode_user_hash(&p.user_hash)?,
+            device_id: p.device_id.clone(),
+            x25519,
+            ed25519,
+            signature: decode_sig64(&p.signature)?,
+            authorization,
+        })
+    }
+}
+
+impl TryFrom<&JsonKeyTxBody> for KeyTxBody {
+    type Error = &'static str;
+
+    fn try_from(body: &JsonKeyTxBody) -> Result<Self, Self::Error> {
+        let tx = match &body.tx {
+            JsonKeyTx::AddDevice(p) => KeyTx::AddDevice(p.try_into()?),
+            JsonKeyTx::RotateDevice(p) => KeyTx::RotateDevice(p.try_into()?),
+            JsonKeyTx::RevokeDevice(p) => KeyTx::RevokeDevice(RevokePayload {
+                user_hash: decode_user_hash(&p.user_hash)?,
+                device_id: p.device_id.clone(),
+                revoking_device_id: p.revoking_device_id.clone(),
+                signature: decode_sig64(&p.signature)?,
+            }),
+        };
+        Ok(Self {
+            version: body.version,
+            sequence: body.sequence,
+            tx,
+        })
+    }
+}
+
+impl TryFrom<&JsonGovernanceBody> for GovernanceBody {
+    type Error = &'static str;
+
+    fn try_from(body: &JsonGovernanceBody) -> Result<Self, Self::Error> {
+        let action = match &body.action {
+            JsonGovernanceAction::AddRelayer { key } => GovernanceAction::AddRelayer {
+                key: decode_key32(key)?,
+            },
+            JsonGovernanceAction::RemoveRelayer { key } => GovernanceAction::RemoveRelayer {
+                key: decode_key32(key)?,
+            },
+            JsonGovernanceAction::SetThreshold { threshold } => GovernanceAction::SetThreshold {
+                threshold: *threshold,
+            },
+        };
+        Ok(Self {
+            version: body.version,
+            set_version: body.set_version,
+            action,
+        })
+    }
+}
+
+pub(super) fn decode_tx(bytes: &[u8]) -> Result<Tx, &'static str> {
+    match serde_json::from_slice::<JsonTx>(bytes).map_err(|_| "invalid JSON")? {
+        JsonTx::Key(tx) => Ok(Tx::Key(Box::new(SignedKeyTx {
+            body: (&tx.body).try_into()?,
+            signed: serde_json::to_vec(&tx.body).map_err(|_| "failed to serialize payload")?,
+            signer: decode_key32(&tx.signer)?,
+            signature: decode_sig64(&tx.signature)?,
+        }))),
+        JsonTx::Governance(tx) => Ok(Tx::Governance(GovernanceTx {
+            governance: (&tx.governance).try_into()?,
+            signed: serde_json::to_vec(&tx.governance)
+                .map_err(|_| "failed to serialize payload")?,
+            signatures: tx
+                .signatures
+                .iter()
+                .map(|s| {
+                    Ok(RelayerSignature {
+                        signer: decode_key32(&s.signer)?,
+                        signature: decode_sig64(&s.signature)?,
+                    })
+                })
+                .collect::<Result<_, &'static str>>()?,
+        })),
+        JsonTx::Unversioned(tx) => Ok(Tx::Unversioned(Box::new(UnversionedKeyTx {
+            payload: (&tx.payload).try_into()?,
+            signed: serde_json::to_vec(&tx.payload).map_err(|_| "failed to serialize payload")?,
+            signature: decode_sig64(&tx.signature)?,
+        }))),
+    }
+}
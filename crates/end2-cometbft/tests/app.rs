@@ -0,0 +1,551 @@
+mod common;
+
+use common::{Chain, Device};
+use ed25519_dalek::SigningKey;
+use end2_cometbft::{
+    policy::{CODE_INVALID, CODE_USER_BLOCK_LIMIT},
+    tx::{KeyBatchBody, KeyBatchEntry, KeyTx, KeyTxBody, TX_VERSION},
+};
+use sha2::{Digest, Sha256};
+
+const USER: [u8; 32] = [7; 32];
+
+fn user_hex() -> String {
+    hex::encode(USER)
+}
+
+fn attribute<'a>(event: &'a tendermint_proto::abci::Event, key: &str) -> &'a str {
+    event
+        .attributes
+        .iter()
+        .find(|a| a.key == key)
+        .map_or("", |a| a.value.as_str())
+}
+
+fn event_type(res: &tendermint_proto::abci::ResponseFinalizeBlock, i: usize) -> &str {
+    &res.tx_results[i].events[0].r#type
+}
+
+#[test]
+fn add_then_rotate_emits_events_and_updates_keys() {
+    let mut chain = Chain::new();
+    let phone = Device::new("phone", 1);
+    let phone2 = Device::new("phone", 2);
+
+    let res = chain.block(vec![
+        chain.key_tx(0, KeyTx::AddDevice(phone.payload(USER, None))),
+    ]);
+    assert_eq!(res.tx_results[0].code, 0, "{}", res.tx_results[0].log);
+    assert_eq!(event_type(&res, 0), "key_add");
+
+    let res = chain.block(vec![
+        chain.key_tx(1, KeyTx::RotateDevice(phone2.payload(USER, Some(&phone)))),
+    ]);
+    assert_eq!(res.tx_results[0].code, 0, "{}", res.tx_results[0].log);
+    assert_eq!(event_type(&res, 0), "key_update");
+
+    let query = chain.query("device", &format!("{}:phone", user_hex()), 0, false);
+    assert_eq!(query.code, 0, "{}", query.log);
+    let keys: serde_json::Value = serde_json::from_slice(&query.value).unwrap();
+    assert_eq!(keys["ed25519"], serde_json::json!(phone2.ed25519()));
+
+    // the first version is still readable at its height
+    let query = chain.query("device", &format!("{}:phone", user_hex()), 1, false);
+    let keys: serde_json::Value = serde_json::from_slice(&query.value).unwrap();
+    assert_eq!(keys["ed25519"], serde_json::json!(phone.ed25519()));
+    // and so is the sequence as of then
+    let query = chain.query("sequence", &user_hex(), 1, false);
+    assert_eq!(query.value.as_ref(), b"1");
+    let query = chain.query("sequence", &user_hex(), 0, false);
+    assert_eq!(query.value.as_ref(), b"2");
+}
+
+#[test]
+fn app_hash_is_stable_across_restarts_and_nodes() {
+    let laptop = Device::new("laptop", 3);
+    let tablet = Device::new("tablet", 4);
+
+    let run = |chain: &mut Chain| {
+        chain.block(vec![
+            chain.key_tx(0, KeyTx::AddDevice(laptop.payload(USER, None))),
+        ]);
+        chain.block(vec![]);
+        chain.block(vec![
+            chain.key_tx(1, KeyTx::AddDevice(tablet.payload(USER, Some(&laptop)))),
+        ]);
+    };
+
+    let mut a = Chain::new();
+    let mut b = Chain::new();
+    run(&mut a);
+    run(&mut b);
+    assert_eq!(a.app_hash(), b.app_hash());
+
+    let before = a.app_hash();
+    a.restart();
+    assert_eq!(a.app_hash(), before);
+
+    // the restarted node keeps applying blocks in step with the other
+    a.block(vec![
+        a.key_tx(2, KeyTx::RevokeDevice(laptop.revoke(USER, &tablet))),
+    ]);
+    b.block(vec![
+        b.key_tx(2, KeyTx::RevokeDevice(laptop.revoke(USER, &tablet))),
+    ]);
+    assert_eq!(a.app_hash(), b.app_hash());
+}
+
+#[test]
+fn rejects_invalid_key_txs() {
+    let mut chain = Chain::new();
+    let phone = Device::new("phone", 1);
+    let laptop = Device::new("laptop", 2);
+    chain.block(vec![
+        chain.key_tx(0, KeyTx::AddDevice(phone.payload(USER, None))),
+    ]);
+
+    let stranger = SigningKey::from_bytes(&[9; 32]);
+    let cases = [
+        (
+            "replayed sequence",
+            chain.key_tx(0, KeyTx::AddDevice(laptop.payload(USER, Some(&phone)))),
+        ),
+        (
+            "unauthorized second device",
+            chain.key_tx(1, KeyTx::AddDevice(laptop.payload(USER, None))),
+        ),
+        (
+            "rotate of a missing device",
+            chain.key_tx(1, KeyTx::RotateDevice(laptop.payload(USER, Some(&phone)))),
+        ),
+        (
+            "unknown relayer",
+            KeyTxBody {
+                version: TX_VERSION,
+                sequence: 1,
+                tx: KeyTx::AddDevice(laptop.payload(USER, Some(&phone))),
+            }
+            .sign(&stranger),
+        ),
+        ("garbage", vec![0xe2, 1, 2, 3]),
+    ];
+    for (name, tx) in cases {
+        let res = chain.check(tx.clone());
+        assert_eq!(res.code, CODE_INVALID, "check_tx accepted {name}");
+        let res = chain.block(vec![tx]);
+        assert_eq!(res.tx_results[0].code, CODE_INVALID, "block applied {name}");
+    }
+}
+
+#[test]
+fn revoked_device_cannot_come_back() {
+    let mut chain = Chain::new();
+    let phone = Device::new("phone", 1);
+    let laptop = Device::new("laptop", 2);
+    chain.block(vec![
+        chain.key_tx(0, KeyTx::AddDevice(phone.payload(USER, None))),
+        chain.key_tx(1, KeyTx::AddDevice(laptop.payload(USER, Some(&phone)))),
+        chain.key_tx(2, KeyTx::RevokeDevice(phone.revoke(USER, &laptop))),
+    ]);
+
+    let res = chain.block(vec![
+        chain.key_tx(3, KeyTx::AddDevice(laptop.payload(USER, Some(&phone)))),
+    ]);
+    assert_eq!(res.tx_results[0].code, CODE_INVALID);
+    assert_eq!(res.tx_results[0].log, "device_id has been revoked");
+
+    let query = chain.query("device", &format!("{}:laptop", user_hex()), 0, false);
+    assert_ne!(query.code, 0);
+}
+
+#[test]
+fn unversioned_uploads_apply_until_the_cutoff() {
+    let mut chain = Chain::with_genesis(serde_json::json!({
+        "tx_cutoffs": { "unversioned_height": 3, "json_height": 0 }
+    }));
+    let phone = Device::new("phone", 1);
+    let phone2 = Device::new("phone", 2);
+
+    let res = chain.block(vec![chain.unversioned_tx(&phone.payload(USER, None))]);
+    assert_eq!(res.tx_results[0].code, 0, "{}", res.tx_results[0].log);
+    assert_eq!(event_type(&res, 0), "key_add");
+
+    // an upload for an existing device rotates it, at the next sequence
+    let res = chain.block(vec![
+        chain.unversioned_tx(&phone2.payload(USER, Some(&phone))),
+    ]);
+    assert_eq!(res.tx_results[0].code, 0, "{}", res.tx_results[0].log);
+    assert_eq!(event_type(&res, 0), "key_update");
+    let query = chain.query("sequence", &user_hex(), 0, false);
+    assert_eq!(query.value.as_ref(), b"2");
+
+    let tx = chain.unversioned_tx(&Device::new("laptop", 3).payload(USER, Some(&phone2)));
+    assert_ne!(chain.check(tx.clone()).code, 0);
+    assert!(!chain.process(vec![tx.clone()]));
+    let res = chain.block(vec![tx]);
+    assert_eq!(res.tx_results[0].code, CODE_INVALID);
+
+    // a chain that doesn't set a cutoff never accepts them
+    let mut chain = Chain::new();
+    let res = chain.block(vec![chain.unversioned_tx(&phone.payload(USER, None))]);
+    assert_eq!(res.tx_results[0].code, CODE_INVALID);
+}
+
+#[test]
+fn json_txs_apply_until_the_cutoff() {
+    let mut chain = Chain::with_genesis(serde_json::json!({
+        "tx_cutoffs": { "unversioned_height": 0, "json_height": 2 }
+    }));
+    let phone = Device::new("phone", 1);
+    let laptop = Device::new("laptop", 2);
+    let tablet = Device::new("tablet", 3);
+
+    // both JSON encodings apply below the cutoff, next to binary txs
+    let res = chain.block(vec![
+        chain.unversioned_tx(&phone.payload(USER, None)),
+        chain.json_add_tx(1, &laptop.payload(USER, Some(&phone))),
+        chain.key_tx(2, KeyTx::AddDevice(tablet.payload(USER, Some(&phone)))),
+    ]);
+    for result in &res.tx_results {
+        assert_eq!(result.code, 0, "{}", result.log);
+    }
+    let query = chain.query("sequence", &user_hex(), 0, false);
+    assert_eq!(query.value.as_ref(), b"3");
+
+    let rotate = Device::new("phone", 4);
+    let tx = chain.json_add_tx(3, &Device::new("watch", 5).payload(USER, Some(&phone)));
+    assert_ne!(chain.check(tx.clone()).code, 0, "check_tx past the cutoff");
+    assert!(!chain.process(vec![tx.clone()]), "proposal past the cutoff");
+    assert!(chain.prepare(vec![tx.clone()], 1 << 20).is_empty());
+    let res = chain.block(vec![
+        tx,
+        chain.unversioned_tx(&rotate.payload(USER, Some(&phone))),
+        chain.key_tx(3, KeyTx::RotateDevice(rotate.payload(USER, Some(&phone)))),
+    ]);
+    assert_eq!(res.tx_results[0].code, CODE_INVALID);
+    assert_eq!(res.tx_results[1].code, CODE_INVALID);
+    assert_eq!(res.tx_results[2].code, 0, "{}", res.tx_results[2].log);
+
+    // a chain that doesn't set a cutoff never accepts them
+    let mut chain = Chain::new();
+    let res = chain.block(vec![chain.json_add_tx(0, &phone.payload(USER, None))]);
+    assert_eq!(res.tx_results[0].code, CODE_INVALID);
+}
+
+#[test]
+fn history_lists_every_event_in_order() {
+    let mut chain = Chain::new();
+    let phone = Device::new("phone", 1);
+    let laptop = Device::new("laptop", 2);
+    chain.block(vec![
+        chain.key_tx(0, KeyTx::AddDevice(phone.payload(USER, None))),
+    ]);
+    chain.block(vec![
+        chain.key_tx(1, KeyTx::AddDevice(laptop.payload(USER, Some(&phone)))),
+        chain.key_tx(2, KeyTx::RevokeDevice(phone.revoke(USER, &laptop))),
+    ]);
+
+    let query = chain.query("history", &user_hex(), 0, true);
+    assert_eq!(query.code, 0, "{}", query.log);
+    assert!(query.proof_ops.is_some());
+    let log: serde_json::Value = serde_json::from_slice(&query.value).unwrap();
+    assert_eq!(log["head"]["count"], 3);
+    assert_eq!(log["entries"].as_array().unwrap().len(), 3);
+
+    let query = chain.query("history", &hex::encode([8; 32]), 0, true);
+    assert_ne!(query.code, 0);
+    let ops = query.proof_ops.expect("absence proof").ops;
+    assert_eq!(
+        ops[0].key,
+        format!("h/{}", hex::encode([8; 32])).into_bytes()
+    );
+}
+
+#[test]
+fn device_lists_prove_their_count() {
+    let mut chain = Chain::new();
+    let phone = Device::new("phone", 1);
+    let laptop = Device::new("laptop", 2);
+    let count_key = format!("u/{}", user_hex()).into_bytes();
+
+    let query = chain.query("devices", &user_hex(), 0, true);
+    assert_ne!(query.code, 0);
+    let ops = query.proof_ops.expect("absence proof").ops;
+    assert_eq!(ops.len(), 1);
+    assert_eq!(ops[0].key, count_key);
+
+    chain.block(vec![
+        chain.key_tx(0, KeyTx::AddDevice(phone.payload(USER, None))),
+        chain.key_tx(1, KeyTx::AddDevice(laptop.payload(USER, Some(&phone)))),
+    ]);
+    let query = chain.query("devices", &user_hex(), 0, true);
+    assert_eq!(query.code, 0, "{}", query.log);
+    let keys: Vec<_> = query
+        .proof_ops
+        .expect("proofs")
+        .ops
+        .into_iter()
+        .map(|op| op.key)
+        .collect();
+    assert_eq!(
+        keys,
+        [
+            format!("{}/laptop", user_hex()).into_bytes(),
+            format!("{}/phone", user_hex()).into_bytes(),
+            count_key,
+        ]
+    );
+}
+
+#[test]
+fn per_block_limit_rejects_extra_txs() {
+    let mut chain = Chain::with_genesis(serde_json::json!({
+        "limits": { "max_user_txs_per_block": 1 }
+    }));
+    let phone = Device::new("phone", 1);
+    let laptop = Device::new("laptop", 2);
+
+    let res = chain.block(vec![
+        chain.key_tx(0, KeyTx::AddDevice(phone.payload(USER, None))),
+        chain.key_tx(1, KeyTx::AddDevice(laptop.payload(USER, Some(&phone)))),
+    ]);
+    assert_eq!(res.tx_results[0].code, 0);
+    assert_eq!(res.tx_results[1].code, CODE_USER_BLOCK_LIMIT);
+
+    // fine again in the next block
+    let res = chain.block(vec![
+        chain.key_tx(1, KeyTx::AddDevice(laptop.payload(USER, Some(&phone)))),
+    ]);
+    assert_eq!(res.tx_results[0].code, 0, "{}", res.tx_results[0].log);
+    assert_eq!(chain.height(), 2);
+}
+
+#[test]
+fn stats_and_user_pages_follow_device_counts() {
+    let mut chain = Chain::new();
+    let alice = [1; 32];
+    let bob = [2; 32];
+    let phone = Device::new("phone", 1);
+    let laptop = Device::new("laptop", 2);
+    chain.block(vec![
+        chain.key_tx(0, KeyTx::AddDevice(phone.payload(alice, None))),
+        chain.key_tx(1, KeyTx::AddDevice(laptop.payload(alice, Some(&phone)))),
+    ]);
+    chain.block(vec![
+        chain.key_tx(0, KeyTx::AddDevice(phone.payload(bob, None))),
+    ]);
+
+    let stats = |chain: &Chain, height| {
+        let query = chain.query("stats", "", height, false);
+        serde_json::from_slice::<serde_json::Value>(&query.value).unwrap()
+    };
+    assert_eq!(
+        stats(&chain, 0),
+        serde_json::json!({"users": 2, "devices": 3})
+    );
+    assert_eq!(
+        stats(&chain, 1),
+        serde_json::json!({"users": 1, "devices": 2})
+    );
+
+    let query = chain.query("users", ":1", 0, false);
+    assert_eq!(query.code, 0, "{}", query.log);
+    let page: serde_json::Value = serde_json::from_slice(&query.value).unwrap();
+    assert_eq!(page["users"][0]["user_hash"], hex::encode(alice));
+    assert_eq!(page["users"][0]["devices"], 2);
+    assert_eq!(page["next"], hex::encode(alice));
+
+    let query = chain.query("users", &format!("{}:1", hex::encode(alice)), 0, false);
+    let page: serde_json::Value = serde_json::from_slice(&query.value).unwrap();
+    assert_eq!(page["users"][0]["user_hash"], hex::encode(bob));
+    assert!(page.get("next").is_none());
+
+    // a revocation takes the device off the totals
+    chain.block(vec![
+        chain.key_tx(2, KeyTx::RevokeDevice(phone.revoke(alice, &laptop))),
+    ]);
+    assert_eq!(
+        stats(&chain, 0),
+        serde_json::json!({"users": 2, "devices": 2})
+    );
+    let query = chain.query("users", "", 0, false);
+    let page: serde_json::Value = serde_json::from_slice(&query.value).unwrap();
+    assert_eq!(page["users"][0]["devices"], 1);
+}
+
+#[test]
+fn proposals_are_ordered_and_filtered() {
+    let mut chain = Chain::new();
+    let phone = Device::new("phone", 1);
+    let laptop = Device::new("laptop", 2);
+    let phone2 = Device::new("phone", 3);
+    let other = [8; 32];
+
+    let add_phone = chain.key_tx(0, KeyTx::AddDevice(phone.payload(USER, None)));
+    let add_laptop = chain.key_tx(1, KeyTx::AddDevice(laptop.payload(USER, Some(&phone))));
+    let rotate = chain.key_tx(2, KeyTx::RotateDevice(phone2.payload(USER, Some(&phone))));
+    let add_other = chain.key_tx(0, KeyTx::AddDevice(phone.payload(other, None)));
+    let unauthorized = chain.key_tx(3, KeyTx::AddDevice(laptop.payload(USER, None)));
+
+    // the mempool saw USER's txs out of order, one twice, and some junk
+    let mempool = vec![
+        rotate.clone(),
+        add_other.clone(),
+        add_laptop.clone(),
+        add_phone.clone(),
+        add_laptop.clone(),
+        unauthorized,
+        vec![0xe2, 0, 1],
+    ];
+    let proposal = chain.prepare(mempool.clone(), 1 << 20);
+    assert_eq!(
+        proposal,
+        vec![
+            add_phone.clone(),
+            add_laptop.clone(),
+            rotate.clone(),
+            add_other.clone()
+        ]
+    );
+    assert!(chain.process(proposal.clone()));
+    assert!(!chain.process(mempool));
+    assert!(!chain.process(vec![add_laptop.clone(), add_phone.clone()]));
+
+    // max_tx_bytes keeps the first txs that fit, counting each one's tag and
+    // length varint the way CometBFT sizes a block's txs
+    let proto_size = |tx: &[u8]| 1 + 2 + tx.len();
+    let limit = (proto_size(&add_phone) + proto_size(&add_laptop)) as i64;
+    assert_eq!(
+        chain.prepare(proposal.clone(), limit),
+        vec![add_phone.clone(), add_laptop.clone()]
+    );
+    // one byte short, the laptop is left out and the smaller add after it
+    // still fits
+    assert_eq!(
+        chain.prepare(proposal.clone(), limit - 1),
+        vec![add_phone.clone(), add_other.clone()]
+    );
+
+    let res = chain.block(proposal);
+    assert!(res.tx_results.iter().all(|r| r.code == 0));
+}
+
+#[test]
+fn batch_reports_each_entry() {
+    let mut chain = Chain::new();
+    let alice = [1; 32];
+    let bob = [2; 32];
+    let phone = Device::new("phone", 1);
+    let laptop = Device::new("laptop", 2);
+
+    let batch = chain.key_batch(vec![
+        (0, KeyTx::AddDevice(phone.payload(alice, None))),
+        (0, KeyTx::AddDevice(phone.payload(bob, None))),
+        // unauthorized, so only this entry fails
+        (1, KeyTx::AddDevice(laptop.payload(alice, None))),
+        // sequences run on within the batch
+        (1, KeyTx::AddDevice(laptop.payload(alice, Some(&phone)))),
+    ]);
+    assert_eq!(chain.check(batch.clone()).code, 0);
+    let res = chain.block(vec![batch.clone()]);
+    let result = &res.tx_results[0];
+    assert_eq!(result.code, 0, "{}", result.log);
+
+    let entries: serde_json::Value = serde_json::from_slice(&result.data).unwrap();
+    let codes: Vec<u64> = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["code"].as_u64().unwrap())
+        .collect();
+    assert_eq!(codes, vec![0, 0, u64::from(CODE_INVALID), 0]);
+    let types: Vec<&str> = result.events.iter().map(|e| e.r#type.as_str()).collect();
+    assert_eq!(
+        types,
+        vec![
+            "key_add",
+            "key_add",
+            "key_batch_rejected",
+            "key_add",
+            "device_authorized"
+        ]
+    );
+    let indexes: Vec<&str> = result
+        .events
+        .iter()
+        .map(|e| attribute(e, "batch_index"))
+        .collect();
+    assert_eq!(indexes, vec!["0", "1", "2", "3", "3"]);
+
+    let query = chain.query("stats", "", 0, false);
+    let stats: serde_json::Value = serde_json::from_slice(&query.value).unwrap();
+    assert_eq!(stats, serde_json::json!({"users": 2, "devices": 3}));
+
+    // a replayed batch applies nothing
+    let res = chain.block(vec![batch]);
+    let entries: serde_json::Value = serde_json::from_slice(&res.tx_results[0].data).unwrap();
+    assert!(entries.as_array().unwrap().iter().all(|e| e["code"] != 0));
+
+    // an empty or foreign-signed batch fails as a whole
+    assert_eq!(chain.check(chain.key_batch(Vec::new())).code, CODE_INVALID);
+    let forged = KeyBatchBody {
+        version: TX_VERSION,
+        entries: vec![KeyBatchEntry {
+            sequence: 2,
+            tx: KeyTx::AddDevice(Device::new("tablet", 3).payload(alice, Some(&phone))),
+        }],
+    }
+    .sign(&SigningKey::from_bytes(&[9; 32]));
+    assert_eq!(chain.check(forged).code, CODE_INVALID);
+}
+
+#[test]
+fn events_index_keys_and_authorizations() {
+    let mut chain = Chain::new();
+    let phone = Device::new("phone", 1);
+    let laptop = Device::new("laptop", 2);
+    let fingerprint = |key: [u8; 32]| hex::encode(Sha256::digest(key));
+
+    chain.block(vec![
+        chain.key_tx(0, KeyTx::AddDevice(phone.payload(USER, None))),
+    ]);
+    let res = chain.block(vec![
+        chain.key_tx(1, KeyTx::AddDevice(laptop.payload(USER, Some(&phone)))),
+    ]);
+    let events = &res.tx_results[0].events;
+    assert_eq!(events.len(), 2);
+
+    let add = &events[0];
+    assert_eq!(add.r#type, "key_add");
+    assert!(add.attributes.iter().all(|a| a.index));
+    assert_eq!(attribute(add, "kind"), "add_device");
+    assert_eq!(attribute(add, "sequence"), "1");
+    assert_eq!(attribute(add, "authorizing_device_id"), "phone");
+    assert_eq!(
+        attribute(add, "ed25519_fingerprint"),
+        fingerprint(laptop.ed25519())
+    );
+    assert_eq!(
+        attribute(add, "x25519_fingerprint"),
+        fingerprint(laptop.x25519)
+    );
+
+    let edge = &events[1];
+    assert_eq!(edge.r#type, "device_authorized");
+    assert_eq!(attribute(edge, "user_hash"), user_hex());
+    assert_eq!(attribute(edge, "device_id"), "laptop");
+    assert_eq!(attribute(edge, "authorizing_device_id"), "phone");
+
+    // a revocation names the revoked keys and the revoking device
+    let res = chain.block(vec![
+        chain.key_tx(2, KeyTx::RevokeDevice(phone.revoke(USER, &laptop))),
+    ]);
+    let revoke = &res.tx_results[0].events[0];
+    assert_eq!(res.tx_results[0].events.len(), 1);
+    assert_eq!(attribute(revoke, "kind"), "revoke_device");
+    assert_eq!(attribute(revoke, "revoking_device_id"), "phone");
+    assert_eq!(
+        attribute(revoke, "ed25519_fingerprint"),
+        fingerprint(laptop.ed25519())
+    );
+}
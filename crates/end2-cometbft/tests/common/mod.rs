@@ -0,0 +1,277 @@
+// Drives KeyDirectoryApp the way CometBFT would, one synthetic block at a
+// time, against a RocksDB in a temp dir.
+
+#![allow(dead_code)]
+
+use ed25519_dalek::{Signer, SigningKey};
+use end2_cometbft::{
+    KeyDirectoryApp, Store,
+    tx::{
+        Authorization, KeyBatchBody, KeyBatchEntry, KeyPayload, KeyTx, KeyTxBody, REVOKE_CONTEXT,
+        RevokePayload, TX_VERSION,
+    },
+};
+use tempfile::TempDir;
+use tendermint_abci::Application;
+use tendermint_proto::abci::{
+    RequestCheckTx, RequestFinalizeBlock, RequestInfo, RequestInitChain, RequestPrepareProposal,
+    RequestProcessProposal, RequestQuery, ResponseCheckTx, ResponseFinalizeBlock, ResponseQuery,
+    response_process_proposal::ProposalStatus,
+};
+
+pub struct Chain {
+    dir: TempDir,
+    app: Option<KeyDirectoryApp>,
+    pub relayer: SigningKey,
+    height: u64,
+    snapshot_interval: u64,
+}
+
+impl Chain {
+    /// A chain with one relayer and no limits.
+    pub fn new() -> Self {
+        Self::with_genesis(serde_json::json!({}))
+    }
+
+    /// A chain whose genesis app_state is `app_state` plus the harness relayer.
+    pub fn with_genesis(app_state: serde_json::Value) -> Self {
+        Self::with_options(app_state, 0)
+    }
+
+    /// A chain that takes a state-sync snapshot every `interval` heights.
+    pub fn with_snapshots(interval: u64) -> Self {
+        Self::with_options(serde_json::json!({}), interval)
+    }
+
+    fn with_options(mut app_state: serde_json::Value, snapshot_interval: u64) -> Self {
+        let chain = Self::syncing(snapshot_interval);
+        app_state["relayers"] = serde_json::json!([b64(chain.relayer.verifying_key().as_bytes())]);
+        chain.app().init_chain(RequestInitChain {
+            app_state_bytes: serde_json::to_vec(&app_state).expect("json").into(),
+            initial_height: 1,
+            ..Default::default()
+        });
+        chain
+    }
+
+    /// A node that hasn't run init_chain, as one about to state sync is.
+    pub fn syncing(snapshot_interval: u64) -> Self {
+        let dir = tempfile::tempdir().expect("temp dir");
+        Self {
+            app: Some(open(&dir, snapshot_interval)),
+            dir,
+            relayer: SigningKey::from_bytes(&[1; 32]),
+            height: 0,
+            snapshot_interval,
+        }
+    }
+
+    pub fn app(&self) -> &KeyDirectoryApp {
+        self.app.as_ref().expect("app is open")
+    }
+
+    /// Closes the store and opens the app again on the same directory, as a
+    /// node restart would.
+    pub fn restart(&mut self) {
+        drop(self.app.take());
+        self.app = Some(open(&self.dir, self.snapshot_interval));
+    }
+
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Finalizes and commits a block holding `txs`.
+    pub fn block(&mut self, txs: Vec<Vec<u8>>) -> ResponseFinalizeBlock {
+        self.height += 1;
+        let res = self.app().finalize_block(RequestFinalizeBlock {
+            txs: txs.into_iter().map(Into::into).collect(),
+            height: self.height as i64,
+            ..Default::default()
+        });
+        self.app().commit();
+        res
+    }
+
+    /// The txs this node would propose for the next block out of `mempool`.
+    pub fn prepare(&self, mempool: Vec<Vec<u8>>, max_tx_bytes: i64) -> Vec<Vec<u8>> {
+        self.app()
+            .prepare_proposal(RequestPrepareProposal {
+                txs: mempool.into_iter().map(Into::into).collect(),
+                max_tx_bytes,
+                height: (self.height + 1) as i64,
+                ..Default::default()
+            })
+            .txs
+            .into_iter()
+            .map(|tx| tx.to_vec())
+            .collect()
+    }
+
+    /// Whether this node would vote for a proposed next block holding `txs`.
+    pub fn process(&self, txs: Vec<Vec<u8>>) -> bool {
+        let status = self
+            .app()
+            .process_proposal(RequestProcessProposal {
+                txs: txs.into_iter().map(Into::into).collect(),
+                height: (self.height + 1) as i64,
+                ..Default::default()
+            })
+            .status;
+        status == ProposalStatus::Accept as i32
+    }
+
+    pub fn check(&self, tx: Vec<u8>) -> ResponseCheckTx {
+        self.app().check_tx(RequestCheckTx {
+            tx: tx.into(),
+            ..Default::default()
+        })
+    }
+
+    pub fn query(&self, path: &str, data: &str, height: u64, prove: bool) -> ResponseQuery {
+        self.app().query(RequestQuery {
+            data: data.as_bytes().to_vec().into(),
+            path: path.to_owned(),
+            height: height as i64,
+            prove,
+        })
+    }
+
+    pub fn app_hash(&self) -> Vec<u8> {
+        self.app()
+            .info(RequestInfo::default())
+            .last_block_app_hash
+            .to_vec()
+    }
+
+    /// `tx` at `sequence`, signed by the harness relayer.
+    pub fn key_tx(&self, sequence: u64, tx: KeyTx) -> Vec<u8> {
+        KeyTxBody {
+            version: TX_VERSION,
+            sequence,
+            tx,
+        }
+        .sign(&self.relayer)
+    }
+
+    /// A batch of `(sequence, tx)` entries, signed by the harness relayer.
+    pub fn key_batch(&self, entries: Vec<(u64, KeyTx)>) -> Vec<u8> {
+        KeyBatchBody {
+            version: TX_VERSION,
+            entries: entries
+                .into_iter()
+                .map(|(sequence, tx)| KeyBatchEntry { sequence, tx })
+                .collect(),
+        }
+        .sign(&self.relayer)
+    }
+}
+
+impl Chain {
+    /// `payload` as the unversioned JSON upload from before txs carried a
+    /// sequence, signed by the harness relayer.
+    pub fn unversioned_tx(&self, payload: &KeyPayload) -> Vec<u8> {
+        let payload = json_payload(payload, "");
+        let signature = self.relayer.sign(payload.as_bytes()).to_bytes();
+        format!(
+            r#"{{"payload":{payload},"signature":"{}"}}"#,
+            b64(&signature)
+        )
+        .into_bytes()
+    }
+
+    /// `payload` as a JSON add_device tx at `sequence`, the encoding from
+    /// before the binary one, signed by the harness relayer.
+    pub fn json_add_tx(&self, sequence: u64, payload: &KeyPayload) -> Vec<u8> {
+        let tx = json_payload(payload, r#""type":"add_device","#);
+        let body = format!(r#"{{"version":{TX_VERSION},"sequence":{sequence},"tx":{tx}}}"#);
+        let signature = self.relayer.sign(body.as_bytes()).to_bytes();
+        format!(
+            r#"{{"body":{body},"signer":"{}","signature":"{}"}}"#,
+            b64(self.relayer.verifying_key().as_bytes()),
+            b64(&signature)
+        )
+        .into_bytes()
+    }
+}
+
+// `payload` as JSON, after `prefix` fields, in the field order relayers signed.
+fn json_payload(payload: &KeyPayload, prefix: &str) -> String {
+    let authorization = payload.authorization.as_ref().map_or(String::new(), |a| {
+        format!(
+            r#","authorization":{{"authorizing_device_id":"{}","signature":"{}"}}"#,
+            a.authorizing_device_id,
+            b64(&a.signature)
+        )
+    });
+    format!(
+        r#"{{{prefix}"user_hash":"{}","device_id":"{}","x25519":"{}","ed25519":"{}","signature":"{}"{authorization}}}"#,
+        hex::encode(payload.user_hash),
+        payload.device_id,
+        b64(&payload.x25519),
+        b64(&payload.ed25519),
+        b64(&payload.signature),
+    )
+}
+
+fn open(dir: &TempDir, snapshot_interval: u64) -> KeyDirectoryApp {
+    let store = Store::open(dir.path().to_str().expect("utf-8 path")).expect("open store");
+    KeyDirectoryApp::new(None, store, snapshot_interval, 0)
+}
+
+pub fn b64(bytes: &[u8]) -> String {
+    use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
+    BASE64_STANDARD_NO_PAD.encode(bytes)
+}
+
+/// A client device with deterministic keys derived from `seed`.
+pub struct Device {
+    pub id: String,
+    pub x25519: [u8; 32],
+    pub signing: SigningKey,
+}
+
+impl Device {
+    pub fn new(id: &str, seed: u8) -> Self {
+        Self {
+            id: id.to_owned(),
+            x25519: [seed; 32],
+            signing: SigningKey::from_bytes(&[seed.wrapping_add(100); 32]),
+        }
+    }
+
+    pub fn ed25519(&self) -> [u8; 32] {
+        self.signing.verifying_key().to_bytes()
+    }
+
+    /// This device's keys for `user`, self-signed and, if given, authorized by
+    /// `authorizer`.
+    pub fn payload(&self, user: [u8; 32], authorizer: Option<&Device>) -> KeyPayload {
+        let signature = self
+            .signing
+            .sign(&[self.x25519, self.ed25519()].concat())
+            .to_bytes();
+        KeyPayload {
+            user_hash: user,
+            device_id: self.id.clone(),
+            x25519: self.x25519,
+            ed25519: self.ed25519(),
+            signature,
+            authorization: authorizer.map(|a| Authorization {
+                authorizing_device_id: a.id.clone(),
+                signature: a.signing.sign(&signature).to_bytes(),
+            }),
+        }
+    }
+
+    /// A revocation of `target` signed by this device.
+    pub fn revoke(&self, user: [u8; 32], target: &Device) -> RevokePayload {
+        let msg = [REVOKE_CONTEXT, &target.x25519, &target.ed25519()].concat();
+        RevokePayload {
+            user_hash: user,
+            device_id: target.id.clone(),
+            revoking_device_id: self.id.clone(),
+            signature: self.signing.sign(&msg).to_bytes(),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{Chain, Device};
+use end2_cometbft::tx::KeyTx;
+use sha2::{Digest, Sha256};
+use tendermint_abci::Application;
+use tendermint_proto::abci::{
+    RequestApplySnapshotChunk, RequestLoadSnapshotChunk, RequestOfferSnapshot, Snapshot,
+    response_apply_snapshot_chunk, response_offer_snapshot,
+};
+
+const USER: [u8; 32] = [7; 32];
+
+// A chain with a device added, one rotated and one revoked, snapshotted at
+// its last height.
+fn snapshotted_chain() -> (Chain, Snapshot) {
+    let mut chain = Chain::with_snapshots(3);
+    let phone = Device::new("phone", 1);
+    let laptop = Device::new("laptop", 2);
+    let tablet = Device::new("tablet", 3);
+    chain.block(vec![
+        chain.key_tx(0, KeyTx::AddDevice(phone.payload(USER, None))),
+        chain.key_tx(1, KeyTx::AddDevice(laptop.payload(USER, Some(&phone)))),
+    ]);
+    chain.block(vec![
+        chain.key_tx(2, KeyTx::AddDevice(tablet.payload(USER, Some(&phone)))),
+        chain.key_tx(
+            3,
+            KeyTx::RotateDevice(Device::new("laptop", 4).payload(USER, Some(&phone))),
+        ),
+    ]);
+    chain.block(vec![
+        chain.key_tx(4, KeyTx::RevokeDevice(phone.revoke(USER, &tablet))),
+    ]);
+    let snapshot = snapshot_at(&chain, 3);
+    (chain, snapshot)
+}
+
+// The snapshot taken at `height`; they are written in the background.
+fn snapshot_at(chain: &Chain, height: u64) -> Snapshot {
+    let start = Instant::now();
+    loop {
+        let snapshots = chain.app().list_snapshots().snapshots;
+        if let Some(snapshot) = snapshots.into_iter().find(|s| s.height == height) {
+            return snapshot;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "no snapshot taken at {height}"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn chunks(chain: &Chain, snapshot: &Snapshot) -> Vec<Vec<u8>> {
+    (0..snapshot.chunks)
+        .map(|chunk| {
+            chain
+                .app()
+                .load_snapshot_chunk(RequestLoadSnapshotChunk {
+                    height: snapshot.height,
+                    format: snapshot.format,
+                    chunk,
+                })
+                .chunk
+                .to_vec()
+        })
+        .collect()
+}
+
+// Offers `snapshot` to `node` and applies `chunks`, returning the result of the
+// last one.
+fn restore(
+    node: &Chain,
+    snapshot: Snapshot,
+    app_hash: Vec<u8>,
+    chunks: Vec<Vec<u8>>,
+) -> response_apply_snapshot_chunk::Result {
+    let offered = node.app().offer_snapshot(RequestOfferSnapshot {
+        snapshot: Some(snapshot),
+        app_hash: app_hash.into(),
+    });
+    assert_eq!(
+        offered.result,
+        response_offer_snapshot::Result::Accept as i32
+    );
+
+    let mut result = response_apply_snapshot_chunk::Result::Unknown;
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        let applied = node.app().apply_snapshot_chunk(RequestApplySnapshotChunk {
+            index: index as u32,
+            chunk: chunk.into(),
+            sender: String::new(),
+        });
+        result = applied.result();
+    }
+    result
+}
+
+#[test]
+fn restored_node_matches_the_chain() {
+    let (chain, snapshot) = snapshotted_chain();
+    let node = Chain::syncing(0);
+
+    let chunks = chunks(&chain, &snapshot);
+    let result = restore(&node, snapshot, chain.app_hash(), chunks);
+    assert_eq!(result, response_apply_snapshot_chunk::Result::Accept);
+    assert_eq!(node.app_hash(), chain.app_hash());
+
+    let user = hex::encode(USER);
+    for (path, data) in [
+        ("devices", user.clone()),
+        ("device", format!("{user}:laptop")),
+        ("device", format!("{user}:tablet")),
+        ("history", user.clone()),
+        ("stats", String::new()),
+    ] {
+        let restored = node.query(path, &data, 0, true);
+        let original = chain.query(path, &data, 0, true);
+        assert_eq!(restored.code, original.code, "{path} {data}");
+        assert_eq!(restored.value, original.value, "{path} {data}");
+        assert_eq!(restored.proof_ops, original.proof_ops, "{path} {data}");
+    }
+}
+
+#[test]
+fn tampered_device_row_rejects_the_snapshot() {
+    let (chain, snapshot) = snapshotted_chain();
+    let node = Chain::syncing(0);
+
+    // A peer swaps the laptop's keys for its own and serves metadata that
+    // matches the tampered chunks, so only the rebuilt tree can catch it.
+    let mut chunks = chunks(&chain, &snapshot);
+    let attacker = Device::new("laptop", 9);
+    let laptop_key = format!("{}/laptop", hex::encode(USER)).into_bytes();
+    let mut tampered = false;
+    for chunk in &mut chunks {
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> = borsh::from_slice(chunk).unwrap();
+        for (k, v) in &mut entries {
+            if *k == laptop_key {
+                *v = serde_json::to_vec(&serde_json::json!({
+                    "x25519": attacker.x25519,
+                    "ed25519": attacker.ed25519(),
+                }))
+                .unwrap();
+                tampered = true;
+            }
+        }
+        *chunk = borsh::to_vec(&entries).unwrap();
+    }
+    assert!(tampered, "snapshot has no laptop row");
+
+    let chunk_hashes: Vec<[u8; 32]> = chunks.iter().map(|c| Sha256::digest(c).into()).collect();
+    let app_hash: [u8; 32] = chain.app_hash().try_into().unwrap();
+    let forged = Snapshot {
+        hash: Sha256::digest(chunk_hashes.concat()).to_vec().into(),
+        metadata: borsh::to_vec(&(app_hash, chunk_hashes)).unwrap().into(),
+        ..snapshot
+    };
+
+    let result = restore(&node, forged, app_hash.to_vec(), chunks);
+    assert_eq!(
+        result,
+        response_apply_snapshot_chunk::Result::RejectSnapshot
+    );
+    let query = node.query("device", &format!("{}:laptop", hex::encode(USER)), 0, false);
+    assert_ne!(query.code, 0, "tampered row survived the rejected restore");
+}
+
+#[test]
+fn a_new_offer_drops_the_rows_of_an_unfinished_restore() {
+    let (mut chain, first) = snapshotted_chain();
+    let user = hex::encode(USER);
+    let laptop_key = format!("{user}/laptop").into_bytes();
+
+    // The first snapshot as one chunk per row, so it can be left half done
+    // with the laptop row, which the second snapshot no longer has, written.
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = chunks(&chain, &first)
+        .iter()
+        .flat_map(|chunk| borsh::from_slice::<Vec<(Vec<u8>, Vec<u8>)>>(chunk).unwrap())
+        .collect();
+    let mut partial: Vec<Vec<u8>> = entries
+        .iter()
+        .map(|entry| borsh::to_vec(&vec![entry]).unwrap())
+        .collect();
+    let chunk_hashes: Vec<[u8; 32]> = partial.iter().map(|c| Sha256::digest(c).into()).collect();
+    let first_app_hash: [u8; 32] = chain.app_hash().try_into().unwrap();
+    let first = Snapshot {
+        chunks: partial.len() as u32,
+        hash: Sha256::digest(chunk_hashes.concat()).to_vec().into(),
+        metadata: borsh::to_vec(&(first_app_hash, chunk_hashes))
+            .unwrap()
+            .into(),
+        ..first
+    };
+    partial.pop();
+    assert!(
+        entries[..partial.len()]
+            .iter()
+            .any(|(k, _)| *k == laptop_key),
+        "laptop row is not in the applied chunks"
+    );
+
+    let laptop = Device::new("laptop", 4);
+    chain.block(vec![chain.key_tx(
+        5,
+        KeyTx::RevokeDevice(Device::new("phone", 1).revoke(USER, &laptop)),
+    )]);
+    chain.block(vec![]);
+    chain.block(vec![]);
+    let second = snapshot_at(&chain, 6);
+
+    let node = Chain::syncing(0);
+    let result = restore(&node, first, first_app_hash.to_vec(), partial);
+    assert_eq!(result, response_apply_snapshot_chunk::Result::Accept);
+
+    let chunks = chunks(&chain, &second);
+    let result = restore(&node, second, chain.app_hash(), chunks);
+    assert_eq!(result, response_apply_snapshot_chunk::Result::Accept);
+    assert_eq!(node.app_hash(), chain.app_hash());
+    let query = node.query("device", &format!("{user}:laptop"), 0, false);
+    assert_ne!(query.code, 0, "row of the abandoned restore survived");
+}
@@ -0,0 +1,148 @@
+// IDs are UUIDs written with a prefix naming what they identify, e.g.
+// `dev_0190...`, so one kind can't be passed where another is expected.
+
+use alloc::string::String;
+use core::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum IdError {
+    WrongPrefix {
+        expected: &'static str,
+        found: String,
+    },
+    InvalidUuid(uuid::Error),
+}
+
+impl fmt::Display for IdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::WrongPrefix { expected, found } => {
+                write!(f, "expected prefix '{expected}', got '{found}'")
+            }
+            Self::InvalidUuid(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl core::error::Error for IdError {}
+
+macro_rules! prefixed_uuid {
+    ($name:ident, $prefix:expr) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        #[cfg_attr(
+                    feature = "diesel",
+                    derive(diesel::expression::AsExpression, diesel::deserialize::FromSqlRow),
+                    diesel(sql_type = diesel::sql_types::Uuid)
+                )]
+        pub struct $name(Uuid);
+
+        impl $name {
+            #[cfg(feature = "std")]
+            #[must_use]
+            pub fn new_v7() -> Self {
+                Self(Uuid::now_v7())
+            }
+
+            #[must_use]
+            pub const fn into_inner(self) -> Uuid {
+                self.0
+            }
+        }
+
+        impl From<Uuid> for $name {
+            fn from(uuid: Uuid) -> Self {
+                Self(uuid)
+            }
+        }
+
+        impl TryFrom<&str> for $name {
+            type Error = IdError;
+
+            fn try_from(value: &str) -> Result<Self, Self::Error> {
+                let rest = value.strip_prefix(concat!($prefix, "_")).ok_or_else(|| {
+                    IdError::WrongPrefix {
+                        expected: $prefix,
+                        found: value.into(),
+                    }
+                })?;
+                Uuid::parse_str(rest)
+                    .map(Self)
+                    .map_err(IdError::InvalidUuid)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}_{}", $prefix, self.0)
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                Self::try_from(s.as_str()).map_err(serde::de::Error::custom)
+            }
+        }
+
+        #[cfg(feature = "diesel")]
+        impl diesel::deserialize::FromSql<diesel::sql_types::Uuid, diesel::pg::Pg> for $name {
+            fn from_sql(bytes: diesel::pg::PgValue<'_>) -> diesel::deserialize::Result<Self> {
+                let uuid = <Uuid as diesel::deserialize::FromSql<
+                    diesel::sql_types::Uuid,
+                    diesel::pg::Pg,
+                >>::from_sql(bytes)?;
+                Ok(Self(uuid))
+            }
+        }
+
+        #[cfg(feature = "diesel")]
+        impl diesel::serialize::ToSql<diesel::sql_types::Uuid, diesel::pg::Pg> for $name {
+            fn to_sql<'b>(
+                &'b self,
+                out: &mut diesel::serialize::Output<'b, '_, diesel::pg::Pg>,
+            ) -> diesel::serialize::Result {
+                <Uuid as diesel::serialize::ToSql<diesel::sql_types::Uuid, diesel::pg::Pg>>::to_sql(
+                    &self.0, out,
+                )
+            }
+        }
+    };
+}
+
+prefixed_uuid!(DeviceId, "dev");
+prefixed_uuid!(UserId, "usr");
+prefixed_uuid!(ChannelId, "ch");
+prefixed_uuid!(MessageId, "msg");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrong_prefix_names_both() {
+        let err = DeviceId::try_from("usr_00000000-0000-0000-0000-000000000000")
+            .expect_err("wrong prefix");
+        assert_eq!(
+            err.to_string(),
+            "expected prefix 'dev', got 'usr_00000000-0000-0000-0000-000000000000'"
+        );
+    }
+
+    #[test]
+    fn roundtrip_serde() {
+        let id = MessageId::from(Uuid::nil());
+        let json = serde_json::to_string(&id).expect("serialize");
+        assert_eq!(json, "\"msg_00000000-0000-0000-0000-000000000000\"");
+        let parsed: MessageId = serde_json::from_str(&json).expect("deserialize");
+        assert_eq!(parsed, id);
+    }
+}
@@ -0,0 +1,190 @@
+// The rules device keys are held to, by the backend before it relays an
+// upload and by the chain before it applies one.
+//
+// Every device signs x25519||ed25519 with its own ed25519 key, and once a
+// user has a device, any further upload must also carry an authorization from
+// one of those devices over the new self-signature. A device can only be
+// revoked by a different live device of the same user, signing over the keys
+// being revoked.
+
+use alloc::{collections::BTreeMap, string::String};
+
+use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::tx::{KeyPayload, RevokePayload};
+
+// Prefix of the message a device signs to revoke another device, followed by
+// the revoked device's x25519||ed25519 keys.
+pub const REVOKE_CONTEXT: &[u8] = b"end2:revoke:";
+
+// Field order matters: the chain stores these as JSON, and verified lookups
+// re-encode them to get the exact bytes its tree holds.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DeviceKeys {
+    pub x25519: [u8; 32],
+    pub ed25519: [u8; 32],
+}
+
+/// Decodes an unpadded base64 public key.
+///
+/// # Errors
+/// If `s` isn't base64 of 32 bytes.
+pub fn decode_key32(s: &str) -> Result<[u8; 32], &'static str> {
+    BASE64_STANDARD_NO_PAD
+        .decode(s)
+        .map_err(|_| "key is not valid base64")?
+        .try_into()
+        .map_err(|_| "key must be 32 bytes")
+}
+
+/// Decodes an unpadded base64 signature.
+///
+/// # Errors
+/// If `s` isn't base64 of 64 bytes.
+pub fn decode_sig64(s: &str) -> Result<[u8; 64], &'static str> {
+    BASE64_STANDARD_NO_PAD
+        .decode(s)
+        .map_err(|_| "signature is not valid base64")?
+        .try_into()
+        .map_err(|_| "signature must be 64 bytes")
+}
+
+/// Checks a device's signature over its own x25519||ed25519 keys.
+///
+/// # Errors
+/// If `ed25519` isn't a valid key or the signature doesn't verify.
+pub fn verify_self_signature(
+    x25519: &[u8; 32],
+    ed25519: &[u8; 32],
+    signature: &[u8; 64],
+) -> Result<(), &'static str> {
+    VerifyingKey::from_bytes(ed25519)
+        .map_err(|_| "ed25519 key is not a valid point")?
+        .verify_strict(
+            &[x25519.as_slice(), ed25519].concat(),
+            &Signature::from_bytes(signature),
+        )
+        .map_err(|_| "device self-signature verification failed")
+}
+
+/// Checks an existing device's authorization of a new device's keys, given as
+/// the new device's self-signature.
+///
+/// # Errors
+/// If `authorizing_ed25519` isn't a valid key or the signature doesn't verify.
+pub fn verify_authorization(
+    authorizing_ed25519: &[u8; 32],
+    self_signature: &[u8; 64],
+    signature: &[u8; 64],
+) -> Result<(), &'static str> {
+    VerifyingKey::from_bytes(authorizing_ed25519)
+        .map_err(|_| "stored authorizing ed25519 key is not a valid point")?
+        .verify_strict(self_signature, &Signature::from_bytes(signature))
+        .map_err(|_| "authorization verification failed")
+}
+
+/// Checks an upload against the user's device set as of the upload and
+/// returns the keys it sets.
+///
+/// # Errors
+/// If the self-signature doesn't verify, or the user has devices and none of
+/// them authorized the upload.
+pub fn verify_device_keys(
+    payload: &KeyPayload,
+    existing: &BTreeMap<String, DeviceKeys>,
+) -> Result<DeviceKeys, &'static str> {
+    verify_self_signature(&payload.x25519, &payload.ed25519, &payload.signature)?;
+
+    if !existing.is_empty() {
+        let authorization = payload
+            .authorization
+            .as_ref()
+            .ok_or("device keys must be authorized by an existing device")?;
+
+        let authorizing = existing
+            .get(&authorization.authorizing_device_id)
+            .ok_or("authorizing_device_id is not a registered device for this user")?;
+        verify_authorization(
+            &authorizing.ed25519,
+            &payload.signature,
+            &authorization.signature,
+        )?;
+    }
+
+    Ok(DeviceKeys {
+        x25519: payload.x25519,
+        ed25519: payload.ed25519,
+    })
+}
+
+/// Checks a revocation against the user's device set and returns the revoked
+/// keys.
+///
+/// # Errors
+/// If either device isn't live, a device revokes itself or the signature
+/// doesn't verify.
+pub fn verify_revocation(
+    payload: &RevokePayload,
+    existing: &BTreeMap<String, DeviceKeys>,
+) -> Result<DeviceKeys, &'static str> {
+    let revoked = existing
+        .get(&payload.device_id)
+        .ok_or("device_id is not a registered device for this user")?;
+
+    if payload.revoking_device_id == payload.device_id {
+        return Err("a device cannot revoke itself");
+    }
+    let revoking = existing
+        .get(&payload.revoking_device_id)
+        .ok_or("revoking_device_id is not a registered device for this user")?;
+    let revoking_key = VerifyingKey::from_bytes(&revoking.ed25519)
+        .map_err(|_| "stored revoking ed25519 key is not a valid point")?;
+
+    let msg = [REVOKE_CONTEXT, &revoked.x25519, &revoked.ed25519].concat();
+    revoking_key
+        .verify_strict(&msg, &Signature::from_bytes(&payload.signature))
+        .map_err(|_| "revocation signature verification failed")?;
+
+    Ok(revoked.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+    use crate::tx::Authorization;
+
+    fn upload(key: &SigningKey, authorizer: Option<(&str, &SigningKey)>) -> KeyPayload {
+        let x25519 = [7; 32];
+        let ed25519 = key.verifying_key().to_bytes();
+        let signature = key.sign(&[x25519, ed25519].concat()).to_bytes();
+        KeyPayload {
+            user_hash: [0; 32],
+            device_id: "dev_b".into(),
+            x25519,
+            ed25519,
+            signature,
+            authorization: authorizer.map(|(id, k)| Authorization {
+                authorizing_device_id: id.into(),
+                signature: k.sign(&signature).to_bytes(),
+            }),
+        }
+    }
+
+    #[test]
+    fn later_devices_need_authorization() {
+        let first = SigningKey::from_bytes(&[1; 32]);
+        let second = SigningKey::from_bytes(&[2; 32]);
+
+        let keys = verify_device_keys(&upload(&first, None), &BTreeMap::new()).expect("first");
+        let existing = BTreeMap::from([("dev_a".into(), keys)]);
+
+        assert!(verify_device_keys(&upload(&second, None), &existing).is_err());
+        assert!(verify_device_keys(&upload(&second, Some(("dev_a", &second))), &existing).is_err());
+        verify_device_keys(&upload(&second, Some(("dev_a", &first))), &existing)
+            .expect("authorized");
+    }
+}
@@ -0,0 +1,18 @@
+//! Types and checks shared by the end2 backend, the end2-cometbft ABCI app and
+//! the WASM client: the prefixed IDs, the key directory's tx encoding and the
+//! rules device keys are verified by. Keeping a single definition means the
+//! three can't drift apart without failing to compile.
+//!
+//! `no_std` with `alloc` when the default `std` feature is off.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod id;
+pub mod keys;
+pub mod message;
+pub mod tx;
+
+pub use id::{ChannelId, DeviceId, IdError, MessageId, UserId};
+pub use message::MessagePayload;
@@ -0,0 +1,14 @@
+use alloc::string::String;
+
+use serde::{Deserialize, Serialize};
+
+use crate::DeviceId;
+
+/// A message encrypted for one recipient device, as a client sends it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessagePayload {
+    pub recipient_device_id: DeviceId,
+    // base64 Olm message
+    pub ciphertext: String,
+    pub is_pre_key: bool,
+}
@@ -0,0 +1,190 @@
+// The key directory's binary tx encoding.
+//
+// A binary tx is BINARY_TX_TAG followed by borsh(WireTx). Bodies travel as the
+// exact borsh bytes that were signed, so a signature never depends on how
+// either side re-encodes a struct.
+
+use alloc::{string::String, vec, vec::Vec};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use ed25519_dalek::{Signer, SigningKey};
+use serde::{Deserialize, Serialize};
+
+pub const BINARY_TX_TAG: u8 = 0xe2;
+
+// Version 2 added `sequence`. Version 1 bodies carry nothing that stops a
+// replay, so they are rejected. Neither does the unversioned upload that
+// preceded them, which the chain only applies below its cutoff height.
+pub const TX_VERSION: u32 = 2;
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Authorization {
+    pub authorizing_device_id: String,
+    // by the authorizing device over the new device's self-signature
+    pub signature: [u8; 64],
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct KeyPayload {
+    pub user_hash: [u8; 32],
+    pub device_id: String,
+    pub x25519: [u8; 32],
+    pub ed25519: [u8; 32],
+    // by the device itself over x25519||ed25519
+    pub signature: [u8; 64],
+    pub authorization: Option<Authorization>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct RevokePayload {
+    pub user_hash: [u8; 32],
+    pub device_id: String,
+    pub revoking_device_id: String,
+    // by the revoking device over REVOKE_CONTEXT||x25519||ed25519 of the
+    // device being revoked
+    pub signature: [u8; 64],
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+#[allow(clippy::enum_variant_names)]
+pub enum KeyTx {
+    AddDevice(KeyPayload),
+    RotateDevice(KeyPayload),
+    RevokeDevice(RevokePayload),
+}
+
+impl KeyTx {
+    #[must_use]
+    pub const fn user_hash(&self) -> [u8; 32] {
+        match self {
+            Self::AddDevice(p) | Self::RotateDevice(p) => p.user_hash,
+            Self::RevokeDevice(p) => p.user_hash,
+        }
+    }
+
+    #[must_use]
+    pub fn user_hash_hex(&self) -> String {
+        hex::encode(self.user_hash())
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct KeyTxBody {
+    pub version: u32,
+    // Must equal the number of txs already committed for `tx.user_hash`, so
+    // each signed tx applies at most once and in order.
+    pub sequence: u64,
+    pub tx: KeyTx,
+}
+
+// Many key txs under one relayer signature. Each entry applies on its own, in
+// order, exactly as a KeyTxBody at that sequence would; one that fails is
+// reported and skipped without affecting the others.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct KeyBatchBody {
+    pub version: u32,
+    pub entries: Vec<KeyBatchEntry>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct KeyBatchEntry {
+    pub sequence: u64,
+    pub tx: KeyTx,
+}
+
+/// One element of the JSON array a key batch leaves in its `ExecTxResult`
+/// data, in entry order. `code` is 0 for an entry that applied.
+#[derive(Serialize, Deserialize)]
+pub struct BatchEntryResult {
+    pub code: u32,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub log: String,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct RelayerSignature {
+    pub signer: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub enum WireTx {
+    // `body` is borsh(KeyTxBody)
+    Key {
+        body: Vec<u8>,
+        // ed25519 key of the relayer that signed the body; must be in the
+        // chain's relayer set
+        signer: [u8; 32],
+        signature: [u8; 64],
+    },
+    // `body` is the ABCI app's borsh(GovernanceBody)
+    Governance {
+        body: Vec<u8>,
+        signatures: Vec<RelayerSignature>,
+    },
+    // `body` is borsh(KeyBatchBody)
+    KeyBatch {
+        body: Vec<u8>,
+        signer: [u8; 32],
+        signature: [u8; 64],
+    },
+}
+
+impl WireTx {
+    /// The tx bytes for this envelope.
+    ///
+    /// # Panics
+    /// Never; borsh encoding into a `Vec` can't fail.
+    #[must_use]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut tx = vec![BINARY_TX_TAG];
+        borsh::to_writer(&mut tx, self).expect("WireTx borsh");
+        tx
+    }
+
+    /// The envelope of a binary tx, or None for a tx without `BINARY_TX_TAG`.
+    ///
+    /// # Errors
+    /// If the bytes after the tag aren't a `WireTx`.
+    pub fn decode(tx: &[u8]) -> Result<Option<Self>, &'static str> {
+        tx.strip_prefix(&[BINARY_TX_TAG])
+            .map(|wire| borsh::from_slice(wire).map_err(|_| "invalid tx encoding"))
+            .transpose()
+    }
+}
+
+impl KeyTxBody {
+    /// The wire bytes of this body signed by `relayer`.
+    ///
+    /// # Panics
+    /// Never; borsh encoding into a `Vec` can't fail.
+    #[must_use]
+    pub fn sign(&self, relayer: &SigningKey) -> Vec<u8> {
+        let body = borsh::to_vec(self).expect("KeyTxBody borsh");
+        let signature = relayer.sign(&body).to_bytes();
+        WireTx::Key {
+            body,
+            signer: relayer.verifying_key().to_bytes(),
+            signature,
+        }
+        .encode()
+    }
+}
+
+impl KeyBatchBody {
+    /// The wire bytes of this body signed by `relayer`.
+    ///
+    /// # Panics
+    /// Never; borsh encoding into a `Vec` can't fail.
+    #[must_use]
+    pub fn sign(&self, relayer: &SigningKey) -> Vec<u8> {
+        let body = borsh::to_vec(self).expect("KeyBatchBody borsh");
+        let signature = relayer.sign(&body).to_bytes();
+        WireTx::KeyBatch {
+            body,
+            signer: relayer.verifying_key().to_bytes(),
+            signature,
+        }
+        .encode()
+    }
+}
@@ -52,7 +52,13 @@ export async function initDevice(user: UserInfo): Promise<ApiResult<null>> {
       return Err(response.error);
     }
 
-    const device = Device.new(response.value.device_id);
+    let device: Device;
+    try {
+      device = Device.new(response.value.device_id);
+    } catch (e) {
+      console.error("failed to create device: ", e);
+      return Err({ status: 0, message: "invalid device id" });
+    }
     context = markRaw(device);
 
     const keys = device.keys() as UploadDeviceKeys;
@@ -77,15 +77,15 @@ impl Device {
     ///
     /// # Errors
     /// Returns `JsError` if the device ID is not a valid UUID.
-    pub fn new(device_id: String) -> Self {
-        Self {
-            device_id: DeviceId(device_id),
+    pub fn new(device_id: &str) -> Result<Self, JsError> {
+        Ok(Self {
+            device_id: DeviceId::try_from(device_id)?,
             account: Account::new(),
-        }
+        })
     }
 
     pub fn device_id(&self) -> String {
-        self.device_id.0.clone()
+        self.device_id.to_string()
     }
 
     /// Serializes the device state for storage.
@@ -95,7 +95,7 @@ impl Device {
     pub fn to_pickle(&self) -> Result<JsValue, JsError> {
         let pickle = PickledDevice {
             account: self.account.pickle(),
-            device_id: self.device_id.clone(),
+            device_id: self.device_id,
         };
 
         Ok(serde_wasm_bindgen::to_value(&pickle)?)
@@ -124,7 +124,7 @@ impl Device {
         let signature = self.account.sign(&message);
 
         let payload = IdentityKeys {
-            device_id: self.device_id.clone(),
+            device_id: self.device_id,
             x25519: keys.curve25519.to_base64(),
             ed25519: keys.ed25519.to_base64(),
             signature: signature.to_base64(),
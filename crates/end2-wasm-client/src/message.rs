@@ -3,6 +3,8 @@ use time::OffsetDateTime;
 
 use crate::types::{ChannelId, DeviceId, MessageId, UserId};
 
+pub use end2_protocol::MessagePayload;
+
 #[derive(Deserialize)]
 pub struct InboundChatMessage {
     pub message_id: MessageId,
@@ -23,10 +25,3 @@ pub struct DecryptedMessage {
     #[serde(with = "time::serde::rfc3339")]
     pub timestamp: OffsetDateTime,
 }
-
-#[derive(Serialize)]
-pub struct MessagePayload {
-    pub recipient_device_id: DeviceId,
-    pub ciphertext: String, // b64encoded
-    pub is_pre_key: bool,
-}
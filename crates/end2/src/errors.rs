@@ -180,6 +180,11 @@ impl ApiError {
                 message: "failed to convert a value".to_string(),
                 detail: Some(s),
             },
+            AppError::VerificationFailed(s) => Self {
+                status: StatusCode::BAD_GATEWAY.into(),
+                message: "chain data failed verification".to_string(),
+                detail: Some(s),
+            },
         }
     }
 }
@@ -196,6 +201,12 @@ impl From<diesel::result::Error> for AppError {
     }
 }
 
+impl From<end2_protocol::IdError> for AppError {
+    fn from(value: end2_protocol::IdError) -> Self {
+        Self::ValueError(value.to_string())
+    }
+}
+
 impl From<JoinError> for AppError {
     fn from(e: JoinError) -> Self {
         Self::PoolError(e.to_string())
@@ -208,7 +219,7 @@ impl From<DecodeError> for AppError {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum AppError {
     ArgonError(String),
     ChallengeFailed(String),
@@ -224,6 +235,8 @@ pub enum AppError {
     QueryFailed(String),
     Unauthorized,
     ValueError(String),
+    /// Chain data that did not check out against the light client's trusted headers
+    VerificationFailed(String),
 }
 
 #[derive(Debug)]
@@ -322,6 +335,12 @@ mod tests {
         assert_eq!(api.status, 500);
     }
 
+    #[test]
+    fn app_error_verification_failed_maps_to_502() {
+        let api: ApiError = AppError::VerificationFailed("test".into()).into();
+        assert_eq!(api.status, 502);
+    }
+
     #[test]
     fn login_error_invalid_password_maps_to_401() {
         let api: ApiError = LoginError::InvalidPassword.into();
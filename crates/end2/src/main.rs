@@ -11,7 +11,7 @@ use diesel::{PgConnection, r2d2::ConnectionManager};
 use ed25519_dalek::SigningKey;
 use end2::{
     AppState, CometBftDeviceKeyService, DeviceKeyService, EthDeviceKeyService,
-    ForgingDeviceKeyService, MaliciousDeviceKeyService,
+    ForgingDeviceKeyService, LightClient, MaliciousDeviceKeyService, TrustOptions,
 };
 use mimalloc::MiMalloc;
 use opentelemetry::trace::TracerProvider;
@@ -106,8 +106,94 @@ fn setup_comet_device_keys(
     pool: Pool<ConnectionManager<PgConnection>>,
     signing_key: Arc<SigningKey>,
 ) -> Arc<dyn DeviceKeyService> {
-    let rpc_url = std::env::var("COMET_RPC_URL").expect("COMET_RPC_URL must be set");
-    Arc::new(CometBftDeviceKeyService::new(rpc_url, signing_key, pool))
+    // COMET_RPC_URL may list several nodes, comma separated, most preferred
+    // first; the event subscriptions use the first.
+    let rpc_urls: Vec<String> = std::env::var("COMET_RPC_URL")
+        .expect("COMET_RPC_URL must be set")
+        .split(',')
+        .map(|url| url.trim().to_owned())
+        .filter(|url| !url.is_empty())
+        .collect();
+    let rpc_url = rpc_urls.first().expect("COMET_RPC_URL is empty").clone();
+    let ws_url = comet_ws_url(&rpc_url);
+    let mut service = CometBftDeviceKeyService::new(rpc_url.clone(), signing_key, pool)
+        .with_tx_events(ws_url.clone());
+    if rpc_urls.len() > 1 {
+        service = service.with_rpc_endpoints(rpc_urls);
+    }
+    if let Some(options) = comet_trust_options() {
+        tracing::info!(
+            height = options.height,
+            "verifying chain data with a light client"
+        );
+        service = service.with_light_client(LightClient::new(options));
+    }
+    if let Some(max_age) = comet_read_cache_max_age() {
+        service = service.with_read_cache(ws_url, max_age);
+    }
+    // COMET_PENDING_UPLOADS=1 answers uploads once they passed check_tx.
+    if std::env::var("COMET_PENDING_UPLOADS").is_ok_and(|v| v == "1") {
+        service = service.with_pending_uploads();
+    }
+    if let Some((window, max_entries)) = comet_batching() {
+        service = service.with_batching(window, max_entries);
+    }
+    Arc::new(service)
+}
+
+// COMET_WS_URL, or the RPC URL's own /websocket endpoint.
+fn comet_ws_url(rpc_url: &str) -> String {
+    std::env::var("COMET_WS_URL").unwrap_or_else(|_| {
+        let base = rpc_url.trim_end_matches('/');
+        let base = base
+            .strip_prefix("http")
+            .map_or_else(|| base.to_owned(), |rest| format!("ws{rest}"));
+        format!("{base}/websocket")
+    })
+}
+
+// COMET_TRUST_HEIGHT and COMET_TRUST_HASH (hex) name a block header to trust;
+// setting them turns on verified mode. COMET_TRUSTING_PERIOD_SECS defaults to
+// one week.
+fn comet_trust_options() -> Option<TrustOptions> {
+    let height = std::env::var("COMET_TRUST_HEIGHT").ok()?;
+    let hash = std::env::var("COMET_TRUST_HASH")
+        .expect("COMET_TRUST_HASH must be set with COMET_TRUST_HEIGHT");
+    let period = std::env::var("COMET_TRUSTING_PERIOD_SECS").map_or(7 * 24 * 60 * 60, |s| {
+        s.parse().expect("invalid COMET_TRUSTING_PERIOD_SECS")
+    });
+    Some(TrustOptions {
+        height: height.parse().expect("invalid COMET_TRUST_HEIGHT"),
+        hash: hex::decode(hash)
+            .ok()
+            .and_then(|h| h.try_into().ok())
+            .expect("COMET_TRUST_HASH must be 32 bytes of hex"),
+        period: std::time::Duration::from_secs(period),
+    })
+}
+
+// Device lookups are cached by default. COMET_CACHE_MAX_AGE_MS (default
+// 10000) bounds how long a cached device list is served before it is read
+// again, 0 turning the cache off.
+fn comet_read_cache_max_age() -> Option<std::time::Duration> {
+    let max_age = std::env::var("COMET_CACHE_MAX_AGE_MS").map_or(10_000, |s| {
+        s.parse().expect("invalid COMET_CACHE_MAX_AGE_MS")
+    });
+    (max_age > 0).then(|| std::time::Duration::from_millis(max_age))
+}
+
+// Key uploads are batched by default. COMET_BATCH_WINDOW_MS (default 20) is
+// how long a batch waits for more uploads, 0 turning batching off, and
+// COMET_BATCH_MAX_ENTRIES (default 64) caps its size.
+fn comet_batching() -> Option<(std::time::Duration, usize)> {
+    let window = std::env::var("COMET_BATCH_WINDOW_MS")
+        .map_or(20, |s| s.parse().expect("invalid COMET_BATCH_WINDOW_MS"));
+    if window == 0 {
+        return None;
+    }
+    let max_entries = std::env::var("COMET_BATCH_MAX_ENTRIES")
+        .map_or(64, |s| s.parse().expect("invalid COMET_BATCH_MAX_ENTRIES"));
+    Some((std::time::Duration::from_millis(window), max_entries))
 }
 
 fn setup_db_device_keys(pool: Pool<ConnectionManager<PgConnection>>) -> Arc<dyn DeviceKeyService> {
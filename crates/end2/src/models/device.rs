@@ -1,6 +1,8 @@
 use diesel::{Insertable, Queryable, Selectable};
 use ed25519_dalek::Signature;
+use end2_protocol::keys::{verify_authorization, verify_self_signature};
 use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
 use vodozemac::{Curve25519PublicKey, Ed25519PublicKey, Ed25519Signature};
 
 use crate::{AppError, DeviceId, UserId, serialize_as_base64, serialize_as_base64_opt};
@@ -30,6 +32,67 @@ pub struct HistoricalKey {
     pub signature: Vec<u8>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub authorization: Option<InboundAuthorization>,
+    /// Hash of the tx that uploaded the keys, upper-case hex
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx_hash: Option<String>,
+    /// Time of the block the keys were committed in
+    #[serde(
+        with = "time::serde::rfc3339::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub block_time: Option<OffsetDateTime>,
+}
+
+/// Which part of a device's key history to return, oldest first. The
+/// `chain_height` of the last entry returned is the `since_height` of the
+/// next page.
+#[derive(Debug, Default, Deserialize)]
+pub struct KeyHistoryQuery {
+    /// Only entries committed above this height
+    pub since_height: Option<u64>,
+    /// At most this many entries, except that entries sharing a height are
+    /// never split across pages, so the last page may run over
+    pub limit: Option<usize>,
+}
+
+/// A device lookup as returned by the key directory together with its Merkle
+/// proof, so a client can check it against the app hash of a signed header.
+#[derive(Debug, Serialize)]
+pub struct DeviceKeyProof {
+    pub device_id: DeviceId,
+    /// Height of the state the proof is from. Its app hash is in the header
+    /// of the next block, `chain_height + 1`, which is the one to check it
+    /// against
+    pub chain_height: u64,
+    /// Key the proof is for in the chain's store
+    #[serde(serialize_with = "serialize_as_base64")]
+    pub key: Vec<u8>,
+    /// Stored value, absent if the proof is a non-inclusion proof
+    #[serde(serialize_with = "serialize_as_base64_opt")]
+    pub value: Option<Vec<u8>>,
+    /// Encoded proof, format given by `proof_type`
+    pub proof_type: String,
+    #[serde(serialize_with = "serialize_as_base64")]
+    pub proof: Vec<u8>,
+}
+
+/// Where a key upload stands on the key directory, for backends that answer
+/// an upload before it is final.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum UploadStatus {
+    /// Accepted into the mempool, not yet in a block
+    Pending {
+        tx_hash: String,
+    },
+    Committed {
+        tx_hash: String,
+        chain_height: u64,
+    },
+    Failed {
+        tx_hash: String,
+        reason: String,
+    },
 }
 
 #[derive(Insertable)]
@@ -57,22 +120,53 @@ pub struct InboundAuthorization {
     pub signature: String,
 }
 
+/// Revokes a device: `signature` is made by `revoking_device_id`, another live
+/// device of the same user, over `"end2:revoke:" || x25519 || ed25519` of the
+/// device being revoked.
+#[derive(Debug, Deserialize)]
+pub struct InboundRevocation {
+    pub revoking_device_id: DeviceId,
+    pub signature: String,
+}
+
 impl InboundAuthorization {
     pub fn verify(
         &self,
         previous_key: &Ed25519PublicKey,
         keys_signature: Signature,
     ) -> Result<(), AppError> {
-        let verifying_key = ed25519_dalek::VerifyingKey::from_bytes(previous_key.as_bytes())
-            .map_err(|e| AppError::InvalidKey(e.to_string()))?;
-
-        let signature_bytes = Ed25519Signature::from_base64(&self.signature)
+        let signature = Ed25519Signature::from_base64(&self.signature)
             .map_err(|_| AppError::InvalidSignature)?;
-        let signature = Signature::from_bytes(&signature_bytes.to_bytes());
 
-        verifying_key
-            .verify_strict(&keys_signature.to_bytes(), &signature)
-            .map_err(|e| AppError::ChallengeFailed(e.to_string()))
+        verify_authorization(
+            previous_key.as_bytes(),
+            &keys_signature.to_bytes(),
+            &signature.to_bytes(),
+        )
+        .map_err(|e| AppError::ChallengeFailed(e.into()))
+    }
+}
+
+impl KeyHistoryQuery {
+    /// Cuts the page out of a history sorted by height.
+    pub fn page<T>(&self, history: Vec<T>, height: impl Fn(&T) -> u64) -> Vec<T> {
+        let mut page: Vec<T> = history
+            .into_iter()
+            .filter(|e| self.since_height.is_none_or(|since| height(e) > since))
+            .collect();
+        match self.limit {
+            Some(0) => page.clear(),
+            Some(limit) if limit < page.len() => {
+                let last = height(&page[limit - 1]);
+                let rest = page[limit..]
+                    .iter()
+                    .take_while(|e| height(e) == last)
+                    .count();
+                page.truncate(limit + rest);
+            }
+            _ => {}
+        }
+        page
     }
 }
 
@@ -85,16 +179,8 @@ impl NewDevice {
         let signature = Ed25519Signature::from_base64(&device.signature)
             .map_err(|_| AppError::InvalidSignature)?;
 
-        let verifying_key = ed25519_dalek::VerifyingKey::from_bytes(ed25519.as_bytes())
-            .map_err(|e| AppError::InvalidKey(e.to_string()))?;
-
-        let signature = Signature::from_bytes(&signature.to_bytes());
-
-        let message = [x25519.as_bytes() as &[u8], ed25519.as_bytes()].concat();
-
-        verifying_key
-            .verify_strict(&message, &signature)
-            .map_err(|e| AppError::ChallengeFailed(e.to_string()))?;
+        verify_self_signature(x25519.as_bytes(), ed25519.as_bytes(), &signature.to_bytes())
+            .map_err(|e| AppError::ChallengeFailed(e.into()))?;
 
         Ok(Self {
             user_id,
@@ -165,4 +251,23 @@ mod tests {
         auth.verify(&prev_key, tampered)
             .expect_err("tampered self-sig must fail authorization check");
     }
+
+    #[test]
+    fn history_page_keeps_heights_whole() {
+        let heights = vec![3, 5, 5, 5, 8, 9];
+        let page = |since_height, limit| {
+            KeyHistoryQuery {
+                since_height,
+                limit,
+            }
+            .page(heights.clone(), |h| *h)
+        };
+
+        assert_eq!(page(None, None), heights);
+        assert_eq!(page(Some(5), None), vec![8, 9]);
+        assert_eq!(page(None, Some(2)), vec![3, 5, 5, 5]);
+        assert_eq!(page(Some(3), Some(4)), vec![5, 5, 5, 8]);
+        assert_eq!(page(Some(9), Some(1)), Vec::<u64>::new());
+        assert_eq!(page(None, Some(0)), Vec::<u64>::new());
+    }
 }
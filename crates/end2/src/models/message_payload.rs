@@ -1,7 +1,6 @@
 use crate::{DeviceId, MessageId};
 use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
 use diesel::{Insertable, Queryable, Selectable};
-use serde::Deserialize;
 
 use crate::AppError;
 
@@ -25,20 +24,19 @@ pub struct NewMessagePayload {
     pub is_pre_key: bool,
 }
 
-#[derive(Clone, Debug, Deserialize)]
-pub struct InboundMessagePayload {
-    pub recipient_device_id: DeviceId,
-    pub ciphertext: String,
-    pub is_pre_key: bool,
-}
+// What clients send, shared with the WASM client that builds it.
+pub use end2_protocol::MessagePayload as InboundMessagePayload;
 
-impl InboundMessagePayload {
-    pub fn into_new_message(self, message_id: MessageId) -> Result<NewMessagePayload, AppError> {
-        Ok(NewMessagePayload {
+impl NewMessagePayload {
+    pub fn from_inbound(
+        message_id: MessageId,
+        payload: InboundMessagePayload,
+    ) -> Result<Self, AppError> {
+        Ok(Self {
             message_id,
-            recipient_device_id: self.recipient_device_id,
-            ciphertext: BASE64_STANDARD_NO_PAD.decode(self.ciphertext)?,
-            is_pre_key: self.is_pre_key,
+            recipient_device_id: payload.recipient_device_id,
+            ciphertext: BASE64_STANDARD_NO_PAD.decode(payload.ciphertext)?,
+            is_pre_key: payload.is_pre_key,
         })
     }
 }
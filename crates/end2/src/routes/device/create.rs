@@ -1,4 +1,4 @@
-use crate::{ApiError, AppError, AppState, Device, DeviceId, InboundDevice, User};
+use crate::{ApiError, AppError, AppState, Device, DeviceId, InboundDevice, UploadStatus, User};
 use axum::{
     Json,
     extract::{Path, State},
@@ -7,8 +7,19 @@ use axum::{
 use base64::prelude::BASE64_STANDARD;
 use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
 use ed25519_dalek::Signature;
+use serde::Serialize;
 use vodozemac::{Curve25519PublicKey, Ed25519PublicKey, Ed25519Signature};
 
+// The device as uploaded, plus where the upload stands if the backend
+// answered before it was final.
+#[derive(Serialize)]
+struct UploadedDevice {
+    #[serde(flatten)]
+    device: Device,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upload: Option<UploadStatus>,
+}
+
 #[tracing::instrument(skip(app_state))]
 pub async fn new_device(
     State(app_state): State<AppState>,
@@ -25,8 +36,8 @@ pub async fn upload_keys(
     Path(device_id): Path<DeviceId>,
     Json(inbound_device_keys): Json<InboundDevice>,
 ) -> Result<impl IntoResponse, ApiError> {
-    let device = validate_device_keys(&app_state, user, device_id, inbound_device_keys).await?;
-    Ok(Json(device))
+    let device = validate_device_keys(&app_state, &user, device_id, inbound_device_keys).await?;
+    Ok(Json(uploaded(&app_state, &user, device).await))
 }
 
 #[tracing::instrument(skip(app_state))]
@@ -38,19 +49,41 @@ pub async fn upload_keys_me(
     let device_id = inbound_device_keys
         .device_id
         .ok_or_else(|| AppError::UserError("no device id provided".to_string()))?;
-    let device = validate_device_keys(&app_state, user, device_id, inbound_device_keys).await?;
-    Ok(Json(device))
+    let device = validate_device_keys(&app_state, &user, device_id, inbound_device_keys).await?;
+    Ok(Json(uploaded(&app_state, &user, device).await))
+}
+
+#[tracing::instrument(skip(app_state))]
+pub async fn get_upload_status(
+    State(app_state): State<AppState>,
+    user: User,
+    Path(device_id): Path<DeviceId>,
+) -> Result<impl IntoResponse, ApiError> {
+    let status = app_state
+        .device_keys
+        .get_upload_status(&user, device_id)
+        .await?;
+    Ok(Json(status))
+}
+
+async fn uploaded(app_state: &AppState, user: &User, device: Device) -> UploadedDevice {
+    let upload = app_state
+        .device_keys
+        .get_upload_status(user, device.id)
+        .await
+        .ok();
+    UploadedDevice { device, upload }
 }
 
 async fn validate_device_keys(
     app_state: &AppState,
-    user: User,
+    user: &User,
     device_id: DeviceId,
     inbound_device_keys: InboundDevice,
 ) -> Result<Device, AppError> {
     let existing: Vec<_> = app_state
         .device_keys
-        .get_all_devices(&user)
+        .get_all_devices(user)
         .await?
         .into_iter()
         .filter(|d| d.ed25519.is_some())
@@ -93,7 +126,7 @@ async fn validate_device_keys(
 
     let device = app_state
         .device_keys
-        .set_device_keys(&user, device_id, inbound_device_keys)
+        .set_device_keys(user, device_id, inbound_device_keys)
         .await?;
 
     Ok(device)
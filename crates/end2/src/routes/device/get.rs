@@ -1,7 +1,7 @@
-use crate::{ApiError, AppError, AppState, DeviceId, User, UserId};
+use crate::{ApiError, AppError, AppState, DeviceId, KeyHistoryQuery, User, UserId};
 use axum::{
     Json,
-    extract::{Path, State},
+    extract::{Path, Query, State},
     response::IntoResponse,
 };
 
@@ -61,12 +61,33 @@ pub async fn get_user_devices(
     Ok(Json(devices))
 }
 
+#[allow(clippy::used_underscore_binding)]
+#[tracing::instrument(skip(app_state))]
+pub async fn get_user_device_at_height(
+    State(app_state): State<AppState>,
+    // _user: User,
+    Path((user_id, device_id, height)): Path<(UserId, DeviceId, u64)>,
+) -> Result<impl IntoResponse, ApiError> {
+    let target_user = app_state
+        .auth
+        .get_user_info(user_id)
+        .await?
+        .ok_or(AppError::NoSuchUser)?;
+
+    let device = app_state
+        .device_keys
+        .get_device_at_height(&target_user, device_id, height)
+        .await?;
+    Ok(Json(device))
+}
+
 #[allow(clippy::used_underscore_binding)]
 #[tracing::instrument(skip(app_state))]
 pub async fn get_user_device_key_history(
     State(app_state): State<AppState>,
     // _user: User,
     Path((user_id, device_id)): Path<(UserId, DeviceId)>,
+    Query(query): Query<KeyHistoryQuery>,
 ) -> Result<impl IntoResponse, ApiError> {
     let target_user = app_state
         .auth
@@ -76,7 +97,27 @@ pub async fn get_user_device_key_history(
 
     let history = app_state
         .device_keys
-        .get_device_key_history(&target_user, device_id)
+        .get_device_key_history(&target_user, device_id, &query)
         .await?;
     Ok(Json(history))
 }
+
+#[allow(clippy::used_underscore_binding)]
+#[tracing::instrument(skip(app_state))]
+pub async fn get_user_device_proof(
+    State(app_state): State<AppState>,
+    // _user: User,
+    Path((user_id, device_id)): Path<(UserId, DeviceId)>,
+) -> Result<impl IntoResponse, ApiError> {
+    let target_user = app_state
+        .auth
+        .get_user_info(user_id)
+        .await?
+        .ok_or(AppError::NoSuchUser)?;
+
+    let proof = app_state
+        .device_keys
+        .get_device_proof(&target_user, device_id)
+        .await?;
+    Ok(Json(proof))
+}
@@ -1,7 +1,9 @@
 mod create;
 mod get;
 mod otk;
+mod revoke;
 
 pub use create::*;
 pub use get::*;
 pub use otk::*;
+pub use revoke::*;
@@ -0,0 +1,20 @@
+use crate::{ApiError, AppState, DeviceId, InboundRevocation, User};
+use axum::{
+    Json,
+    extract::{Path, State},
+    response::IntoResponse,
+};
+
+#[tracing::instrument(skip(app_state))]
+pub async fn revoke_device(
+    State(app_state): State<AppState>,
+    user: User,
+    Path(device_id): Path<DeviceId>,
+    Json(revocation): Json<InboundRevocation>,
+) -> Result<impl IntoResponse, ApiError> {
+    app_state
+        .device_keys
+        .revoke_device(&user, device_id, revocation)
+        .await?;
+    Ok(Json(serde_json::json!({ "status": "success" })))
+}
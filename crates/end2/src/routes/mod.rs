@@ -39,7 +39,13 @@ impl Api {
             .route("/me/devices", get(device::get_devices))
             .route(
                 "/me/device/{device_id}",
-                get(device::get_device).put(device::upload_keys),
+                get(device::get_device)
+                    .put(device::upload_keys)
+                    .delete(device::revoke_device),
+            )
+            .route(
+                "/me/device/{device_id}/upload",
+                get(device::get_upload_status),
             )
             .route(
                 "/me/device/{device_id}/otks",
@@ -52,10 +58,18 @@ impl Api {
                 "/user/{user_id}/device/{device_id}",
                 get(device::get_user_device),
             )
+            .route(
+                "/user/{user_id}/device/{device_id}/at/{height}",
+                get(device::get_user_device_at_height),
+            )
             .route(
                 "/user/{user_id}/device/{device_id}/history",
                 get(device::get_user_device_key_history),
             )
+            .route(
+                "/user/{user_id}/device/{device_id}/proof",
+                get(device::get_user_device_proof),
+            )
             .route(
                 "/user/{user_id}/device/{device_id}/otk",
                 post(device::get_user_device_otk),
@@ -1,49 +1,367 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
 use base64::{Engine, prelude::BASE64_STANDARD, prelude::BASE64_STANDARD_NO_PAD};
 use diesel::{
-    BoolExpressionMethods, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl,
-    SelectableHelper, r2d2::ConnectionManager,
+    BoolExpressionMethods, ExpressionMethods, PgConnection, RunQueryDsl, SelectableHelper,
+    r2d2::ConnectionManager,
+};
+use ed25519_dalek::SigningKey;
+use end2_protocol::{
+    keys::DeviceKeys,
+    tx::{
+        Authorization, BatchEntryResult, KeyBatchBody, KeyBatchEntry, KeyPayload, KeyTx, KeyTxBody,
+        RevokePayload, TX_VERSION, WireTx,
+    },
 };
-use ed25519_dalek::{Signer, SigningKey};
+use futures::{StreamExt, TryStreamExt, future::BoxFuture, stream};
+use jmt::{KeyHash, RootHash, proof::SparseMerkleProof};
 use r2d2::Pool;
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::sync::Mutex;
 use vodozemac::{Curve25519PublicKey, Ed25519PublicKey};
 
 use crate::{
-    AppError, Device, DeviceId, DeviceKeyService, HistoricalKey, InboundAuthorization,
-    InboundDevice, NewDevice, User,
-    schema::{device, user as user_table},
+    AppError, Device, DeviceId, DeviceKeyProof, DeviceKeyService, HistoricalKey,
+    InboundAuthorization, InboundDevice, InboundRevocation, KeyHistoryQuery, NewDevice,
+    UploadStatus, User, UserId, schema::device,
 };
 
-// These structs mirror the ones in end2-cometbft/src/main.rs and must stay in sync.
-// Binary fields are unpadded base64 strings.
-#[derive(Serialize, Deserialize)]
-struct KeyPayload {
-    user_hash: String,
-    // String to simplify passing to cometbft
+mod batcher;
+mod in_flight;
+mod light_client;
+mod read_cache;
+mod rpc_pool;
+mod sequences;
+mod subscription;
+mod tx_watcher;
+
+use batcher::Batcher;
+use in_flight::InFlightUploads;
+pub use light_client::{LightClient, TrustOptions};
+use read_cache::{DeviceMap, ReadCache};
+use rpc_pool::RpcPool;
+use sequences::Sequences;
+use tx_watcher::TxWatcher;
+
+// The proof op type end2-cometbft attaches to query responses.
+const PROOF_OP_JMT: &str = "jmt:sha256";
+
+// The JSON encoding used before the binary one, kept so key history can still
+// read the txs in old blocks. Binary fields are unpadded base64 strings.
+#[derive(Deserialize)]
+struct JsonKeyPayload {
     device_id: String,
     x25519: String,
     ed25519: String,
-    // base64 device self-signature (ed25519 over x25519||ed25519 bytes)
     signature: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
     authorization: Option<InboundAuthorization>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct KeyUploadTx {
-    payload: KeyPayload,
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonKeyTx {
+    AddDevice(JsonKeyPayload),
+    RotateDevice(JsonKeyPayload),
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct JsonKeyTxBody {
+    tx: JsonKeyTx,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonTx {
+    Signed { body: JsonKeyTxBody },
+    // unversioned upsert from before the envelope
+    Legacy { payload: JsonKeyPayload },
+}
+
+// Keys uploaded by an add or rotate tx, whichever encoding it was sent in.
+struct UploadedKeys {
+    x25519: Vec<u8>,
+    ed25519: Vec<u8>,
+    signature: Vec<u8>,
+    authorization: Option<InboundAuthorization>,
+}
+
+impl UploadedKeys {
+    // The uploads `tx` made for `device_id`, in order: none for txs that don't
+    // upload keys, several for a batch that touched the device more than once.
+    fn from_tx(bytes: &[u8], device_id: &str) -> Result<Vec<Self>, AppError> {
+        let Some(wire) = WireTx::decode(bytes).map_err(|e| AppError::ValueError(e.into()))? else {
+            return Ok(Self::from_json_tx(bytes, device_id)?.into_iter().collect());
+        };
+
+        let decode_err = |e: std::io::Error| AppError::ValueError(e.to_string());
+        let txs = match wire {
+            WireTx::Key { body, .. } => {
+                vec![
+                    borsh::from_slice::<KeyTxBody>(&body)
+                        .map_err(decode_err)?
+                        .tx,
+                ]
+            }
+            WireTx::KeyBatch { body, .. } => borsh::from_slice::<KeyBatchBody>(&body)
+                .map_err(decode_err)?
+                .entries
+                .into_iter()
+                .map(|e| e.tx)
+                .collect(),
+            WireTx::Governance { .. } => Vec::new(),
+        };
+        txs.into_iter()
+            .filter_map(|tx| match tx {
+                KeyTx::AddDevice(p) | KeyTx::RotateDevice(p) if p.device_id == device_id => {
+                    Some(Self::from_payload(p))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn from_payload(p: KeyPayload) -> Result<Self, AppError> {
+        let authorization = p
+            .authorization
+            .map(|a| {
+                Ok::<_, AppError>(InboundAuthorization {
+                    authorizing_device_id: DeviceId::try_from(a.authorizing_device_id.as_str())?,
+                    signature: BASE64_STANDARD_NO_PAD.encode(a.signature),
+                })
+            })
+            .transpose()?;
+        Ok(Self {
+            x25519: p.x25519.to_vec(),
+            ed25519: p.ed25519.to_vec(),
+            signature: p.signature.to_vec(),
+            authorization,
+        })
+    }
+
+    fn into_historical(self, device_id: DeviceId, tx: &DeviceTx) -> HistoricalKey {
+        HistoricalKey {
+            device_id,
+            chain_height: tx.height,
+            x25519: self.x25519,
+            ed25519: self.ed25519,
+            signature: self.signature,
+            authorization: self.authorization,
+            tx_hash: Some(tx.hash.clone()),
+            block_time: None,
+        }
+    }
+
+    fn from_json_tx(bytes: &[u8], device_id: &str) -> Result<Option<Self>, AppError> {
+        let payload = match serde_json::from_slice::<JsonTx>(bytes)
+            .map_err(|e| AppError::ValueError(e.to_string()))?
+        {
+            JsonTx::Signed {
+                body:
+                    JsonKeyTxBody {
+                        tx: JsonKeyTx::AddDevice(p) | JsonKeyTx::RotateDevice(p),
+                    },
+            }
+            | JsonTx::Legacy { payload: p } => p,
+            JsonTx::Signed { .. } => return Ok(None),
+        };
+        if payload.device_id != device_id {
+            return Ok(None);
+        }
+
+        let decode = |s: &str| {
+            BASE64_STANDARD_NO_PAD
+                .decode(s)
+                .map_err(|e| AppError::InvalidB64(e.to_string()))
+        };
+        Ok(Some(Self {
+            x25519: decode(&payload.x25519)?,
+            ed25519: decode(&payload.ed25519)?,
+            signature: decode(&payload.signature)?,
+            authorization: payload.authorization,
+        }))
+    }
+}
+
+// Mirrors keylog::LogEntry in end2-cometbft. Binary fields are unpadded base64.
+#[derive(Deserialize)]
+struct KeyLogEntry {
+    height: u64,
+    // missing from entries written before sequences were
+    sequence: Option<u64>,
+    #[serde(rename = "type")]
+    kind: String,
+    device_id: String,
+    x25519: String,
+    ed25519: String,
     signature: String,
+    authorization: Option<InboundAuthorization>,
 }
 
+// Serialized back, it is the head's value in the tree.
+#[derive(Serialize, Deserialize)]
+struct KeyLogHead {
+    count: u64,
+    // hex
+    hash: String,
+}
+
+// Response of the `history` query.
 #[derive(Deserialize)]
-struct AbciDeviceKeys {
-    x25519: [u8; 32],
-    ed25519: [u8; 32],
+struct KeyLog {
+    head: KeyLogHead,
+    // unpadded base64 of each stored entry, oldest first
+    entries: Vec<String>,
+}
+
+// Checks that `op` proves `value` (None: absence) under its key against
+// `app_hash`, and returns that key.
+fn verify_proof_op(
+    op: &AbciProofOp,
+    value: Option<&[u8]>,
+    app_hash: [u8; 32],
+) -> Result<Vec<u8>, AppError> {
+    if op.kind != PROOF_OP_JMT {
+        return Err(AppError::VerificationFailed(format!(
+            "unknown proof type {}",
+            op.kind
+        )));
+    }
+    let key = BASE64_STANDARD
+        .decode(&op.key)
+        .map_err(|e| AppError::InvalidB64(e.to_string()))?;
+    let data = BASE64_STANDARD
+        .decode(&op.data)
+        .map_err(|e| AppError::InvalidB64(e.to_string()))?;
+    let proof: SparseMerkleProof<Sha256> = borsh::from_slice(&data)
+        .map_err(|e| AppError::VerificationFailed(format!("malformed proof: {e}")))?;
+    proof
+        .verify(RootHash(app_hash), KeyHash::with::<Sha256>(&key), value)
+        .map_err(|e| AppError::VerificationFailed(format!("proof does not verify: {e}")))?;
+    Ok(key)
+}
+
+// Checks that the proof a failed lookup came with shows `key` absent.
+fn verify_absence(ops: &[AbciProofOp], key: &[u8], app_hash: [u8; 32]) -> Result<(), AppError> {
+    let [op] = ops else {
+        return Err(AppError::VerificationFailed(
+            "expected one proof for a missing key".into(),
+        ));
+    };
+    if verify_proof_op(op, None, app_hash)? != key {
+        return Err(AppError::VerificationFailed(
+            "proof is for another key".into(),
+        ));
+    }
+    Ok(())
+}
+
+// Checks that `ops` prove each of the user's `devices`, in store key order like
+// the map, and then the user's live device count, so none were left out.
+fn verify_device_list(
+    user_hash_hex: &str,
+    devices: &DeviceMap,
+    ops: &[AbciProofOp],
+    app_hash: [u8; 32],
+) -> Result<(), AppError> {
+    let Some((count_op, device_ops)) = ops.split_last() else {
+        return Err(AppError::VerificationFailed(
+            "expected proofs for the devices".into(),
+        ));
+    };
+    if device_ops.len() != devices.len() {
+        return Err(AppError::VerificationFailed(
+            "expected one proof per device".into(),
+        ));
+    }
+    for (op, (id, keys)) in device_ops.iter().zip(devices) {
+        let value = serde_json::to_vec(keys).map_err(|e| AppError::ValueError(e.to_string()))?;
+        let key = verify_proof_op(op, Some(&value), app_hash)?;
+        if key != format!("{user_hash_hex}/{id}").as_bytes() {
+            return Err(AppError::VerificationFailed(
+                "proof is for another device".into(),
+            ));
+        }
+    }
+    let count = (devices.len() as u64).to_be_bytes();
+    if verify_proof_op(count_op, Some(&count), app_hash)? != format!("u/{user_hash_hex}").as_bytes()
+    {
+        return Err(AppError::VerificationFailed(
+            "proof is for another user's device count".into(),
+        ));
+    }
+    Ok(())
+}
+
+impl KeyLog {
+    // Checks that `ops` prove this head as the user's committed one, so the
+    // entries chaining up to it are the whole log.
+    fn verify_head(
+        &self,
+        user_hash_hex: &str,
+        ops: &[AbciProofOp],
+        app_hash: [u8; 32],
+    ) -> Result<(), AppError> {
+        let [op] = ops else {
+            return Err(AppError::VerificationFailed(
+                "expected one proof for the key log head".into(),
+            ));
+        };
+        let head =
+            serde_json::to_vec(&self.head).map_err(|e| AppError::ValueError(e.to_string()))?;
+        if verify_proof_op(op, Some(&head), app_hash)? != format!("h/{user_hash_hex}").as_bytes() {
+            return Err(AppError::VerificationFailed(
+                "proof is for another key log".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Decodes the entries after checking they hash-chain up to the head, so
+    // none were dropped or altered: head = sha256(previous || entry bytes),
+    // starting from 32 zero bytes. Only `verify_head` ties the head itself to
+    // the chain.
+    fn chained_entries(&self) -> Result<Vec<KeyLogEntry>, AppError> {
+        let mut hash = [0u8; 32];
+        let mut entries = Vec::with_capacity(self.entries.len());
+        for entry in &self.entries {
+            let bytes = BASE64_STANDARD_NO_PAD
+                .decode(entry)
+                .map_err(|e| AppError::InvalidB64(e.to_string()))?;
+            hash = Sha256::new()
+                .chain_update(hash)
+                .chain_update(&bytes)
+                .finalize()
+                .into();
+            entries.push(
+                serde_json::from_slice(&bytes).map_err(|e| AppError::ValueError(e.to_string()))?,
+            );
+        }
+        if entries.len() as u64 != self.head.count || hex::encode(hash) != self.head.hash {
+            return Err(AppError::VerificationFailed(
+                "key log entries do not chain up to its head".into(),
+            ));
+        }
+        Ok(entries)
+    }
+}
+
+// Response of the `stats` query.
+#[derive(Deserialize)]
+struct ChainStats {
+    // users with at least one live device
+    users: usize,
 }
 
 #[derive(Serialize)]
@@ -68,6 +386,42 @@ struct BroadcastSyncResult {
     hash: String,
 }
 
+// How long the status of a finished upload stays around for clients to read.
+const UPLOAD_STATUS_TTL: Duration = Duration::from_mins(10);
+
+// Tries, 250ms apart, to get a read from a node that has caught up with the
+// txs this service saw commit.
+const FRESH_READ_ATTEMPTS: u32 = 8;
+
+// Header lookups a history page keeps in flight at once.
+const BLOCK_TIME_CONCURRENCY: usize = 8;
+
+struct TrackedUpload {
+    user_id: UserId,
+    status: UploadStatus,
+    updated: Instant,
+}
+
+// A tx as committed, from the `tx` RPC or a tx event.
+struct CommittedTx {
+    height: u64,
+    tx_result: serde_json::Value,
+}
+
+impl CommittedTx {
+    // Errs if finalize_block rejected the tx.
+    fn applied(self) -> Result<Self, AppError> {
+        let code = self.tx_result["code"].as_u64().unwrap_or(1);
+        if code != 0 {
+            let log = self.tx_result["log"].as_str().unwrap_or("unknown error");
+            return Err(AppError::ValueError(format!(
+                "finalize_block rejected: {log}"
+            )));
+        }
+        Ok(self)
+    }
+}
+
 #[derive(Serialize)]
 struct TxQueryParams {
     hash: String,
@@ -84,6 +438,37 @@ struct AbciQueryResponse {
     code: u32,
     log: String,
     value: Option<String>,
+    #[serde(default)]
+    height: String, // int64 encoded as a string
+    proof_ops: Option<AbciProofOps>,
+}
+
+impl AbciQueryResponse {
+    // The decoded value, or the chain's error as a UserError.
+    fn into_value(self) -> Result<Vec<u8>, AppError> {
+        if self.code != 0 {
+            return Err(AppError::UserError(self.log));
+        }
+        let value = self
+            .value
+            .ok_or_else(|| AppError::ValueError("empty response value".into()))?;
+        BASE64_STANDARD
+            .decode(&value)
+            .map_err(|e| AppError::InvalidB64(e.to_string()))
+    }
+}
+
+#[derive(Deserialize)]
+struct AbciProofOps {
+    ops: Vec<AbciProofOp>,
+}
+
+#[derive(Deserialize)]
+struct AbciProofOp {
+    #[serde(rename = "type")]
+    kind: String,
+    key: String,  // base64
+    data: String, // base64
 }
 
 #[derive(Serialize)]
@@ -95,6 +480,8 @@ struct BroadcastParams {
 struct AbciQueryParams {
     path: String,
     data: String,
+    // CometBFT takes int64 params as strings; "0" means the latest height
+    height: String,
     prove: bool,
 }
 
@@ -110,20 +497,98 @@ struct TxSearchParams {
 #[derive(Deserialize)]
 struct TxSearchResult {
     txs: Vec<TxResultInfo>,
+    total_count: String,
 }
 
 #[derive(Deserialize)]
 struct TxResultInfo {
+    hash: String,   // upper-case hex
     height: String, // CometBFT returns block height as a string
+    index: u32,     // position in the block
     tx: String,     // base64-encoded raw tx bytes
+    tx_result: TxResultEvents,
+}
+
+#[derive(Deserialize)]
+struct TxResultEvents {
+    #[serde(default)]
+    events: Vec<AbciEvent>,
+}
+
+#[derive(Deserialize)]
+struct AbciEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    attributes: Vec<AbciEventAttribute>,
+}
+
+#[derive(Deserialize)]
+struct AbciEventAttribute {
+    key: String,
+    #[serde(default)]
+    value: String,
+}
+
+impl AbciEvent {
+    fn attribute(&self, key: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|a| a.key == key)
+            .map(|a| a.value.as_str())
+    }
+}
+
+// A tx from the tx index that uploaded keys for the device searched for.
+struct DeviceTx {
+    height: u64,
+    hash: String,
+    tx: Vec<u8>,
+    // Sequence numbers of the device's uploads in it, from key events that
+    // carry them.
+    sequences: Vec<u64>,
+}
+
+#[derive(Serialize)]
+struct HeightParams {
+    height: String,
+}
+
+#[derive(Deserialize)]
+struct HeaderResult {
+    header: BlockHeader,
+}
+
+#[derive(Deserialize)]
+struct BlockHeader {
+    time: String, // RFC 3339
 }
 
 #[derive(Clone)]
 pub struct CometBftDeviceKeyService {
-    http: Client,
-    rpc_url: String,
+    rpc: Arc<RpcPool>,
     signing_key: Arc<SigningKey>,
+    // Sequences of the key txs signed but not yet committed.
+    sequences: Arc<Sequences>,
+    // Highest block a tx this service sent committed in. Reads that decide
+    // what to sign next must see at least that state, whichever node they
+    // land on.
+    committed_height: Arc<AtomicU64>,
+    // Devices with a key upload signed but not yet committed.
+    in_flight: Arc<InFlightUploads>,
     pool: Pool<ConnectionManager<PgConnection>>,
+    // Verified mode: device lookups are proven against headers this checks.
+    light_client: Option<Arc<LightClient>>,
+    // Coalesces key uploads into batch txs when set.
+    batcher: Option<Arc<Batcher>>,
+    // Resolves commit waits from tx events instead of polling when set.
+    tx_watcher: Option<Arc<TxWatcher>>,
+    // Serves unverified latest-height device lookups when set.
+    read_cache: Option<Arc<ReadCache>>,
+    // set_device_keys returns once the upload passed check_tx, and the
+    // upload's progress is kept in `uploads`.
+    pending_uploads: bool,
+    uploads: Arc<Mutex<HashMap<DeviceId, TrackedUpload>>>,
 }
 
 impl CometBftDeviceKeyService {
@@ -134,13 +599,92 @@ impl CometBftDeviceKeyService {
         pool: Pool<ConnectionManager<PgConnection>>,
     ) -> Self {
         Self {
-            http: Client::new(),
-            rpc_url,
+            rpc: Arc::new(RpcPool::new(vec![rpc_url])),
             signing_key,
+            sequences: Arc::default(),
+            committed_height: Arc::default(),
+            in_flight: Arc::default(),
             pool,
+            light_client: None,
+            batcher: None,
+            tx_watcher: None,
+            read_cache: None,
+            pending_uploads: false,
+            uploads: Arc::default(),
         }
     }
 
+    /// Spreads RPC calls over `rpc_urls`, in order of preference, in place of
+    /// the single node given to `new`. Nodes are health checked through their
+    /// `status`; reads and broadcasts go to a node that is up, caught up and
+    /// not behind the others, and move on to the next node if it can't be
+    /// reached. Must be called from within a Tokio runtime.
+    ///
+    /// # Panics
+    /// If `rpc_urls` is empty.
+    #[must_use]
+    pub fn with_rpc_endpoints(mut self, rpc_urls: Vec<String>) -> Self {
+        let rpc = Arc::new(RpcPool::new(rpc_urls));
+        rpc.spawn_health_checks();
+        self.rpc = rpc;
+        self
+    }
+
+    /// Turns on verified mode: device lookups come with proofs, which are
+    /// checked against app hashes from headers `light_client` verified, so a
+    /// lying RPC node yields `AppError::VerificationFailed` instead of keys.
+    /// The light client fetches headers from the same nodes as every other
+    /// call.
+    /// A device list is proven complete through the user's live device count,
+    /// a key history through the head of the user's key log, and a missing
+    /// device, an empty list or a missing log through the absence of its key.
+    #[must_use]
+    pub fn with_light_client(mut self, light_client: LightClient) -> Self {
+        self.light_client = Some(Arc::new(light_client));
+        self
+    }
+
+    /// Learns about commits from one WebSocket subscription to the node's tx
+    /// events at `ws_url` (e.g. `ws://node0:26657/websocket`) rather than by
+    /// polling for each tx, falling back to polling while the socket is down.
+    /// Must be called from within a Tokio runtime.
+    #[must_use]
+    pub fn with_tx_events(mut self, ws_url: String) -> Self {
+        self.tx_watcher = Some(Arc::new(TxWatcher::spawn(ws_url)));
+        self
+    }
+
+    /// Answers `get_device` and `get_all_devices` from users' device lists
+    /// as last read, dropping a user's list once a block at `ws_url` changes
+    /// their keys and refetching any list older than `max_age`. Lookups by
+    /// height and verified lookups always go to the chain. Must be called from
+    /// within a Tokio runtime.
+    #[must_use]
+    pub fn with_read_cache(mut self, ws_url: String, max_age: Duration) -> Self {
+        self.read_cache = Some(Arc::new(ReadCache::spawn(ws_url, max_age)));
+        self
+    }
+
+    /// Makes `set_device_keys` return as soon as the chain accepted the upload
+    /// into its mempool, with the keys it will hold once committed. The DB copy
+    /// is updated on commit, and `get_upload_status` follows the upload.
+    #[must_use]
+    pub const fn with_pending_uploads(mut self) -> Self {
+        self.pending_uploads = true;
+        self
+    }
+
+    /// Sends key uploads and revocations in batch txs: concurrent ones that
+    /// arrive within `window` of each other, up to `max_entries`, share one
+    /// tx. Call it after the other `with_` methods, whose settings the
+    /// batcher copies. Must be called from within a Tokio runtime.
+    #[must_use]
+    pub fn with_batching(mut self, window: Duration, max_entries: usize) -> Self {
+        let batcher = Batcher::spawn(self.clone(), window, max_entries);
+        self.batcher = Some(Arc::new(batcher));
+        self
+    }
+
     fn get_conn(
         &self,
     ) -> Result<r2d2::PooledConnection<ConnectionManager<PgConnection>>, AppError> {
@@ -149,36 +693,170 @@ impl CometBftDeviceKeyService {
             .map_err(|e| AppError::PoolError(e.to_string()))
     }
 
+    fn to_devices(user: &User, map: DeviceMap) -> Vec<Device> {
+        map.into_iter()
+            .filter_map(|(id_str, keys)| {
+                DeviceId::try_from(id_str.as_str()).ok().map(|id| Device {
+                    id,
+                    user_id: user.id,
+                    x25519: Some(keys.x25519.to_vec()),
+                    ed25519: Some(keys.ed25519.to_vec()),
+                })
+            })
+            .collect()
+    }
+
     fn user_hash(user: &User) -> [u8; 32] {
         Sha256::digest(format!("{}", user.id)).into()
     }
 
-    // Submits tx and returns immediately after check_tx with the tx hash.
-    async fn broadcast_tx_sync(&self, tx: &KeyUploadTx) -> Result<String, AppError> {
-        let tx_bytes = serde_json::to_vec(tx).map_err(|e| AppError::ValueError(e.to_string()))?;
+    // The number of key txs the chain has committed for the user, which the
+    // next one must carry.
+    async fn sequence(&self, user_hash_hex: &str) -> Result<u64, AppError> {
+        let value = self
+            .fresh_query("sequence", user_hash_hex.as_bytes())
+            .await?;
+        std::str::from_utf8(&value)
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .ok_or_else(|| AppError::ValueError("invalid sequence from chain".into()))
+    }
+
+    // Signs `tx` with the user's next sequence number and broadcasts it,
+    // returning its hash and sequence once it passed check_tx. The user's
+    // sequence lock is held throughout, so their txs reach the mempool in
+    // sequence order.
+    async fn broadcast_key_tx(
+        &self,
+        user_hash_hex: &str,
+        tx: KeyTx,
+    ) -> Result<(String, u64), AppError> {
+        let mut user = self.sequences.lock(user_hash_hex).await;
+        let body = KeyTxBody {
+            version: TX_VERSION,
+            sequence: user.next(self.sequence(user_hash_hex).await?),
+            tx,
+        };
+        let result = self.broadcast_tx_sync(&body.sign(&self.signing_key)).await;
+        match &result {
+            Ok(_) => user.accepted(body.sequence + 1),
+            Err(_) => user.rejected(),
+        }
+        drop(user);
+        result.map(|hash| (hash, body.sequence))
+    }
+
+    // Waits for a tx from `broadcast_key_tx` to commit and settles its
+    // sequence either way.
+    async fn wait_for_key_tx(
+        &self,
+        user_hash_hex: &str,
+        tx_hash: &str,
+        sequence: u64,
+    ) -> Result<u64, AppError> {
+        let result = self.wait_for_tx(tx_hash).await;
+        let mut user = self.sequences.lock(user_hash_hex).await;
+        match &result {
+            Ok(_) => user.committed(sequence + 1),
+            Err(_) => user.rejected(),
+        }
+        drop(user);
+        result.map(|tx| tx.height)
+    }
+
+    // Submits a key tx, batched if batching is on, and returns once it passed
+    // check_tx, with its tx hash and a future for the height it commits at.
+    async fn submit_key_tx(
+        &self,
+        user_hash_hex: String,
+        tx: KeyTx,
+    ) -> Result<(String, BoxFuture<'static, Result<u64, AppError>>), AppError> {
+        if let Some(batcher) = &self.batcher {
+            let accepted = batcher.submit(user_hash_hex, tx).await?;
+            let committed = async move {
+                accepted
+                    .committed
+                    .await
+                    .map_err(|_| AppError::ValueError("key batcher dropped the upload".into()))?
+            };
+            return Ok((accepted.tx_hash, Box::pin(committed)));
+        }
+
+        let (hash, sequence) = self.broadcast_key_tx(&user_hash_hex, tx).await?;
+        let service = self.clone();
+        let tx_hash = hash.clone();
+        let committed = async move {
+            service
+                .wait_for_key_tx(&user_hash_hex, &tx_hash, sequence)
+                .await
+        };
+        Ok((hash, Box::pin(committed)))
+    }
+
+    // Copies uploaded keys into the device's DB row.
+    async fn store_keys(
+        &self,
+        user_id: UserId,
+        device_id: DeviceId,
+        x25519: Vec<u8>,
+        ed25519: Vec<u8>,
+    ) -> Result<Device, AppError> {
+        let mut conn = self.get_conn()?;
+        let device = tokio::task::spawn_blocking(move || {
+            diesel::update(device::table)
+                .filter(device::id.eq(device_id).and(device::user_id.eq(user_id)))
+                .set((device::x25519.eq(x25519), device::ed25519.eq(ed25519)))
+                .returning(Device::as_returning())
+                .get_result(&mut conn)
+        })
+        .await??;
+        Ok(device)
+    }
+
+    async fn track_upload(&self, user_id: UserId, device_id: DeviceId, status: UploadStatus) {
+        let mut uploads = self.uploads.lock().await;
+        uploads.retain(|_, u| {
+            matches!(u.status, UploadStatus::Pending { .. })
+                || u.updated.elapsed() < UPLOAD_STATUS_TTL
+        });
+        uploads.insert(
+            device_id,
+            TrackedUpload {
+                user_id,
+                status,
+                updated: Instant::now(),
+            },
+        );
+    }
+
+    fn decode_sig64(signature: &str) -> Result<[u8; 64], AppError> {
+        BASE64_STANDARD_NO_PAD
+            .decode(signature)
+            .map_err(|e| AppError::InvalidB64(e.to_string()))?
+            .try_into()
+            .map_err(|_| AppError::InvalidSignature)
+    }
 
+    // Submits tx and returns immediately after check_tx with the tx hash.
+    async fn broadcast_tx_sync(&self, tx_bytes: &[u8]) -> Result<String, AppError> {
         let req = JsonRpcRequest {
             jsonrpc: "2.0",
             id: 1,
             method: "broadcast_tx_sync",
             params: BroadcastParams {
-                tx: BASE64_STANDARD.encode(&tx_bytes),
+                tx: BASE64_STANDARD.encode(tx_bytes),
             },
         };
 
-        let res: JsonRpcResponse<BroadcastSyncResult> = self
-            .http
-            .post(&self.rpc_url)
-            .json(&req)
-            .send()
-            .await
-            .map_err(|e| AppError::ValueError(e.to_string()))?
-            .json()
-            .await
-            .map_err(|e| AppError::ValueError(e.to_string()))?;
+        let res: JsonRpcResponse<BroadcastSyncResult> = self.rpc.post(&req).await?;
 
         let result = match res {
             JsonRpcResponse::Ok { result } => result,
+            // A broadcast retried on another node after the first one took it
+            // but went away before answering; the tx is already gossiped.
+            JsonRpcResponse::Err { error } if error.to_string().contains("already exists") => {
+                return Ok(hex::encode_upper(Sha256::digest(tx_bytes)));
+            }
             JsonRpcResponse::Err { error } => {
                 return Err(AppError::ValueError(format!("rpc error: {error}")));
             }
@@ -194,64 +872,212 @@ impl CometBftDeviceKeyService {
         Ok(result.hash)
     }
 
-    // Polls until the tx with the given hash is committed in a block.
-    // CometBFT JSON-RPC expects `hash` as base64-encoded raw bytes.
-    async fn wait_for_tx(&self, hash: &str) -> Result<(), AppError> {
-        let hash_bytes = hex::decode(hash).map_err(|e| AppError::ValueError(e.to_string()))?;
-        let hash_b64 = BASE64_STANDARD.encode(&hash_bytes);
-
-        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(30);
+    // Waits until the tx with the given hash is committed in a block, for up
+    // to 30 seconds, and returns it if the block applied it. Uses the tx event
+    // subscription when it is up and polls the `tx` RPC otherwise.
+    async fn wait_for_tx(&self, hash: &str) -> Result<CommittedTx, AppError> {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(30);
+        let timed_out =
+            || AppError::ValueError(format!("timed out waiting for tx {hash} to commit"));
+
+        if let Some(committed) = self.tx_watcher.as_ref().and_then(|w| w.register(hash)) {
+            // it may have committed before it was registered
+            if let Some(tx) = self.poll_tx(hash).await? {
+                return self.seen_commit(tx);
+            }
+            match tokio::time::timeout_at(deadline, committed).await {
+                Ok(Ok(tx)) => return self.seen_commit(tx),
+                // the socket dropped; poll for the rest of the time
+                Ok(Err(_)) => {}
+                Err(_) => return Err(timed_out()),
+            }
+        }
 
         loop {
             if tokio::time::Instant::now() >= deadline {
-                return Err(AppError::ValueError(format!(
-                    "timed out waiting for tx {hash} to commit"
-                )));
+                return Err(timed_out());
             }
+            if let Some(tx) = self.poll_tx(hash).await? {
+                return self.seen_commit(tx);
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+    }
 
-            let req = JsonRpcRequest {
-                jsonrpc: "2.0",
-                id: 1,
-                method: "tx",
-                params: TxQueryParams {
-                    hash: hash_b64.clone(),
-                    prove: false,
-                },
-            };
+    // Notes the height `tx` committed at for `fresh_query`, then checks that
+    // the block applied it.
+    fn seen_commit(&self, tx: CommittedTx) -> Result<CommittedTx, AppError> {
+        self.committed_height.fetch_max(tx.height, Ordering::AcqRel);
+        tx.applied()
+    }
 
-            let resp: serde_json::Value = self
-                .http
-                .post(&self.rpc_url)
-                .json(&req)
-                .send()
-                .await
-                .map_err(|e| AppError::ValueError(e.to_string()))?
-                .json()
-                .await
-                .map_err(|e| AppError::ValueError(e.to_string()))?;
+    // The tx with the given hash if it is committed yet. CometBFT JSON-RPC
+    // expects `hash` as base64-encoded raw bytes.
+    async fn poll_tx(&self, hash: &str) -> Result<Option<CommittedTx>, AppError> {
+        let hash_bytes = hex::decode(hash).map_err(|e| AppError::ValueError(e.to_string()))?;
+        let req = JsonRpcRequest {
+            jsonrpc: "2.0",
+            id: 1,
+            method: "tx",
+            params: TxQueryParams {
+                hash: BASE64_STANDARD.encode(&hash_bytes),
+                prove: false,
+            },
+        };
 
-            if resp.get("error").is_some() {
-                // Not yet committed — wait one poll interval and retry.
-                tokio::time::sleep(std::time::Duration::from_millis(250)).await;
-                continue;
-            }
+        let resp: serde_json::Value = self.rpc.post(&req).await?;
 
-            let code = resp["result"]["tx_result"]["code"].as_u64().unwrap_or(1);
-            if code != 0 {
-                let log = resp["result"]["tx_result"]["log"]
-                    .as_str()
-                    .unwrap_or("unknown error")
-                    .to_owned();
+        // an error means it isn't committed yet
+        if resp.get("error").is_some() {
+            return Ok(None);
+        }
+        Ok(Some(CommittedTx {
+            height: resp["result"]["height"]
+                .as_str()
+                .and_then(|h| h.parse().ok())
+                .unwrap_or(0),
+            tx_result: resp["result"]["tx_result"].clone(),
+        }))
+    }
+
+    async fn abci_query(&self, path: &str, data: &[u8]) -> Result<Vec<u8>, AppError> {
+        self.abci_query_at(path, data, 0).await
+    }
+
+    // Like `abci_query`, but only from a state that has every tx this service
+    // saw commit. A node behind the one that ran them would hand back a
+    // sequence or device set from before them, so a lagging answer is retried
+    // until some node has caught up.
+    async fn fresh_query(&self, path: &str, data: &[u8]) -> Result<Vec<u8>, AppError> {
+        let min_height = self.committed_height.load(Ordering::Acquire);
+        let mut attempt = 1;
+        let response = loop {
+            let response = self.send_abci_query(path, data, 0, false).await?;
+            let height: u64 = response
+                .height
+                .parse()
+                .map_err(|_| AppError::ValueError("invalid height from chain".into()))?;
+            if height >= min_height {
+                break response;
+            }
+            if attempt == FRESH_READ_ATTEMPTS {
                 return Err(AppError::ValueError(format!(
-                    "finalize_block rejected: {log}"
+                    "chain state at {height} is behind committed height {min_height}"
                 )));
             }
+            attempt += 1;
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        };
+        response.into_value()
+    }
 
-            return Ok(());
+    // The user's devices from the read cache, fetched and cached on a miss,
+    // or None if lookups can't be cached.
+    async fn cached_devices(&self, user_hash_hex: &str) -> Result<Option<DeviceMap>, AppError> {
+        let Some(cache) = self
+            .read_cache
+            .as_ref()
+            .filter(|_| self.light_client.is_none())
+        else {
+            return Ok(None);
+        };
+        if let Some(devices) = cache.get(user_hash_hex) {
+            return Ok(Some(devices));
         }
+
+        let response = self
+            .send_abci_query("devices", user_hash_hex.as_bytes(), 0, false)
+            .await?;
+        let height = response
+            .height
+            .parse()
+            .map_err(|_| AppError::ValueError("invalid height from chain".into()))?;
+        // a user without devices is an error, and just as cacheable
+        let devices: DeviceMap = match (response.code, response.value) {
+            (0, Some(value)) => serde_json::from_slice(&BASE64_STANDARD.decode(&value)?)
+                .map_err(|e| AppError::ValueError(e.to_string()))?,
+            (0, None) => return Err(AppError::ValueError("empty response value".into())),
+            _ => DeviceMap::new(),
+        };
+        cache.insert(user_hash_hex, height, devices.clone());
+        Ok(Some(devices))
     }
 
-    async fn abci_query(&self, path: &str, data: &[u8]) -> Result<Vec<u8>, AppError> {
+    // Like `abci_query`, but against the state as of `height` (0 for latest).
+    async fn abci_query_at(
+        &self,
+        path: &str,
+        data: &[u8],
+        height: u64,
+    ) -> Result<Vec<u8>, AppError> {
+        self.send_abci_query(path, data, height, false)
+            .await?
+            .into_value()
+    }
+
+    // Like `abci_query_at`, but against a state a verified header commits to.
+    // Returns the value, the proof ops and the app hash they must prove
+    // against. A failed lookup must carry a non-inclusion proof of
+    // `absent_key`, the store key it says is missing.
+    async fn verified_query(
+        &self,
+        light_client: &LightClient,
+        path: &str,
+        data: &[u8],
+        absent_key: &[u8],
+        height: u64,
+    ) -> Result<(Vec<u8>, Vec<AbciProofOp>, [u8; 32]), AppError> {
+        // The state after block h is committed to by header h + 1, so the
+        // latest provable state is the one before the latest header.
+        let header_height = if height == 0 {
+            light_client.latest_height(&self.rpc).await?
+        } else {
+            height + 1
+        };
+        let version = header_height
+            .checked_sub(1)
+            .filter(|v| *v > 0)
+            .ok_or_else(|| AppError::UserError("no provable state yet".into()))?;
+        let app_hash: [u8; 32] = light_client
+            .app_hash(&self.rpc, header_height)
+            .await?
+            .try_into()
+            .map_err(|_| AppError::VerificationFailed("app hash is not 32 bytes".into()))?;
+
+        let response = self.send_abci_query(path, data, version, true).await?;
+        if response.height != version.to_string() {
+            return Err(AppError::VerificationFailed(format!(
+                "asked for state at {version}, got {}",
+                response.height
+            )));
+        }
+        let ops = response.proof_ops.map(|p| p.ops).unwrap_or_default();
+        if response.code != 0 {
+            // Only a missing key can fail with a proof attached.
+            if ops.is_empty() {
+                return Err(AppError::VerificationFailed(format!(
+                    "unproven error from the chain: {}",
+                    response.log
+                )));
+            }
+            verify_absence(&ops, absent_key, app_hash)?;
+            return Err(AppError::UserError(response.log));
+        }
+        let value = BASE64_STANDARD
+            .decode(response.value.unwrap_or_default())
+            .map_err(|e| AppError::InvalidB64(e.to_string()))?;
+        Ok((value, ops, app_hash))
+    }
+
+    // Returns the raw query response without checking its code, so a failed
+    // lookup can still hand back its non-inclusion proof when `prove` is set.
+    async fn send_abci_query(
+        &self,
+        path: &str,
+        data: &[u8],
+        height: u64,
+        prove: bool,
+    ) -> Result<AbciQueryResponse, AppError> {
         let req = JsonRpcRequest {
             jsonrpc: "2.0",
             id: 1,
@@ -259,89 +1085,166 @@ impl CometBftDeviceKeyService {
             params: AbciQueryParams {
                 path: path.to_owned(),
                 data: hex::encode(data),
-                prove: false,
+                height: height.to_string(),
+                prove,
             },
         };
 
-        let res: JsonRpcResponse<AbciQueryResult> = self
-            .http
-            .post(&self.rpc_url)
-            .json(&req)
-            .send()
-            .await
-            .map_err(|e| AppError::ValueError(e.to_string()))?
-            .json()
-            .await
-            .map_err(|e| AppError::ValueError(e.to_string()))?;
+        let res: JsonRpcResponse<AbciQueryResult> = self.rpc.post(&req).await?;
 
-        let result = match res {
-            JsonRpcResponse::Ok { result } => result,
+        match res {
+            JsonRpcResponse::Ok { result } => Ok(result.response),
             JsonRpcResponse::Err { error } => {
-                return Err(AppError::ValueError(format!("rpc error: {error}")));
+                Err(AppError::ValueError(format!("rpc error: {error}")))
             }
-        };
+        }
+    }
 
-        if result.response.code != 0 {
-            return Err(AppError::UserError(result.response.log));
+    // Key history rebuilt from the key_add and key_update events in CometBFT's
+    // tx index, for chains or users from before the key log.
+    async fn history_from_tx_index(
+        &self,
+        user_hash_hex: &str,
+        device_id: DeviceId,
+        query: &KeyHistoryQuery,
+    ) -> Result<Vec<HistoricalKey>, AppError> {
+        let device_id_str = device_id.to_string();
+        let mut history = Vec::new();
+        for tx in self
+            .device_txs(user_hash_hex, &device_id_str, query.since_height)
+            .await?
+        {
+            for keys in UploadedKeys::from_tx(&tx.tx, &device_id_str)? {
+                history.push(keys.into_historical(device_id, &tx));
+            }
         }
 
-        let value = result
-            .response
-            .value
-            .ok_or_else(|| AppError::ValueError("empty response value".into()))?;
+        let mut history = query.page(history, |k| k.chain_height);
+        self.add_block_times(&mut history).await?;
+        Ok(history)
+    }
 
-        BASE64_STANDARD
-            .decode(&value)
-            .map_err(|e| AppError::InvalidB64(e.to_string()))
+    // Every tx that added or rotated the device's keys, ordered by height and
+    // position in the block, optionally only those above `since_height`.
+    async fn device_txs(
+        &self,
+        user_hash_hex: &str,
+        device_id: &str,
+        since_height: Option<u64>,
+    ) -> Result<Vec<DeviceTx>, AppError> {
+        // key_add fires on first registration, key_update on each subsequent
+        // change. A batch that did both shows up in both searches.
+        let since = since_height
+            .map(|height| format!(" AND tx.height > {height}"))
+            .unwrap_or_default();
+        let mut txs = BTreeMap::new();
+        for event_type in ["key_add", "key_update"] {
+            let query = format!(
+                "{event_type}.user_hash='{user_hash_hex}' AND {event_type}.device_id='{device_id}'{since}"
+            );
+            for info in self.tx_search(&query).await? {
+                let height = info.height.parse::<u64>().unwrap_or(0);
+                if txs.contains_key(&(height, info.index)) {
+                    continue;
+                }
+                let tx = BASE64_STANDARD
+                    .decode(&info.tx)
+                    .map_err(|e| AppError::InvalidB64(e.to_string()))?;
+                let sequences = info
+                    .tx_result
+                    .events
+                    .iter()
+                    .filter(|e| matches!(e.kind.as_str(), "key_add" | "key_update"))
+                    .filter(|e| e.attribute("device_id") == Some(device_id))
+                    .filter_map(|e| e.attribute("sequence")?.parse().ok())
+                    .collect();
+                txs.insert(
+                    (height, info.index),
+                    DeviceTx {
+                        height,
+                        hash: info.hash,
+                        tx,
+                        sequences,
+                    },
+                );
+            }
+        }
+        Ok(txs.into_values().collect())
+    }
+
+    // All committed txs matching the CometBFT event query, page by page.
+    async fn tx_search(&self, query: &str) -> Result<Vec<TxResultInfo>, AppError> {
+        const PER_PAGE: usize = 100; // the most CometBFT returns at once
+
+        let mut txs = Vec::new();
+        for page in 1.. {
+            let req = JsonRpcRequest {
+                jsonrpc: "2.0",
+                id: 1,
+                method: "tx_search",
+                params: TxSearchParams {
+                    query: query.to_owned(),
+                    prove: false,
+                    page: page.to_string(),
+                    per_page: PER_PAGE.to_string(),
+                    order_by: "asc".to_owned(),
+                },
+            };
+
+            let res: JsonRpcResponse<TxSearchResult> = self.rpc.post(&req).await?;
+            let result = match res {
+                JsonRpcResponse::Ok { result } => result,
+                JsonRpcResponse::Err { error } => {
+                    return Err(AppError::ValueError(format!("rpc error: {error}")));
+                }
+            };
+
+            let total = result.total_count.parse::<usize>().unwrap_or(0);
+            let fetched = result.txs.len();
+            txs.extend(result.txs);
+            // asking past the last page is an error
+            if fetched < PER_PAGE || txs.len() >= total {
+                break;
+            }
+        }
+        Ok(txs)
+    }
+
+    // Fills in `block_time` from the headers of the blocks the keys are in.
+    async fn add_block_times(&self, history: &mut [HistoricalKey]) -> Result<(), AppError> {
+        let mut heights: Vec<u64> = history.iter().map(|k| k.chain_height).collect();
+        heights.dedup();
+        let times: HashMap<u64, OffsetDateTime> = stream::iter(heights)
+            .map(
+                |height| async move { Ok::<_, AppError>((height, self.block_time(height).await?)) },
+            )
+            .buffer_unordered(BLOCK_TIME_CONCURRENCY)
+            .try_collect()
+            .await?;
+        for key in history {
+            key.block_time = times.get(&key.chain_height).copied();
+        }
+        Ok(())
     }
 
-    // Returns all committed txs matching the CometBFT event query, oldest first.
-    // Each entry is (block_height, tx).
-    async fn tx_search(&self, query: &str) -> Result<Vec<(u64, KeyUploadTx)>, AppError> {
+    async fn block_time(&self, height: u64) -> Result<OffsetDateTime, AppError> {
         let req = JsonRpcRequest {
             jsonrpc: "2.0",
             id: 1,
-            method: "tx_search",
-            params: TxSearchParams {
-                query: query.to_owned(),
-                prove: false,
-                page: "1".to_owned(),
-                per_page: "100".to_owned(),
-                order_by: "asc".to_owned(),
+            method: "header",
+            params: HeightParams {
+                height: height.to_string(),
             },
         };
-
-        let res: JsonRpcResponse<TxSearchResult> = self
-            .http
-            .post(&self.rpc_url)
-            .json(&req)
-            .send()
-            .await
-            .map_err(|e| AppError::ValueError(e.to_string()))?
-            .json()
-            .await
-            .map_err(|e| AppError::ValueError(e.to_string()))?;
-
-        let result = match res {
-            JsonRpcResponse::Ok { result } => result,
+        let res: JsonRpcResponse<HeaderResult> = self.rpc.post(&req).await?;
+        let header = match res {
+            JsonRpcResponse::Ok { result } => result.header,
             JsonRpcResponse::Err { error } => {
                 return Err(AppError::ValueError(format!("rpc error: {error}")));
             }
         };
-
-        result
-            .txs
-            .into_iter()
-            .map(|info| {
-                let height = info.height.parse::<u64>().unwrap_or(0);
-                let bytes = BASE64_STANDARD
-                    .decode(&info.tx)
-                    .map_err(|e| AppError::InvalidB64(e.to_string()))?;
-                let tx = serde_json::from_slice::<KeyUploadTx>(&bytes)
-                    .map_err(|e| AppError::ValueError(e.to_string()))?;
-                Ok((height, tx))
-            })
-            .collect()
+        OffsetDateTime::parse(&header.time, &Rfc3339)
+            .map_err(|e| AppError::ValueError(format!("invalid block time: {e}")))
     }
 }
 
@@ -366,13 +1269,67 @@ impl DeviceKeyService for CometBftDeviceKeyService {
 
     #[tracing::instrument(skip(self))]
     async fn get_device(&self, user: &User, device_id: DeviceId) -> Result<Device, AppError> {
+        self.get_device_at_height(user, device_id, 0).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_device_at_height(
+        &self,
+        user: &User,
+        device_id: DeviceId,
+        height: u64,
+    ) -> Result<Device, AppError> {
         let user_hash = Self::user_hash(user);
         let user_hash_hex = hex::encode(user_hash);
         let query = format!("{user_hash_hex}:{device_id}");
+        let store_key = format!("{user_hash_hex}/{device_id}");
+
+        // Misses go to the chain below, for its error on a missing device.
+        if height == 0
+            && let Some(keys) = self
+                .cached_devices(&user_hash_hex)
+                .await?
+                .and_then(|mut devices| devices.remove(&device_id.to_string()))
+        {
+            return Ok(Device {
+                id: device_id,
+                user_id: user.id,
+                x25519: Some(keys.x25519.to_vec()),
+                ed25519: Some(keys.ed25519.to_vec()),
+            });
+        }
 
-        let value = self.abci_query("device", query.as_bytes()).await?;
+        let value = match &self.light_client {
+            Some(light_client) => {
+                let (value, ops, app_hash) = self
+                    .verified_query(
+                        light_client,
+                        "device",
+                        query.as_bytes(),
+                        store_key.as_bytes(),
+                        height,
+                    )
+                    .await?;
+                let [op] = ops.as_slice() else {
+                    return Err(AppError::VerificationFailed(
+                        "expected one proof for a device".into(),
+                    ));
+                };
+                let key = verify_proof_op(op, Some(&value), app_hash)?;
+                if key != store_key.as_bytes() {
+                    return Err(AppError::VerificationFailed(
+                        "proof is for another device".into(),
+                    ));
+                }
+                value
+            }
+            None => {
+                self.abci_query_at("device", query.as_bytes(), height)
+                    .await?
+            }
+        };
 
-        let keys: AbciDeviceKeys =
+        let keys: DeviceKeys =
             serde_json::from_slice(&value).map_err(|e| AppError::ValueError(e.to_string()))?;
 
         Ok(Device {
@@ -388,28 +1345,40 @@ impl DeviceKeyService for CometBftDeviceKeyService {
         let user_hash = Self::user_hash(user);
         let user_hash_hex = hex::encode(user_hash);
 
-        let value = match self.abci_query("devices", user_hash_hex.as_bytes()).await {
-            Ok(v) => v,
-            Err(AppError::UserError(_)) => return Ok(vec![]),
-            Err(e) => return Err(e),
+        if let Some(map) = self.cached_devices(&user_hash_hex).await? {
+            return Ok(Self::to_devices(user, map));
+        }
+
+        let (value, proven) = match &self.light_client {
+            Some(light_client) => match self
+                .verified_query(
+                    light_client,
+                    "devices",
+                    user_hash_hex.as_bytes(),
+                    format!("u/{user_hash_hex}").as_bytes(),
+                    0,
+                )
+                .await
+            {
+                Ok((value, ops, app_hash)) => (value, Some((ops, app_hash))),
+                Err(AppError::UserError(_)) => return Ok(vec![]),
+                Err(e) => return Err(e),
+            },
+            None => match self.abci_query("devices", user_hash_hex.as_bytes()).await {
+                Ok(v) => (v, None),
+                Err(AppError::UserError(_)) => return Ok(vec![]),
+                Err(e) => return Err(e),
+            },
         };
 
-        let map: HashMap<String, AbciDeviceKeys> =
+        let map: DeviceMap =
             serde_json::from_slice(&value).map_err(|e| AppError::ValueError(e.to_string()))?;
 
-        let devices = map
-            .into_iter()
-            .filter_map(|(id_str, keys)| {
-                DeviceId::try_from(id_str.as_str()).ok().map(|id| Device {
-                    id,
-                    user_id: user.id,
-                    x25519: Some(keys.x25519.to_vec()),
-                    ed25519: Some(keys.ed25519.to_vec()),
-                })
-            })
-            .collect();
+        if let Some((ops, app_hash)) = proven {
+            verify_device_list(&user_hash_hex, &map, &ops, app_hash)?;
+        }
 
-        Ok(devices)
+        Ok(Self::to_devices(user, map))
     }
 
     #[tracing::instrument(skip(self))]
@@ -433,103 +1402,631 @@ impl DeviceKeyService for CometBftDeviceKeyService {
             .try_into()
             .map_err(|_| AppError::InvalidKeySize)?;
 
+        let user_hash_hex = hex::encode(Self::user_hash(user));
+        let (in_flight, earlier) = self.in_flight.start(&user_hash_hex, &device_id.to_string());
+        let on_chain = earlier
+            || match self
+                .fresh_query("device", format!("{user_hash_hex}:{device_id}").as_bytes())
+                .await
+            {
+                Ok(_) => true,
+                Err(AppError::UserError(_)) => false,
+                Err(e) => return Err(e),
+            };
+
+        let authorization = keys
+            .authorization
+            .as_ref()
+            .map(|a| {
+                Ok::<_, AppError>(Authorization {
+                    authorizing_device_id: a.authorizing_device_id.to_string(),
+                    signature: Self::decode_sig64(&a.signature)?,
+                })
+            })
+            .transpose()?;
         let payload = KeyPayload {
-            user_hash: hex::encode(Self::user_hash(user)),
+            user_hash: Self::user_hash(user),
             device_id: device_id.to_string(),
-            x25519: BASE64_STANDARD_NO_PAD.encode(x25519_bytes),
-            ed25519: BASE64_STANDARD_NO_PAD.encode(ed25519_bytes),
-            signature: keys.signature.clone(),
-            authorization: keys.authorization.clone(),
+            x25519: *x25519_bytes,
+            ed25519: *ed25519_bytes,
+            signature: Self::decode_sig64(&keys.signature)?,
+            authorization,
         };
 
-        let msg = serde_json::to_vec(&payload).map_err(|e| AppError::ValueError(e.to_string()))?;
-        let sig = self.signing_key.sign(&msg);
-
-        let hash = self
-            .broadcast_tx_sync(&KeyUploadTx {
-                payload,
-                signature: BASE64_STANDARD_NO_PAD.encode(sig.to_bytes()),
-            })
-            .await?;
-        self.wait_for_tx(&hash).await?;
+        let tx = if on_chain {
+            KeyTx::RotateDevice(payload)
+        } else {
+            KeyTx::AddDevice(payload)
+        };
 
-        // Store keys in DB as well
+        let (tx_hash, committed) = self.submit_key_tx(user_hash_hex, tx).await?;
         let x25519_db = x25519.as_bytes().to_vec();
         let ed25519_db = ed25519.as_bytes().to_vec();
-        let user = user.clone();
-        let mut conn = self.get_conn()?;
-
-        let device = tokio::task::spawn_blocking(move || {
-            diesel::update(device::table)
-                .filter(device::id.eq(device_id).and(device::user_id.eq(user.id)))
-                .set((device::x25519.eq(x25519_db), device::ed25519.eq(ed25519_db)))
-                .returning(Device::as_returning())
-                .get_result(&mut conn)
-        })
-        .await??;
+        if !self.pending_uploads {
+            committed.await?;
+            // Store keys in DB as well
+            return self
+                .store_keys(user.id, device_id, x25519_db, ed25519_db)
+                .await;
+        }
 
+        let user_id = user.id;
+        self.track_upload(
+            user_id,
+            device_id,
+            UploadStatus::Pending {
+                tx_hash: tx_hash.clone(),
+            },
+        )
+        .await;
+        let device = Device {
+            id: device_id,
+            user_id,
+            ed25519: Some(ed25519_db.clone()),
+            x25519: Some(x25519_db.clone()),
+        };
+        let service = self.clone();
+        tokio::spawn(async move {
+            let committed = committed.await;
+            drop(in_flight);
+            let stored = match committed {
+                Ok(chain_height) => service
+                    .store_keys(user_id, device_id, x25519_db, ed25519_db)
+                    .await
+                    .map(|_| chain_height),
+                Err(e) => Err(e),
+            };
+            let status = match stored {
+                Ok(chain_height) => UploadStatus::Committed {
+                    tx_hash,
+                    chain_height,
+                },
+                Err(e) => {
+                    tracing::warn!(error = %e, %tx_hash, "pending upload failed");
+                    UploadStatus::Failed {
+                        tx_hash,
+                        reason: e.to_string(),
+                    }
+                }
+            };
+            service.track_upload(user_id, device_id, status).await;
+        });
         Ok(device)
     }
 
+    #[tracing::instrument(skip(self))]
+    async fn get_upload_status(
+        &self,
+        user: &User,
+        device_id: DeviceId,
+    ) -> Result<UploadStatus, AppError> {
+        self.uploads
+            .lock()
+            .await
+            .get(&device_id)
+            .filter(|u| u.user_id == user.id)
+            .map(|u| u.status.clone())
+            .ok_or_else(|| AppError::UserError("no recent upload for this device".into()))
+    }
+
     #[tracing::instrument(skip(self))]
     async fn get_valid_users(&self) -> Result<usize, AppError> {
-        let mut conn = self.get_conn()?;
-        let users = tokio::task::spawn_blocking(move || {
-            user_table::table.select(User::as_select()).load(&mut conn)
-        })
-        .await??;
+        let value = self.abci_query("stats", &[]).await?;
+        let stats: ChainStats =
+            serde_json::from_slice(&value).map_err(|e| AppError::ValueError(e.to_string()))?;
+        Ok(stats.users)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_device_key_history(
+        &self,
+        user: &User,
+        device_id: DeviceId,
+        query: &KeyHistoryQuery,
+    ) -> Result<Vec<HistoricalKey>, AppError> {
+        let user_hash_hex = hex::encode(Self::user_hash(user));
 
-        let mut have_devices = 0_usize;
-        for user in &users {
-            if self
-                .get_all_devices(user)
+        let history = match &self.light_client {
+            Some(light_client) => self
+                .verified_query(
+                    light_client,
+                    "history",
+                    user_hash_hex.as_bytes(),
+                    format!("h/{user_hash_hex}").as_bytes(),
+                    0,
+                )
                 .await
-                .is_ok_and(|d| !d.is_empty())
-            {
-                have_devices += 1;
+                .map(|(value, ops, app_hash)| (value, Some((ops, app_hash)))),
+            None => self
+                .abci_query("history", user_hash_hex.as_bytes())
+                .await
+                .map(|value| (value, None)),
+        };
+        let (value, proven) = match history {
+            Ok(v) => v,
+            // Users whose keys all predate the key log only show up in the tx index.
+            Err(AppError::UserError(_)) => {
+                return self
+                    .history_from_tx_index(&user_hash_hex, device_id, query)
+                    .await;
             }
+            Err(e) => return Err(e),
+        };
+        let log: KeyLog =
+            serde_json::from_slice(&value).map_err(|e| AppError::ValueError(e.to_string()))?;
+
+        if let Some((ops, app_hash)) = proven {
+            log.verify_head(&user_hash_hex, &ops, app_hash)?;
         }
+        let entries = log.chained_entries()?;
+        // Users with keys from before the key log have entries only from the
+        // height their first event after it landed at; the earlier keys are
+        // only in the tx index.
+        let log_start = entries.first().map_or(0, |e| e.height);
+        let pre_log = query
+            .since_height
+            .is_none_or(|since| since.saturating_add(1) < log_start);
 
-        Ok(have_devices)
+        let device_id_str = device_id.to_string();
+        let decode = |s: &str| {
+            BASE64_STANDARD_NO_PAD
+                .decode(s)
+                .map_err(|e| AppError::InvalidB64(e.to_string()))
+        };
+        // with each entry's sequence, to find its tx by below
+        let logged = entries
+            .into_iter()
+            .filter(|e| {
+                e.device_id == device_id_str
+                    && matches!(e.kind.as_str(), "add_device" | "rotate_device")
+            })
+            .map(|e| {
+                let key = HistoricalKey {
+                    device_id,
+                    chain_height: e.height,
+                    x25519: decode(&e.x25519)?,
+                    ed25519: decode(&e.ed25519)?,
+                    signature: decode(&e.signature)?,
+                    authorization: e.authorization,
+                    tx_hash: None,
+                    block_time: None,
+                };
+                Ok((key, e.sequence))
+            })
+            .collect::<Result<Vec<_>, AppError>>()?;
+
+        // The log doesn't record which tx an entry came from; the tx index
+        // does, by sequence number, or by height for txs from before events
+        // carried one.
+        let (page, txs) = if pre_log {
+            let txs = self
+                .device_txs(&user_hash_hex, &device_id_str, query.since_height)
+                .await?;
+            let mut history = Vec::new();
+            for tx in txs.iter().take_while(|tx| tx.height < log_start) {
+                for keys in UploadedKeys::from_tx(&tx.tx, &device_id_str)? {
+                    history.push((keys.into_historical(device_id, tx), None));
+                }
+            }
+            history.extend(logged);
+            (query.page(history, |(k, _)| k.chain_height), txs)
+        } else {
+            let page = query.page(logged, |(k, _)| k.chain_height);
+            let Some((first, _)) = page.first() else {
+                return Ok(Vec::new());
+            };
+            let txs = self
+                .device_txs(
+                    &user_hash_hex,
+                    &device_id_str,
+                    Some(first.chain_height.saturating_sub(1)),
+                )
+                .await?;
+            (page, txs)
+        };
+
+        let mut history: Vec<HistoricalKey> = page
+            .into_iter()
+            .map(|(mut key, sequence)| {
+                if key.tx_hash.is_none() {
+                    key.tx_hash = txs
+                        .iter()
+                        .find(|tx| sequence.is_some_and(|seq| tx.sequences.contains(&seq)))
+                        .or_else(|| txs.iter().find(|tx| tx.height == key.chain_height))
+                        .map(|tx| tx.hash.clone());
+                }
+                key
+            })
+            .collect();
+        self.add_block_times(&mut history).await?;
+        Ok(history)
     }
 
     #[tracing::instrument(skip(self))]
-    async fn get_device_key_history(
+    async fn get_device_proof(
         &self,
         user: &User,
         device_id: DeviceId,
-    ) -> Result<Vec<HistoricalKey>, AppError> {
+    ) -> Result<DeviceKeyProof, AppError> {
         let user_hash_hex = hex::encode(Self::user_hash(user));
-        let device_id_str = device_id.to_string();
+        let query = format!("{user_hash_hex}:{device_id}");
 
-        // key_add fires on first registration, key_update on each subsequent change.
-        // Both are queried ascending by height so history is chronological.
-        let mut history = Vec::new();
-        for event_type in &["key_add", "key_update"] {
-            let query = format!(
-                "{event_type}.user_hash='{user_hash_hex}' AND {event_type}.device_id='{device_id_str}'"
-            );
-            for (chain_height, tx) in self.tx_search(&query).await? {
-                let x25519 = BASE64_STANDARD_NO_PAD
-                    .decode(&tx.payload.x25519)
-                    .map_err(|e| AppError::InvalidB64(e.to_string()))?;
-                let ed25519 = BASE64_STANDARD_NO_PAD
-                    .decode(&tx.payload.ed25519)
-                    .map_err(|e| AppError::InvalidB64(e.to_string()))?;
-                let signature = BASE64_STANDARD_NO_PAD
-                    .decode(&tx.payload.signature)
-                    .map_err(|e| AppError::InvalidB64(e.to_string()))?;
-                history.push(HistoricalKey {
-                    device_id,
-                    chain_height,
-                    x25519,
-                    ed25519,
-                    signature,
-                    authorization: tx.payload.authorization,
-                });
+        let response = self
+            .send_abci_query("device", query.as_bytes(), 0, true)
+            .await?;
+
+        let op = response
+            .proof_ops
+            .and_then(|p| p.ops.into_iter().next())
+            .ok_or_else(|| AppError::UserError(response.log.clone()))?;
+
+        let value = match (response.code, response.value) {
+            (0, Some(v)) => Some(
+                BASE64_STANDARD
+                    .decode(&v)
+                    .map_err(|e| AppError::InvalidB64(e.to_string()))?,
+            ),
+            _ => None,
+        };
+
+        Ok(DeviceKeyProof {
+            device_id,
+            chain_height: response.height.parse::<u64>().unwrap_or(0),
+            key: BASE64_STANDARD
+                .decode(&op.key)
+                .map_err(|e| AppError::InvalidB64(e.to_string()))?,
+            value,
+            proof_type: op.kind,
+            proof: BASE64_STANDARD
+                .decode(&op.data)
+                .map_err(|e| AppError::InvalidB64(e.to_string()))?,
+        })
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn revoke_device(
+        &self,
+        user: &User,
+        device_id: DeviceId,
+        revocation: InboundRevocation,
+    ) -> Result<(), AppError> {
+        let user_hash_hex = hex::encode(Self::user_hash(user));
+        let tx = KeyTx::RevokeDevice(RevokePayload {
+            user_hash: Self::user_hash(user),
+            device_id: device_id.to_string(),
+            revoking_device_id: revocation.revoking_device_id.to_string(),
+            signature: Self::decode_sig64(&revocation.signature)?,
+        });
+
+        let (_, committed) = self.submit_key_tx(user_hash_hex, tx).await?;
+        committed.await?;
+
+        // Drop the keys from the DB copy too so the row can't be mistaken for a
+        // live device.
+        let user_id = user.id;
+        let mut conn = self.get_conn()?;
+        tokio::task::spawn_blocking(move || {
+            diesel::update(device::table)
+                .filter(device::id.eq(device_id).and(device::user_id.eq(user_id)))
+                .set((
+                    device::x25519.eq(None::<Vec<u8>>),
+                    device::ed25519.eq(None::<Vec<u8>>),
+                ))
+                .execute(&mut conn)
+        })
+        .await??;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Json, Router, routing::post};
+    use diesel::r2d2::ConnectionManager;
+    use jmt::{JellyfishMerkleTree, mock::MockTreeStore};
+    use tokio::net::TcpListener;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn payload() -> KeyPayload {
+        KeyPayload {
+            user_hash: [7; 32],
+            device_id: "phone".into(),
+            x25519: [1; 32],
+            ed25519: [2; 32],
+            signature: [3; 64],
+            authorization: None,
+        }
+    }
+
+    fn json_payload(prefix: &str) -> String {
+        let p = payload();
+        format!(
+            r#"{{{prefix}"user_hash":"{}","device_id":"phone","x25519":"{}","ed25519":"{}","signature":"{}"}}"#,
+            hex::encode(p.user_hash),
+            BASE64_STANDARD_NO_PAD.encode(p.x25519),
+            BASE64_STANDARD_NO_PAD.encode(p.ed25519),
+            BASE64_STANDARD_NO_PAD.encode(p.signature),
+        )
+    }
+
+    #[test]
+    fn history_decodes_every_tx_encoding() {
+        let signature = BASE64_STANDARD_NO_PAD.encode([9; 64]);
+        // the baseline upload, from before txs carried a version
+        let baseline = format!(
+            r#"{{"payload":{},"signature":"{signature}"}}"#,
+            json_payload("")
+        );
+        let json = format!(
+            r#"{{"body":{{"version":2,"sequence":0,"tx":{}}},"signer":"{}","signature":"{signature}"}}"#,
+            json_payload(r#""type":"add_device","#),
+            BASE64_STANDARD_NO_PAD.encode([4; 32]),
+        );
+        let binary = KeyTxBody {
+            version: TX_VERSION,
+            sequence: 0,
+            tx: KeyTx::AddDevice(payload()),
+        }
+        .sign(&SigningKey::from_bytes(&[5; 32]));
+
+        for tx in [baseline.into_bytes(), json.into_bytes(), binary] {
+            let uploads = UploadedKeys::from_tx(&tx, "phone").expect("decode");
+            assert_eq!(uploads.len(), 1);
+            assert_eq!(uploads[0].x25519, [1; 32]);
+            assert_eq!(uploads[0].ed25519, [2; 32]);
+            assert_eq!(uploads[0].signature, [3; 64]);
+
+            let other = UploadedKeys::from_tx(&tx, "laptop").expect("decode");
+            assert!(other.is_empty());
+        }
+    }
+
+    // Proof ops for `keys` against a tree holding `entries`, as the chain
+    // would send them, and the tree's root.
+    fn prove(entries: &[(String, Vec<u8>)], keys: &[&str]) -> (Vec<AbciProofOp>, [u8; 32]) {
+        let store = MockTreeStore::default();
+        let tree = JellyfishMerkleTree::<_, Sha256>::new(&store);
+        let (root, batch) = tree
+            .put_value_set(
+                entries
+                    .iter()
+                    .map(|(k, v)| (KeyHash::with::<Sha256>(k), Some(v.clone()))),
+                0,
+            )
+            .expect("put");
+        store.write_tree_update_batch(batch).expect("write");
+        let ops = keys
+            .iter()
+            .map(|key| {
+                let (_, proof) = tree
+                    .get_with_proof(KeyHash::with::<Sha256>(key), 0)
+                    .expect("prove");
+                AbciProofOp {
+                    kind: PROOF_OP_JMT.into(),
+                    key: BASE64_STANDARD.encode(key),
+                    data: BASE64_STANDARD.encode(borsh::to_vec(&proof).expect("borsh")),
+                }
+            })
+            .collect();
+        (ops, root.0)
+    }
+
+    fn devices() -> DeviceMap {
+        ["laptop", "phone"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, id)| {
+                let keys = DeviceKeys {
+                    x25519: [i as u8; 32],
+                    ed25519: [i as u8 + 10; 32],
+                };
+                (id.to_owned(), keys)
+            })
+            .collect()
+    }
+
+    fn tree_entries(devices: &DeviceMap) -> Vec<(String, Vec<u8>)> {
+        let mut entries: Vec<_> = devices
+            .iter()
+            .map(|(id, keys)| (format!("aa/{id}"), serde_json::to_vec(keys).expect("json")))
+            .collect();
+        entries.push(("u/aa".into(), (devices.len() as u64).to_be_bytes().to_vec()));
+        entries
+    }
+
+    #[test]
+    fn absence_proofs_must_be_for_the_queried_key() {
+        let entries = tree_entries(&devices());
+
+        let (ops, app_hash) = prove(&entries, &["aa/tablet"]);
+        verify_absence(&ops, b"aa/tablet", app_hash).expect("tablet is missing");
+        // a node hiding the phone behind a proof that the tablet is missing
+        assert!(matches!(
+            verify_absence(&ops, b"aa/phone", app_hash),
+            Err(AppError::VerificationFailed(_))
+        ));
+
+        let (ops, app_hash) = prove(&entries, &["u/bb"]);
+        verify_absence(&ops, b"u/bb", app_hash).expect("bb has no devices");
+        assert!(matches!(
+            verify_absence(&ops, b"u/aa", app_hash),
+            Err(AppError::VerificationFailed(_))
+        ));
+    }
+
+    #[test]
+    fn device_lists_must_prove_their_count() {
+        let devices = devices();
+        let entries = tree_entries(&devices);
+
+        let (ops, app_hash) = prove(&entries, &["aa/laptop", "aa/phone", "u/aa"]);
+        verify_device_list("aa", &devices, &ops, app_hash).expect("whole list");
+
+        // the phone left out, with every remaining proof valid
+        let mut partial = devices;
+        partial.remove("phone");
+        let (ops, app_hash) = prove(&entries, &["aa/laptop", "u/aa"]);
+        assert!(matches!(
+            verify_device_list("aa", &partial, &ops, app_hash),
+            Err(AppError::VerificationFailed(_))
+        ));
+        // or without the count
+        let (ops, app_hash) = prove(&entries, &["aa/laptop"]);
+        assert!(matches!(
+            verify_device_list("aa", &partial, &ops, app_hash),
+            Err(AppError::VerificationFailed(_))
+        ));
+    }
+
+    // A key log of `entries` as the chain serves it.
+    fn key_log(entries: &[serde_json::Value]) -> KeyLog {
+        let mut hash = [0u8; 32];
+        let mut encoded = Vec::new();
+        for entry in entries {
+            let bytes = serde_json::to_vec(entry).expect("json");
+            hash = Sha256::new()
+                .chain_update(hash)
+                .chain_update(&bytes)
+                .finalize()
+                .into();
+            encoded.push(BASE64_STANDARD_NO_PAD.encode(bytes));
+        }
+        KeyLog {
+            head: KeyLogHead {
+                count: entries.len() as u64,
+                hash: hex::encode(hash),
+            },
+            entries: encoded,
+        }
+    }
+
+    fn log_entry(height: u64, device_id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "height": height,
+            "sequence": height,
+            "type": "add_device",
+            "device_id": device_id,
+            "x25519": "",
+            "ed25519": "",
+            "signature": "",
+        })
+    }
+
+    #[test]
+    fn key_logs_must_end_at_the_proven_head() {
+        let log = key_log(&[log_entry(1, "phone"), log_entry(2, "laptop")]);
+        let head = serde_json::to_vec(&log.head).expect("json");
+        let entries = [("h/aa".to_owned(), head)];
+
+        let (ops, app_hash) = prove(&entries, &["h/aa"]);
+        log.verify_head("aa", &ops, app_hash).expect("whole log");
+        assert_eq!(log.chained_entries().expect("chained").len(), 2);
+
+        // the laptop's entry left out, under a head recomputed to match
+        let truncated = key_log(&[log_entry(1, "phone")]);
+        assert_eq!(truncated.chained_entries().expect("chained").len(), 1);
+        assert!(matches!(
+            truncated.verify_head("aa", &ops, app_hash),
+            Err(AppError::VerificationFailed(_))
+        ));
+        // or another user's log
+        assert!(matches!(
+            log.verify_head("bb", &ops, app_hash),
+            Err(AppError::VerificationFailed(_))
+        ));
+    }
+
+    // A node with no devices that takes every tx into its mempool and never
+    // commits one, and the txs broadcast to it.
+    async fn mempool_node() -> (String, Arc<std::sync::Mutex<Vec<Vec<u8>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let url = format!("http://{}", listener.local_addr().expect("addr"));
+        let txs = Arc::<std::sync::Mutex<Vec<Vec<u8>>>>::default();
+        let mempool = Arc::clone(&txs);
+        let answer = move |Json(req): Json<serde_json::Value>| {
+            let mempool = Arc::clone(&mempool);
+            async move {
+                let result = match req["method"].as_str() {
+                    Some("abci_query") if req["params"]["path"] == "sequence" => {
+                        serde_json::json!({"response": {
+                            "code": 0, "log": "", "height": "1",
+                            "value": BASE64_STANDARD.encode("0"),
+                        }})
+                    }
+                    Some("abci_query") => serde_json::json!({"response": {
+                        "code": 1, "log": "device not found", "height": "1",
+                    }}),
+                    Some("broadcast_tx_sync") => {
+                        let tx = BASE64_STANDARD
+                            .decode(req["params"]["tx"].as_str().expect("tx"))
+                            .expect("base64");
+                        let hash = hex::encode_upper(Sha256::digest(&tx));
+                        mempool.lock().expect("lock mempool").push(tx);
+                        serde_json::json!({"code": 0, "log": "", "hash": hash})
+                    }
+                    _ => {
+                        return Json(serde_json::json!({
+                            "jsonrpc": "2.0", "id": 1,
+                            "error": {"code": -32603, "message": "not found"},
+                        }));
+                    }
+                };
+                Json(serde_json::json!({"jsonrpc": "2.0", "id": 1, "result": result}))
             }
+        };
+        let router = Router::new().route("/", post(answer));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        (url, txs)
+    }
+
+    fn inbound_device(device_id: DeviceId) -> InboundDevice {
+        InboundDevice {
+            device_id: Some(device_id),
+            ed25519: BASE64_STANDARD_NO_PAD
+                .encode(SigningKey::from_bytes(&[6; 32]).verifying_key().as_bytes()),
+            x25519: BASE64_STANDARD_NO_PAD.encode([1; 32]),
+            signature: BASE64_STANDARD_NO_PAD.encode([3; 64]),
+            authorization: None,
         }
+    }
 
-        Ok(history)
+    #[tokio::test]
+    async fn an_upload_behind_an_uncommitted_add_rotates() {
+        let (url, txs) = mempool_node().await;
+        let pool = Pool::builder().build_unchecked(ConnectionManager::new("postgres://unused"));
+        let service =
+            CometBftDeviceKeyService::new(url, Arc::new(SigningKey::from_bytes(&[5; 32])), pool)
+                .with_pending_uploads();
+        let user = User {
+            id: UserId::from(Uuid::nil()),
+            username: "alice".into(),
+            nickname: None,
+            password: None,
+        };
+        let device_id = DeviceId::from(Uuid::nil());
+
+        for _ in 0..2 {
+            service
+                .set_device_keys(&user, device_id, inbound_device(device_id))
+                .await
+                .expect("upload");
+        }
+
+        let sent: Vec<(u64, bool)> = txs
+            .lock()
+            .expect("lock mempool")
+            .iter()
+            .map(|tx| match WireTx::decode(tx) {
+                Ok(Some(WireTx::Key { body, .. })) => {
+                    let body: KeyTxBody = borsh::from_slice(&body).expect("key tx body");
+                    (body.sequence, matches!(body.tx, KeyTx::RotateDevice(_)))
+                }
+                _ => panic!("not a key tx"),
+            })
+            .collect();
+        assert_eq!(sent, [(0, false), (1, true)]);
     }
 }
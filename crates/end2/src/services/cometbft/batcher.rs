@@ -0,0 +1,321 @@
+// Coalesces concurrent key txs into KeyBatch txs, so a burst of uploads and
+// revocations costs one signature, one broadcast and one commit wait instead
+// of one of each per device. Batches are pipelined: the next one is signed and
+// broadcast as soon as the one before it passed check_tx, numbering each
+// user's entries on from the sequences still in flight (see `sequences`), and
+// each batch's commit is awaited on its own task. Broadcasts stay in order, so
+// a node's mempool holds a user's batches in sequence order.
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use futures::future::try_join_all;
+use tokio::sync::{Semaphore, mpsc, oneshot};
+
+use super::{
+    BatchEntryResult, CometBftDeviceKeyService, KeyBatchBody, KeyBatchEntry, KeyTx, TX_VERSION,
+};
+use crate::AppError;
+
+// Txs waiting for a batch; submitters wait for room beyond this.
+const QUEUE_CAPACITY: usize = 4096;
+// Batches broadcast but not yet committed; the next waits for one to finish.
+const MAX_IN_FLIGHT: usize = 8;
+
+struct Submission {
+    user_hash_hex: String,
+    tx: KeyTx,
+    accepted: oneshot::Sender<Result<Accepted, AppError>>,
+}
+
+// A tx whose batch passed check_tx.
+pub(super) struct Accepted {
+    pub(super) tx_hash: String,
+    // the height the batch committed at, or why this entry failed
+    pub(super) committed: oneshot::Receiver<Result<u64, AppError>>,
+}
+
+pub(super) struct Batcher {
+    submissions: mpsc::Sender<Submission>,
+}
+
+impl Batcher {
+    // Starts the task that batches and submits key txs; it stops once the
+    // Batcher is dropped and the queue has drained. Needs a Tokio runtime.
+    pub(super) fn spawn(
+        service: CometBftDeviceKeyService,
+        window: Duration,
+        max_entries: usize,
+    ) -> Self {
+        let (submissions, queue) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(run(service, queue, window, max_entries.max(1)));
+        Self { submissions }
+    }
+
+    // Queues `tx` and waits until the batch carrying it passed check_tx.
+    pub(super) async fn submit(
+        &self,
+        user_hash_hex: String,
+        tx: KeyTx,
+    ) -> Result<Accepted, AppError> {
+        let (accepted, result) = oneshot::channel();
+        self.submissions
+            .send(Submission {
+                user_hash_hex,
+                tx,
+                accepted,
+            })
+            .await
+            .map_err(|_| AppError::ValueError("key batcher has stopped".into()))?;
+        result
+            .await
+            .map_err(|_| AppError::ValueError("key batcher dropped the upload".into()))?
+    }
+}
+
+// A batch opens with the first queued tx and takes whatever else arrives
+// within `window`, up to `max_entries`.
+async fn run(
+    service: CometBftDeviceKeyService,
+    mut queue: mpsc::Receiver<Submission>,
+    window: Duration,
+    max_entries: usize,
+) {
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    while let Some(first) = queue.recv().await {
+        let deadline = tokio::time::Instant::now() + window;
+        let mut batch = vec![first];
+        while batch.len() < max_entries {
+            match tokio::time::timeout_at(deadline, queue.recv()).await {
+                Ok(Some(submission)) => batch.push(submission),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        let permit = Arc::clone(&in_flight)
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        let Some(broadcast) = broadcast(&service, batch).await else {
+            continue;
+        };
+        let service = service.clone();
+        tokio::spawn(async move {
+            broadcast.commit(&service).await;
+            drop(permit);
+        });
+    }
+}
+
+// A batch that passed check_tx, waiting to commit.
+struct Broadcast {
+    tx_hash: String,
+    // whose each entry is, in batch order
+    users: Vec<String>,
+    // the sequence after each user's last entry
+    next: HashMap<String, u64>,
+    committed: Vec<oneshot::Sender<Result<u64, AppError>>>,
+}
+
+// Broadcasts one batch and hands each submitter its acceptance, or the error
+// if the batch was turned away.
+#[tracing::instrument(skip_all, fields(entries = batch.len()))]
+async fn broadcast(
+    service: &CometBftDeviceKeyService,
+    batch: Vec<Submission>,
+) -> Option<Broadcast> {
+    let (txs, waiting): (Vec<_>, Vec<_>) = batch
+        .into_iter()
+        .map(|s| ((s.user_hash_hex, s.tx), s.accepted))
+        .unzip();
+    let users: Vec<String> = txs.iter().map(|(user, _)| user.clone()).collect();
+    let (tx_hash, next) = match broadcast_batch(service, txs).await {
+        Ok(broadcast) => broadcast,
+        Err(e) => {
+            tracing::warn!(error = %e, "key batch rejected");
+            for accepted in waiting {
+                let _ = accepted.send(Err(e.clone()));
+            }
+            return None;
+        }
+    };
+
+    let mut committed = Vec::with_capacity(users.len());
+    for accepted in waiting {
+        let (sender, receiver) = oneshot::channel();
+        committed.push(sender);
+        let _ = accepted.send(Ok(Accepted {
+            tx_hash: tx_hash.clone(),
+            committed: receiver,
+        }));
+    }
+    Some(Broadcast {
+        tx_hash,
+        users,
+        next,
+        committed,
+    })
+}
+
+impl Broadcast {
+    // Resolves every entry once the batch commits.
+    #[tracing::instrument(skip_all, fields(tx_hash = %self.tx_hash))]
+    async fn commit(self, service: &CometBftDeviceKeyService) {
+        let results = entry_results(service, &self.tx_hash, self.users.len())
+            .await
+            .unwrap_or_else(|e| {
+                tracing::warn!(error = %e, "key batch failed");
+                vec![Err(e); self.users.len()]
+            });
+        settle(service, self.next, &self.users, &results).await;
+        for (sender, result) in self.committed.into_iter().zip(results) {
+            let _ = sender.send(result);
+        }
+    }
+}
+
+// Signs `txs` as one batch and broadcasts it, returning its hash once it
+// passed check_tx along with the sequence after each user's last entry.
+async fn broadcast_batch(
+    service: &CometBftDeviceKeyService,
+    txs: Vec<(String, KeyTx)>,
+) -> Result<(String, HashMap<String, u64>), AppError> {
+    let mut users: Vec<String> = txs.iter().map(|(user, _)| user.clone()).collect();
+    users.sort_unstable();
+    users.dedup();
+    // Taken in user order, so two lockers of several users can't deadlock.
+    let mut locked = Vec::with_capacity(users.len());
+    for user in &users {
+        locked.push(service.sequences.lock(user).await);
+    }
+    let committed = try_join_all(users.iter().map(|user| service.sequence(user))).await?;
+    let mut next: HashMap<String, u64> = users
+        .iter()
+        .cloned()
+        .zip(locked.iter_mut().zip(committed))
+        .map(|(user, (lock, committed))| (user, lock.next(committed)))
+        .collect();
+
+    let entries = assign_sequences(txs, &mut next);
+    let body = KeyBatchBody {
+        version: TX_VERSION,
+        entries,
+    };
+    let result = service
+        .broadcast_tx_sync(&body.sign(&service.signing_key))
+        .await;
+    for (user, lock) in users.iter().zip(&mut locked) {
+        match &result {
+            Ok(_) => lock.accepted(next[user]),
+            Err(_) => lock.rejected(),
+        }
+    }
+    drop(locked);
+    result.map(|tx_hash| (tx_hash, next))
+}
+
+// Numbers `txs` from each user's `next` sequence on, leaving `next` past the
+// user's last entry. A user with several uploads in the batch gets consecutive
+// sequence numbers, so if one of them fails the ones after it fail too.
+fn assign_sequences(
+    txs: Vec<(String, KeyTx)>,
+    next: &mut HashMap<String, u64>,
+) -> Vec<KeyBatchEntry> {
+    txs.into_iter()
+        .map(|(user, tx)| {
+            let next = next
+                .get_mut(&user)
+                .expect("sequence fetched for every user");
+            let sequence = *next;
+            *next += 1;
+            KeyBatchEntry { sequence, tx }
+        })
+        .collect()
+}
+
+// Settles each user's in-flight sequence once the batch is done: committed up
+// to `next` if all of the user's entries applied, otherwise back to the
+// chain's count.
+async fn settle(
+    service: &CometBftDeviceKeyService,
+    next: HashMap<String, u64>,
+    users: &[String],
+    results: &[Result<u64, AppError>],
+) {
+    for (user, next) in next {
+        let applied = users
+            .iter()
+            .zip(results)
+            .all(|(u, result)| *u != user || result.is_ok());
+        let mut lock = service.sequences.lock(&user).await;
+        if applied {
+            lock.committed(next);
+        } else {
+            lock.rejected();
+        }
+    }
+}
+
+// Waits for the batch to commit and returns each entry's outcome in order.
+async fn entry_results(
+    service: &CometBftDeviceKeyService,
+    tx_hash: &str,
+    count: usize,
+) -> Result<Vec<Result<u64, AppError>>, AppError> {
+    let committed = service.wait_for_tx(tx_hash).await?;
+    let data = BASE64_STANDARD.decode(committed.tx_result["data"].as_str().unwrap_or_default())?;
+    let results: Vec<BatchEntryResult> =
+        serde_json::from_slice(&data).map_err(|e| AppError::ValueError(e.to_string()))?;
+    if results.len() != count {
+        return Err(AppError::ValueError(format!(
+            "key batch of {count} entries got {} results",
+            results.len()
+        )));
+    }
+
+    Ok(results
+        .into_iter()
+        .map(|r| match r.code {
+            0 => Ok(committed.height),
+            _ => Err(AppError::ValueError(format!(
+                "finalize_block rejected: {}",
+                r.log
+            ))),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::cometbft::RevokePayload;
+
+    fn revoke(user: u8, device_id: &str) -> (String, KeyTx) {
+        let tx = KeyTx::RevokeDevice(RevokePayload {
+            user_hash: [user; 32],
+            device_id: device_id.into(),
+            revoking_device_id: "phone".into(),
+            signature: [0; 64],
+        });
+        (hex::encode([user; 32]), tx)
+    }
+
+    #[test]
+    fn each_users_entries_get_consecutive_sequences() {
+        let (alice, bob) = (hex::encode([1; 32]), hex::encode([2; 32]));
+        let mut next = HashMap::from([(alice.clone(), 4), (bob.clone(), 0)]);
+
+        let entries = assign_sequences(
+            vec![
+                revoke(1, "laptop"),
+                revoke(2, "laptop"),
+                revoke(1, "tablet"),
+                revoke(1, "watch"),
+            ],
+            &mut next,
+        );
+
+        let sequences: Vec<_> = entries.iter().map(|e| e.sequence).collect();
+        assert_eq!(sequences, [4, 0, 5, 6]);
+        assert_eq!(next[&alice], 7);
+        assert_eq!(next[&bob], 1);
+    }
+}
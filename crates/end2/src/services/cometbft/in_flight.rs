@@ -0,0 +1,55 @@
+// Key uploads between signing and commit, per device. Whether an upload adds
+// its device or rotates it is read off the chain, which only has committed
+// devices, so an upload that follows another still in flight for the same
+// device would add it a second time and be rejected. It rotates it instead:
+// the earlier upload either adds the device or found it on chain already.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Default)]
+pub(super) struct InFlightUploads {
+    // "<user_hash_hex>/<device_id>" to the number of its uploads in flight
+    devices: Mutex<HashMap<String, usize>>,
+}
+
+impl InFlightUploads {
+    // Counts an upload for the device until the returned guard is dropped,
+    // and says whether another was already in flight.
+    pub(super) fn start(
+        self: &Arc<Self>,
+        user_hash_hex: &str,
+        device_id: &str,
+    ) -> (InFlightUpload, bool) {
+        let key = format!("{user_hash_hex}/{device_id}");
+        let mut devices = self.devices.lock().expect("lock in-flight uploads");
+        let count = devices.entry(key.clone()).or_default();
+        *count += 1;
+        let earlier = *count > 1;
+        drop(devices);
+        let upload = InFlightUpload {
+            uploads: Arc::clone(self),
+            key,
+        };
+        (upload, earlier)
+    }
+}
+
+pub(super) struct InFlightUpload {
+    uploads: Arc<InFlightUploads>,
+    key: String,
+}
+
+impl Drop for InFlightUpload {
+    fn drop(&mut self) {
+        let mut devices = self.uploads.devices.lock().expect("lock in-flight uploads");
+        if let Some(count) = devices.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                devices.remove(&self.key);
+            }
+        }
+    }
+}
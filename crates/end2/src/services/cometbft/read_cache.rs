@@ -0,0 +1,278 @@
+// Users' device lists as last read from the chain, so repeated lookups don't
+// each cost an abci_query. An entry is tagged with the height it was read at
+// and dropped as soon as a NewBlock event shows a key_add, key_update or
+// key_revoke for its user, so it is never older than the last block seen.
+// That only holds while no block is missed: the cache is emptied and stops
+// taking entries whenever the subscription drops or a height is skipped, and
+// entries older than the staleness bound are refetched regardless.
+// Lookups are counted in `comet.cache.lookups`, tagged with `cache.hit`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use opentelemetry::{KeyValue, metrics::Counter};
+use serde::Deserialize;
+
+use super::{
+    AbciEvent, DeviceKeys,
+    subscription::{self, Subscriber},
+};
+use crate::AppError;
+
+static LOOKUP_COUNTER: OnceLock<Counter<u64>> = OnceLock::new();
+
+pub(super) type DeviceMap = BTreeMap<String, DeviceKeys>;
+
+// A new block's header and the events its txs emitted.
+#[derive(Deserialize)]
+struct NewBlockValue {
+    block: Block,
+    result_finalize_block: FinalizeBlockResult,
+}
+
+#[derive(Deserialize)]
+struct Block {
+    header: BlockHeader,
+}
+
+#[derive(Deserialize)]
+struct BlockHeader {
+    height: String,
+}
+
+#[derive(Deserialize)]
+struct FinalizeBlockResult {
+    #[serde(default)]
+    tx_results: Vec<ExecTxResult>,
+}
+
+#[derive(Deserialize)]
+struct ExecTxResult {
+    #[serde(default)]
+    events: Vec<AbciEvent>,
+}
+
+struct Entry {
+    height: u64,
+    read_at: Instant,
+    devices: DeviceMap,
+}
+
+struct State {
+    max_age: Duration,
+    // None while the subscription is down or before its first block.
+    last_block: Option<u64>,
+    // by hex user_hash
+    entries: HashMap<String, Entry>,
+}
+
+impl State {
+    fn reset(&mut self) {
+        self.last_block = None;
+        self.entries.clear();
+    }
+}
+
+pub(super) struct ReadCache {
+    state: Arc<Mutex<State>>,
+}
+
+impl ReadCache {
+    // Follows new blocks on `ws_url`; entries are served for up to `max_age`.
+    pub(super) fn spawn(ws_url: String, max_age: Duration) -> Self {
+        let state = Arc::new(Mutex::new(State {
+            max_age,
+            last_block: None,
+            entries: HashMap::new(),
+        }));
+        subscription::spawn(ws_url, "tm.event='NewBlock'", Arc::downgrade(&state));
+        Self { state }
+    }
+
+    pub(super) fn get(&self, user_hash_hex: &str) -> Option<DeviceMap> {
+        let state = self.state.lock().expect("lock read cache");
+        let devices = state
+            .last_block
+            .and_then(|_| state.entries.get(user_hash_hex))
+            .filter(|e| e.read_at.elapsed() <= state.max_age)
+            .map(|e| e.devices.clone());
+        drop(state);
+        record(devices.is_some());
+        devices
+    }
+
+    // Keeps `devices`, read at `height`, unless blocks after it have already
+    // been seen: one of them may have changed the user's devices.
+    pub(super) fn insert(&self, user_hash_hex: &str, height: u64, devices: DeviceMap) {
+        let mut state = self.state.lock().expect("lock read cache");
+        if state.last_block.is_some_and(|last| height >= last) {
+            state.entries.insert(
+                user_hash_hex.to_owned(),
+                Entry {
+                    height,
+                    read_at: Instant::now(),
+                    devices,
+                },
+            );
+        }
+    }
+}
+
+impl Subscriber for Mutex<State> {
+    type Event = NewBlockValue;
+
+    fn subscribed(&self) {
+        tracing::info!("subscribed to new blocks");
+    }
+
+    fn event(&self, block: NewBlockValue) {
+        apply_block(self, &block);
+    }
+
+    fn lost(&self, error: Option<&AppError>) {
+        self.lock().expect("lock read cache").reset();
+        match error {
+            None => tracing::warn!("new block subscription closed, read cache is off"),
+            Some(e) => tracing::warn!(
+                error = %e,
+                "new block subscription failed, read cache is off"
+            ),
+        }
+    }
+}
+
+fn apply_block(state: &Mutex<State>, block: &NewBlockValue) {
+    let Ok(height) = block.block.header.height.parse::<u64>() else {
+        return;
+    };
+    let changed = block
+        .result_finalize_block
+        .tx_results
+        .iter()
+        .flat_map(|r| &r.events)
+        .filter(|e| matches!(e.kind.as_str(), "key_add" | "key_update" | "key_revoke"))
+        .filter_map(|e| e.attribute("user_hash"));
+
+    let mut state = state.lock().expect("lock read cache");
+    match state.last_block {
+        Some(last) if height == last + 1 => {
+            // an entry read at this height already has the block's changes
+            for user_hash_hex in changed {
+                if state
+                    .entries
+                    .get(user_hash_hex)
+                    .is_some_and(|e| e.height < height)
+                {
+                    state.entries.remove(user_hash_hex);
+                }
+            }
+            let max_age = state.max_age;
+            state.entries.retain(|_, e| e.read_at.elapsed() <= max_age);
+        }
+        // a missed block could have changed anyone's devices
+        Some(last) => {
+            tracing::warn!(
+                last,
+                height,
+                "new blocks skipped a height, emptying read cache"
+            );
+            state.entries.clear();
+        }
+        None => {}
+    }
+    state.last_block = Some(height);
+}
+
+fn record(hit: bool) {
+    LOOKUP_COUNTER
+        .get_or_init(|| {
+            opentelemetry::global::meter("end2")
+                .u64_counter("comet.cache.lookups")
+                .build()
+        })
+        .add(1, &[KeyValue::new("cache.hit", hit)]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> ReadCache {
+        ReadCache {
+            state: Arc::new(Mutex::new(State {
+                max_age: Duration::from_secs(60),
+                last_block: None,
+                entries: HashMap::new(),
+            })),
+        }
+    }
+
+    fn devices() -> DeviceMap {
+        DeviceMap::from([(
+            "phone".to_owned(),
+            DeviceKeys {
+                x25519: [1; 32],
+                ed25519: [2; 32],
+            },
+        )])
+    }
+
+    // A block at `height` with a key event of `kind` for each of `users`.
+    fn block(height: u64, kind: &str, users: &[&str]) -> NewBlockValue {
+        let events: Vec<_> = users
+            .iter()
+            .map(|user| {
+                serde_json::json!({
+                    "type": kind,
+                    "attributes": [{ "key": "user_hash", "value": user }],
+                })
+            })
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "block": { "header": { "height": height.to_string() } },
+            "result_finalize_block": { "tx_results": [{ "events": events }] },
+        }))
+        .expect("block json")
+    }
+
+    #[test]
+    fn key_events_drop_the_users_entry() {
+        let cache = cache();
+        cache.insert("aa", 1, devices());
+        assert!(cache.get("aa").is_none(), "nothing is kept before a block");
+
+        apply_block(&cache.state, &block(1, "key_add", &[]));
+        cache.insert("aa", 1, devices());
+        cache.insert("bb", 1, devices());
+        assert!(cache.get("aa").is_some());
+
+        apply_block(&cache.state, &block(2, "key_update", &["aa"]));
+        assert!(cache.get("aa").is_none());
+        assert!(cache.get("bb").is_some());
+
+        // read at the block's own height, so it already has the change
+        cache.insert("bb", 3, devices());
+        apply_block(&cache.state, &block(3, "key_revoke", &["bb"]));
+        assert!(cache.get("bb").is_some());
+
+        // an event for an unrelated type leaves entries alone
+        apply_block(&cache.state, &block(4, "set_limits", &["bb"]));
+        assert!(cache.get("bb").is_some());
+    }
+
+    #[test]
+    fn a_skipped_height_empties_the_cache() {
+        let cache = cache();
+        apply_block(&cache.state, &block(1, "key_add", &[]));
+        cache.insert("aa", 1, devices());
+
+        apply_block(&cache.state, &block(3, "key_add", &[]));
+        assert!(cache.get("aa").is_none());
+
+        cache.insert("aa", 2, devices());
+        assert!(cache.get("aa").is_none(), "read before the last block seen");
+    }
+}
@@ -0,0 +1,312 @@
+// Spreads RPC calls over several CometBFT nodes, so losing one node costs a
+// retry instead of stalling every upload on it. A background task checks each
+// node's `status` and takes nodes that are down, catching up or trailing the
+// others out of rotation. A call goes to the first healthy node in the
+// configured order and moves down the list while nodes can't be reached;
+// only transport failures move it on, since a JSON-RPC error is the node's
+// answer. Per-node latency and errors are exported as `comet.rpc.duration.ms`
+// and `comet.rpc.errors`, tagged with `rpc.endpoint` and `rpc.method`.
+
+use std::{
+    sync::{
+        Arc, OnceLock, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use futures::future::join_all;
+use opentelemetry::{
+    KeyValue,
+    metrics::{Counter, Histogram},
+};
+use reqwest::Client;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use super::{JsonRpcRequest, JsonRpcResponse};
+use crate::AppError;
+
+const HEALTH_INTERVAL: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Blocks a node may trail the highest node seen and still take calls.
+const MAX_LAG: u64 = 2;
+
+static DURATION_HISTOGRAM_MS: OnceLock<Histogram<f64>> = OnceLock::new();
+static ERROR_COUNTER: OnceLock<Counter<u64>> = OnceLock::new();
+
+#[derive(Serialize)]
+struct NoParams {}
+
+#[derive(Deserialize)]
+struct StatusResult {
+    sync_info: SyncInfo,
+}
+
+#[derive(Deserialize)]
+struct SyncInfo {
+    latest_block_height: String,
+    catching_up: bool,
+}
+
+struct Endpoint {
+    url: String,
+    // Until the first health check every node counts as healthy.
+    healthy: AtomicBool,
+}
+
+pub(super) struct RpcPool {
+    http: Client,
+    endpoints: Vec<Endpoint>,
+}
+
+impl RpcPool {
+    // Endpoints are preferred in the order given. Panics if `urls` is empty.
+    pub(super) fn new(urls: Vec<String>) -> Self {
+        assert!(!urls.is_empty(), "need at least one CometBFT RPC endpoint");
+        Self {
+            http: Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("build rpc client"),
+            endpoints: urls
+                .into_iter()
+                .map(|url| Endpoint {
+                    url,
+                    healthy: AtomicBool::new(true),
+                })
+                .collect(),
+        }
+    }
+
+    // Checks every endpoint each HEALTH_INTERVAL for as long as the pool
+    // lives.
+    pub(super) fn spawn_health_checks(self: &Arc<Self>) {
+        tokio::spawn(check_health(Arc::downgrade(self)));
+    }
+
+    // Sends `req` to the first healthy endpoint, trying the others in turn,
+    // unhealthy ones last, for as long as the node can't be reached or
+    // doesn't answer with JSON.
+    pub(super) async fn post<P: Serialize + Sync, R: DeserializeOwned>(
+        &self,
+        req: &JsonRpcRequest<P>,
+    ) -> Result<R, AppError> {
+        let mut endpoints: Vec<(bool, &Endpoint)> = self
+            .endpoints
+            .iter()
+            .map(|e| (e.healthy.load(Ordering::Acquire), e))
+            .collect();
+        endpoints.sort_by_key(|(healthy, _)| !healthy);
+
+        let mut last_err = None;
+        for (_, endpoint) in endpoints {
+            match self.send(endpoint, req).await {
+                Ok(res) => return Ok(res),
+                Err(e) => {
+                    tracing::warn!(
+                        endpoint = %endpoint.url,
+                        method = req.method,
+                        error = %e,
+                        "rpc call failed"
+                    );
+                    // out of rotation until the next health check says otherwise
+                    endpoint.healthy.store(false, Ordering::Release);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.expect("pool has endpoints"))
+    }
+
+    async fn send<P: Serialize + Sync, R: DeserializeOwned>(
+        &self,
+        endpoint: &Endpoint,
+        req: &JsonRpcRequest<P>,
+    ) -> Result<R, AppError> {
+        let start = tokio::time::Instant::now();
+        let result = async {
+            self.http
+                .post(&endpoint.url)
+                .json(req)
+                .send()
+                .await?
+                .json()
+                .await
+        }
+        .await
+        .map_err(|e: reqwest::Error| AppError::ValueError(e.to_string()));
+        record(endpoint, req.method, start.elapsed(), result.is_ok());
+        result
+    }
+
+    // The endpoint's latest height, or None if it is catching up.
+    async fn status(&self, endpoint: &Endpoint) -> Result<Option<u64>, AppError> {
+        let req = JsonRpcRequest {
+            jsonrpc: "2.0",
+            id: 1,
+            method: "status",
+            params: NoParams {},
+        };
+        let status = match self.send(endpoint, &req).await? {
+            JsonRpcResponse::<StatusResult>::Ok { result } => result.sync_info,
+            JsonRpcResponse::Err { error } => {
+                return Err(AppError::ValueError(format!("rpc error: {error}")));
+            }
+        };
+        if status.catching_up {
+            return Ok(None);
+        }
+        status
+            .latest_block_height
+            .parse()
+            .map(Some)
+            .map_err(|_| AppError::ValueError("invalid height in status".into()))
+    }
+}
+
+async fn check_health(pool: Weak<RpcPool>) {
+    let mut interval = tokio::time::interval(HEALTH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(pool) = pool.upgrade() else {
+            return;
+        };
+        let statuses = join_all(pool.endpoints.iter().map(|e| pool.status(e))).await;
+        let best = statuses
+            .iter()
+            .filter_map(|s| s.as_ref().ok().copied().flatten())
+            .max()
+            .unwrap_or(0);
+
+        for (endpoint, status) in pool.endpoints.iter().zip(statuses) {
+            let healthy = match &status {
+                Ok(Some(height)) => height + MAX_LAG >= best,
+                Ok(None) | Err(_) => false,
+            };
+            if endpoint.healthy.swap(healthy, Ordering::AcqRel) == healthy {
+                continue;
+            }
+            match status {
+                _ if healthy => tracing::info!(endpoint = %endpoint.url, "rpc endpoint is healthy"),
+                Ok(Some(height)) => tracing::warn!(
+                    endpoint = %endpoint.url,
+                    height,
+                    best,
+                    "rpc endpoint is behind"
+                ),
+                Ok(None) => tracing::warn!(endpoint = %endpoint.url, "rpc endpoint is catching up"),
+                Err(e) => tracing::warn!(
+                    endpoint = %endpoint.url,
+                    error = %e,
+                    "rpc endpoint is down"
+                ),
+            }
+        }
+    }
+}
+
+fn record(endpoint: &Endpoint, method: &'static str, elapsed: Duration, ok: bool) {
+    let attributes = [
+        KeyValue::new("rpc.endpoint", endpoint.url.clone()),
+        KeyValue::new("rpc.method", method),
+    ];
+    DURATION_HISTOGRAM_MS
+        .get_or_init(|| {
+            opentelemetry::global::meter("end2")
+                .f64_histogram("comet.rpc.duration.ms")
+                .with_unit("ms")
+                .with_boundaries(vec![
+                    1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0,
+                    10000.0,
+                ])
+                .build()
+        })
+        .record(elapsed.as_secs_f64() * 1000.0, &attributes);
+    if !ok {
+        ERROR_COUNTER
+            .get_or_init(|| {
+                opentelemetry::global::meter("end2")
+                    .u64_counter("comet.rpc.errors")
+                    .build()
+            })
+            .add(1, &attributes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Json, Router, routing::post};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // A node that answers every call with a status at `height`.
+    async fn node(height: u64) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let url = format!("http://{}", listener.local_addr().expect("addr"));
+        let status = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": {
+                "sync_info": {
+                    "latest_block_height": height.to_string(),
+                    "catching_up": false,
+                },
+            },
+        });
+        let router = Router::new().route("/", post(move || async move { Json(status) }));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        url
+    }
+
+    // An address nothing listens on.
+    async fn down() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        format!("http://{}", listener.local_addr().expect("addr"))
+    }
+
+    fn status_req() -> JsonRpcRequest<NoParams> {
+        JsonRpcRequest {
+            jsonrpc: "2.0",
+            id: 1,
+            method: "status",
+            params: NoParams {},
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_fail_over_to_the_next_endpoint() {
+        let pool = RpcPool::new(vec![down().await, node(7).await]);
+
+        let res: JsonRpcResponse<StatusResult> = pool.post(&status_req()).await.expect("failover");
+        assert!(matches!(
+            res,
+            JsonRpcResponse::Ok { result } if result.sync_info.latest_block_height == "7"
+        ));
+        assert!(!pool.endpoints[0].healthy.load(Ordering::Acquire));
+        assert!(pool.endpoints[1].healthy.load(Ordering::Acquire));
+
+        let pool = RpcPool::new(vec![down().await, down().await]);
+        let res: Result<JsonRpcResponse<StatusResult>, _> = pool.post(&status_req()).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn health_checks_drop_lagging_endpoints() {
+        let pool = Arc::new(RpcPool::new(vec![
+            node(3).await,
+            node(10).await,
+            down().await,
+        ]));
+        pool.spawn_health_checks();
+        // the first check runs straight away
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let healthy: Vec<_> = pool
+            .endpoints
+            .iter()
+            .map(|e| e.healthy.load(Ordering::Acquire))
+            .collect();
+        assert_eq!(healthy, [false, true, false]);
+    }
+}
@@ -0,0 +1,153 @@
+// Sequence numbers for the key txs this backend signs. The chain's `sequence`
+// query only counts committed txs, so two txs for one user signed before the
+// first commits would carry the same number and one of them would fail. A
+// user's txs are signed and broadcast one at a time under that user's lock,
+// and the sequence after the last one that passed check_tx is kept until the
+// chain has caught up with it.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::OwnedMutexGuard;
+
+// Per user, the sequence after the last tx still in flight, if any.
+type InFlight = Arc<tokio::sync::Mutex<Option<u64>>>;
+
+#[derive(Default)]
+pub(super) struct Sequences {
+    users: Mutex<HashMap<String, InFlight>>,
+}
+
+impl Sequences {
+    // Waits until no other tx for the user is between signing and check_tx.
+    pub(super) async fn lock(&self, user_hash_hex: &str) -> UserSequence<'_> {
+        let in_flight = Arc::clone(
+            self.users
+                .lock()
+                .expect("lock sequences")
+                .entry(user_hash_hex.to_owned())
+                .or_default(),
+        );
+        UserSequence {
+            sequences: self,
+            user_hash_hex: user_hash_hex.to_owned(),
+            in_flight: Some(in_flight.lock_owned().await),
+        }
+    }
+}
+
+pub(super) struct UserSequence<'a> {
+    sequences: &'a Sequences,
+    user_hash_hex: String,
+    // Only None while being dropped.
+    in_flight: Option<OwnedMutexGuard<Option<u64>>>,
+}
+
+impl UserSequence<'_> {
+    fn in_flight(&mut self) -> &mut Option<u64> {
+        self.in_flight.as_mut().expect("guard held until drop")
+    }
+
+    // The sequence the user's next tx must carry, given the chain's count of
+    // committed ones.
+    pub(super) fn next(&mut self, committed: u64) -> u64 {
+        let in_flight = self.in_flight();
+        match *in_flight {
+            Some(next) if next > committed => next,
+            _ => {
+                *in_flight = None;
+                committed
+            }
+        }
+    }
+
+    // Txs up to `next - 1` passed check_tx.
+    pub(super) fn accepted(&mut self, next: u64) {
+        *self.in_flight() = Some(next);
+    }
+
+    // Txs up to `next - 1` committed.
+    pub(super) fn committed(&mut self, next: u64) {
+        let in_flight = self.in_flight();
+        if in_flight.is_some_and(|n| n <= next) {
+            *in_flight = None;
+        }
+    }
+
+    // A tx was turned away or failed in its block, so the ones signed after it
+    // fail too; go by the chain's count again.
+    pub(super) fn rejected(&mut self) {
+        *self.in_flight() = None;
+    }
+}
+
+impl Drop for UserSequence<'_> {
+    fn drop(&mut self) {
+        let idle = self.in_flight.take().is_some_and(|guard| guard.is_none());
+        if !idle {
+            return;
+        }
+        // Nothing in flight and nobody else waiting: the entry can go.
+        let mut users = self.sequences.users.lock().expect("lock sequences");
+        if users
+            .get(&self.user_hash_hex)
+            .is_some_and(|entry| Arc::strong_count(entry) == 1)
+        {
+            users.remove(&self.user_hash_hex);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn in_flight_txs_push_the_sequence_past_the_chain() {
+        let sequences = Sequences::default();
+
+        let mut user = sequences.lock("a").await;
+        assert_eq!(user.next(5), 5);
+        user.accepted(6);
+        drop(user);
+
+        // the chain hasn't committed it yet
+        let mut user = sequences.lock("a").await;
+        assert_eq!(user.next(5), 6);
+        user.accepted(7);
+        user.committed(6);
+        assert_eq!(user.next(6), 7);
+        user.committed(7);
+        drop(user);
+        assert!(sequences.users.lock().expect("lock").is_empty());
+
+        let mut user = sequences.lock("a").await;
+        user.accepted(9);
+        user.rejected();
+        assert_eq!(user.next(7), 7);
+    }
+
+    #[tokio::test]
+    async fn a_users_txs_go_one_at_a_time() {
+        let sequences = Arc::new(Sequences::default());
+        let mut first = sequences.lock("a").await;
+        let other = sequences.lock("b").await;
+
+        let waiting = {
+            let sequences = Arc::clone(&sequences);
+            tokio::spawn(async move {
+                let mut user = sequences.lock("a").await;
+                user.next(0)
+            })
+        };
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        drop(other);
+        first.accepted(1);
+        drop(first);
+        assert_eq!(waiting.await.expect("join"), 1);
+    }
+}
@@ -0,0 +1,119 @@
+// A WebSocket subscription to one CometBFT event query, kept open by a task
+// that reconnects with backoff until whoever it feeds is dropped. The read
+// cache follows NewBlock events through it and the tx watcher Tx events.
+
+use std::{sync::Weak, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::JsonRpcRequest;
+use crate::AppError;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Serialize)]
+struct SubscribeParams {
+    query: &'static str,
+}
+
+// What the node pushes for each event; `value` is the event's own data.
+#[derive(Deserialize)]
+struct EventMessage<E> {
+    result: EventResult<E>,
+}
+
+#[derive(Deserialize)]
+struct EventResult<E> {
+    data: EventData<E>,
+}
+
+#[derive(Deserialize)]
+struct EventData<E> {
+    value: E,
+}
+
+pub(super) trait Subscriber: Send + Sync + 'static {
+    // The event's `value`. Implementations declare only the fields they read
+    // and serde skips the rest, so a node adding fields breaks nothing.
+    type Event: DeserializeOwned;
+
+    // The subscription is in place; events from here on are delivered.
+    fn subscribed(&self);
+
+    fn event(&self, event: Self::Event);
+
+    // The socket closed, with the error if it failed. Events may have been
+    // missed; the next `subscribed` comes after a backoff.
+    fn lost(&self, error: Option<&AppError>);
+}
+
+// Starts the task that keeps `query` subscribed on `ws_url`, reconnecting with
+// backoff, and feeds `subscriber`; it stops once the subscriber is dropped.
+// Needs a Tokio runtime.
+pub(super) fn spawn<S: Subscriber>(ws_url: String, query: &'static str, subscriber: Weak<S>) {
+    tokio::spawn(run(ws_url, query, subscriber));
+}
+
+async fn run<S: Subscriber>(ws_url: String, query: &'static str, subscriber: Weak<S>) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let result = subscribe(&ws_url, query, &subscriber, &mut backoff).await;
+        let Some(live) = subscriber.upgrade() else {
+            return;
+        };
+        live.lost(result.as_ref().err());
+        drop(live);
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+// Subscribes and delivers events until the socket closes or the subscriber is
+// dropped.
+async fn subscribe<S: Subscriber>(
+    ws_url: &str,
+    query: &'static str,
+    subscriber: &Weak<S>,
+    backoff: &mut Duration,
+) -> Result<(), AppError> {
+    let ws_err = |e: tokio_tungstenite::tungstenite::Error| AppError::ValueError(e.to_string());
+    let (mut socket, _) = tokio_tungstenite::connect_async(ws_url)
+        .await
+        .map_err(ws_err)?;
+    let req = JsonRpcRequest {
+        jsonrpc: "2.0",
+        id: 1,
+        method: "subscribe",
+        params: SubscribeParams { query },
+    };
+    let req = serde_json::to_string(&req).map_err(|e| AppError::ValueError(e.to_string()))?;
+    socket
+        .send(Message::Text(req.into()))
+        .await
+        .map_err(ws_err)?;
+
+    match subscriber.upgrade() {
+        Some(live) => live.subscribed(),
+        None => return Ok(()),
+    }
+    *backoff = MIN_BACKOFF;
+
+    while let Some(message) = socket.next().await {
+        let Message::Text(text) = message.map_err(ws_err)? else {
+            continue;
+        };
+        // the subscribe reply and anything else without an event in it
+        let Ok(event) = serde_json::from_str::<EventMessage<S::Event>>(&text) else {
+            continue;
+        };
+        let Some(live) = subscriber.upgrade() else {
+            return Ok(());
+        };
+        live.event(event.result.data.value);
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+// One WebSocket subscription to the node's Tx events, shared by every tx the
+// service is waiting on. A waiter registers the tx hash and is resolved with
+// the tx's result when the block holding it commits. While the socket is down
+// nothing can register and pending waiters are dropped, so `wait_for_tx` falls
+// back to polling the `tx` RPC.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::oneshot;
+
+use super::{
+    CommittedTx,
+    subscription::{self, Subscriber},
+};
+use crate::AppError;
+
+// A committed tx with its height and result.
+#[derive(Deserialize)]
+struct TxEventValue {
+    #[serde(rename = "TxResult")]
+    tx_result: EventTxResult,
+}
+
+#[derive(Deserialize)]
+struct EventTxResult {
+    height: String,
+    tx: String, // base64
+    result: serde_json::Value,
+}
+
+#[derive(Default)]
+struct Shared {
+    connected: AtomicBool,
+    // by upper-case hex tx hash, as broadcast_tx_sync returns it
+    waiters: Mutex<HashMap<String, Vec<oneshot::Sender<CommittedTx>>>>,
+}
+
+pub(super) struct TxWatcher {
+    shared: Arc<Shared>,
+}
+
+impl TxWatcher {
+    // Follows committed txs on `ws_url`.
+    pub(super) fn spawn(ws_url: String) -> Self {
+        let shared = Arc::new(Shared::default());
+        subscription::spawn(ws_url, "tm.event='Tx'", Arc::downgrade(&shared));
+        Self { shared }
+    }
+
+    // A receiver for the tx's result once it commits, or None while the socket
+    // is down. A tx that committed before it was registered is never resolved,
+    // so the caller must check for that itself.
+    pub(super) fn register(&self, hash: &str) -> Option<oneshot::Receiver<CommittedTx>> {
+        if !self.shared.connected.load(Ordering::Acquire) {
+            return None;
+        }
+        let (sender, receiver) = oneshot::channel();
+        let mut waiters = self.shared.waiters.lock().expect("lock tx waiters");
+        // drop waiters that gave up, e.g. on a timeout or an early commit
+        waiters.retain(|_, senders| {
+            senders.retain(|s| !s.is_closed());
+            !senders.is_empty()
+        });
+        waiters.entry(hash.to_uppercase()).or_default().push(sender);
+        drop(waiters);
+        Some(receiver)
+    }
+}
+
+impl Subscriber for Shared {
+    type Event = TxEventValue;
+
+    fn subscribed(&self) {
+        self.connected.store(true, Ordering::Release);
+        tracing::info!("subscribed to tx events");
+    }
+
+    fn event(&self, event: TxEventValue) {
+        resolve(self, &event.tx_result);
+    }
+
+    fn lost(&self, error: Option<&AppError>) {
+        self.connected.store(false, Ordering::Release);
+        self.waiters.lock().expect("lock tx waiters").clear();
+        match error {
+            None => tracing::warn!("tx event subscription closed, polling until it is back"),
+            Some(e) => tracing::warn!(
+                error = %e,
+                "tx event subscription failed, polling until it is back"
+            ),
+        }
+    }
+}
+
+fn resolve(shared: &Shared, event: &EventTxResult) {
+    let Ok(tx) = BASE64_STANDARD.decode(&event.tx) else {
+        return;
+    };
+    let hash = hex::encode_upper(Sha256::digest(&tx));
+    let Some(senders) = shared
+        .waiters
+        .lock()
+        .expect("lock tx waiters")
+        .remove(&hash)
+    else {
+        return;
+    };
+    let height = event.height.parse().unwrap_or(0);
+    for sender in senders {
+        let _ = sender.send(CommittedTx {
+            height,
+            tx_result: event.result.clone(),
+        });
+    }
+}
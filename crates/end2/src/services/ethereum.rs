@@ -11,10 +11,12 @@ use diesel::{
     SelectableHelper, r2d2::ConnectionManager,
 };
 use r2d2::Pool;
+use time::OffsetDateTime;
 use vodozemac::{Curve25519PublicKey, Ed25519PublicKey, Ed25519Signature};
 
 use crate::{
-    AppError, Device, DeviceId, DeviceKeyService, HistoricalKey, InboundDevice, NewDevice, User,
+    AppError, Device, DeviceId, DeviceKeyService, HistoricalKey, InboundDevice, KeyHistoryQuery,
+    NewDevice, User,
     schema::{device, user as user_table},
 };
 
@@ -171,7 +173,13 @@ where
 
         let send = if nonce == U256::ZERO {
             contract
-                .add_first_device(user_hash, device_id_u128, x25519_bytes, ed25519_bytes, sig_bytes)
+                .add_first_device(
+                    user_hash,
+                    device_id_u128,
+                    x25519_bytes,
+                    ed25519_bytes,
+                    sig_bytes,
+                )
                 .send()
                 .await
                 .map_err(|e| AppError::ValueError(e.to_string()))?
@@ -246,6 +254,7 @@ where
         &self,
         user: &User,
         device_id: DeviceId,
+        query: &KeyHistoryQuery,
     ) -> Result<Vec<HistoricalKey>, AppError> {
         use alloy::{eips::BlockNumberOrTag, rpc::types::Filter, sol_types::SolEvent};
 
@@ -256,7 +265,9 @@ where
             .address(self.contract_address)
             .event_signature(KeyDirectory::DeviceAdded::SIGNATURE_HASH)
             .topic1(user_hash)
-            .from_block(BlockNumberOrTag::Earliest)
+            .from_block(query.since_height.map_or(BlockNumberOrTag::Earliest, |h| {
+                BlockNumberOrTag::Number(h + 1)
+            }))
             .to_block(BlockNumberOrTag::Latest);
 
         let logs = self
@@ -280,10 +291,16 @@ where
                     ed25519: event.ed25519.to_vec(),
                     signature: event.signature.to_vec(),
                     authorization: None,
+                    tx_hash: log
+                        .transaction_hash
+                        .map(|h| hex::encode_upper(h.as_slice())),
+                    block_time: log
+                        .block_timestamp
+                        .and_then(|t| OffsetDateTime::from_unix_timestamp(t.try_into().ok()?).ok()),
                 })
             })
             .collect();
 
-        Ok(history)
+        Ok(query.page(history, |k| k.chain_height))
     }
 }
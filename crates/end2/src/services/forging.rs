@@ -7,7 +7,7 @@ use r2d2::Pool;
 
 use crate::{
     AppError, CometBftDeviceKeyService, Device, DeviceId, DeviceKeyService, HistoricalKey,
-    InboundDevice, User,
+    InboundDevice, KeyHistoryQuery, User,
 };
 
 // Attacker keys injected as a forged secondary device. The self-signature
@@ -101,7 +101,10 @@ impl DeviceKeyService for ForgingDeviceKeyService {
         &self,
         user: &User,
         device_id: DeviceId,
+        query: &KeyHistoryQuery,
     ) -> Result<Vec<HistoricalKey>, AppError> {
-        self.inner.get_device_key_history(user, device_id).await
+        self.inner
+            .get_device_key_history(user, device_id, query)
+            .await
     }
 }
@@ -8,7 +8,7 @@ use r2d2::Pool;
 
 use crate::{
     AppError, CometBftDeviceKeyService, Device, DeviceId, DeviceKeyService, HistoricalKey,
-    InboundDevice, User,
+    InboundDevice, KeyHistoryQuery, User,
 };
 
 // Attacker-controlled keys distributed in place of the real ones when a
@@ -86,7 +86,10 @@ impl DeviceKeyService for MaliciousDeviceKeyService {
         &self,
         user: &User,
         device_id: DeviceId,
+        query: &KeyHistoryQuery,
     ) -> Result<Vec<HistoricalKey>, AppError> {
-        self.inner.get_device_key_history(user, device_id).await
+        self.inner
+            .get_device_key_history(user, device_id, query)
+            .await
     }
 }
@@ -207,7 +207,7 @@ impl MessageRelayService for DbMessageRelayService {
         let payloads = message
             .payloads
             .into_iter()
-            .map(|m| m.into_new_message(message.message_id))
+            .map(|m| NewMessagePayload::from_inbound(message.message_id, m))
             .collect::<Result<Vec<NewMessagePayload>, _>>()?;
 
         let message = diesel::insert_into(message::table)
@@ -1,6 +1,9 @@
 use async_trait::async_trait;
 
-use crate::{AppError, Device, DeviceId, HistoricalKey, InboundDevice, User};
+use crate::{
+    AppError, Device, DeviceId, DeviceKeyProof, HistoricalKey, InboundDevice, InboundRevocation,
+    KeyHistoryQuery, UploadStatus, User,
+};
 
 /// How the backend stores and distributes long-term device keys
 #[async_trait]
@@ -19,11 +22,60 @@ pub trait DeviceKeyService: Send + Sync {
         Err(AppError::UserError("not supported".into()))
     }
 
+    /// The keys the device has uploaded, oldest first, cut down to `query`.
     async fn get_device_key_history(
         &self,
         _user: &User,
         _device_id: DeviceId,
+        _query: &KeyHistoryQuery,
     ) -> Result<Vec<HistoricalKey>, AppError> {
-        Err(AppError::UserError("key history not supported by this backend".into()))
+        Err(AppError::UserError(
+            "key history not supported by this backend".into(),
+        ))
+    }
+
+    /// The device's keys as committed at chain `height`.
+    async fn get_device_at_height(
+        &self,
+        _user: &User,
+        _device_id: DeviceId,
+        _height: u64,
+    ) -> Result<Device, AppError> {
+        Err(AppError::UserError(
+            "historical queries not supported by this backend".into(),
+        ))
+    }
+
+    async fn get_device_proof(
+        &self,
+        _user: &User,
+        _device_id: DeviceId,
+    ) -> Result<DeviceKeyProof, AppError> {
+        Err(AppError::UserError(
+            "key proofs not supported by this backend".into(),
+        ))
+    }
+
+    /// The state of the device's last key upload, if `set_device_keys`
+    /// returned before it was committed.
+    async fn get_upload_status(
+        &self,
+        _user: &User,
+        _device_id: DeviceId,
+    ) -> Result<UploadStatus, AppError> {
+        Err(AppError::UserError(
+            "upload status not supported by this backend".into(),
+        ))
+    }
+
+    async fn revoke_device(
+        &self,
+        _user: &User,
+        _device_id: DeviceId,
+        _revocation: InboundRevocation,
+    ) -> Result<(), AppError> {
+        Err(AppError::UserError(
+            "device revocation not supported by this backend".into(),
+        ))
     }
 }
@@ -6,6 +6,10 @@ use uuid::Uuid;
 
 use crate::AppError;
 
+// The IDs that cross the wire are end2-protocol's; the rest only ever live in
+// the backend.
+pub use end2_protocol::{ChannelId, DeviceId, MessageId, UserId};
+
 macro_rules! prefixed_uuid {
     ($name:ident, $prefix:expr) => {
         #[derive(
@@ -93,10 +97,6 @@ macro_rules! prefixed_uuid {
     };
 }
 
-prefixed_uuid!(DeviceId, "dev");
-prefixed_uuid!(UserId, "usr");
-prefixed_uuid!(ChannelId, "ch");
-prefixed_uuid!(MessageId, "msg");
 prefixed_uuid!(SessionId, "sess");
 prefixed_uuid!(OtkId, "otk");
 prefixed_uuid!(DiscordInfoId, "di");